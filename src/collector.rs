@@ -0,0 +1,108 @@
+use crate::config::Config;
+use crate::metrics::metrics::Metrics;
+use crate::metrics::runner::CommandRunner;
+use crate::metrics::timer;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::Registry;
+use std::sync::{Arc, RwLock};
+
+/// Collector of the JVM, process and system metrics, to register into an existing
+/// `prometheus::Registry`. Metrics are collected in the background on the Tokio runtime,
+/// so a scrape returns the latest collected values.
+pub struct JvmCollector {
+    registry: Registry,
+    metrics: Arc<Metrics>,
+}
+
+impl JvmCollector {
+    pub fn builder() -> JvmCollectorBuilder {
+        JvmCollectorBuilder::default()
+    }
+
+    pub(crate) fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+}
+
+impl Collector for JvmCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.metrics
+            .collectors()
+            .into_iter()
+            .flat_map(|collector| collector.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.registry.gather()
+    }
+}
+
+/// Builder of a [`JvmCollector`].
+#[derive(Default)]
+pub struct JvmCollectorBuilder {
+    java_home: Option<String>,
+    java_home_source: Option<&'static str>,
+    full_path: bool,
+    config: Arc<RwLock<Config>>,
+    runner: Option<Arc<dyn CommandRunner>>,
+}
+
+impl JvmCollectorBuilder {
+    /// JAVA_HOME of the JDK tools, jps, jstat and jcmd are looked up on the PATH otherwise.
+    pub fn java_home(mut self, java_home: Option<String>) -> Self {
+        self.java_home = java_home;
+        self
+    }
+
+    // Where the exporter found java_home, reported by jvm_exporter_java_home_info
+    pub(crate) fn java_home_source(mut self, source: &'static str) -> Self {
+        self.java_home_source = Some(source);
+        self
+    }
+
+    /// Same as the `--full-path` argument of the exporter.
+    pub fn full_path(mut self, full_path: bool) -> Self {
+        self.full_path = full_path;
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Arc::new(RwLock::new(config));
+        self
+    }
+
+    // Share the configuration updated by /config and SIGHUP
+    pub(crate) fn shared_config(mut self, config: Arc<RwLock<Config>>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Run jps, jstat, jcmd and container runtime commands through a custom [`CommandRunner`].
+    pub fn command_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = Some(runner);
+        self
+    }
+
+    /// Create the collector and start collecting. Must be called within a Tokio runtime.
+    pub fn build(self) -> JvmCollector {
+        let registry = Registry::new();
+        let mut metrics = Metrics::new(&registry, self.config);
+        if let Some(runner) = self.runner {
+            metrics.runner = runner;
+        }
+        let source = self.java_home_source.unwrap_or(match self.java_home {
+            Some(_) => "builder",
+            None => "detected",
+        });
+        metrics
+            .system_metrics
+            .java_home_info
+            .with_label_values(&[source, self.java_home.as_deref().unwrap_or_default()])
+            .set(1.0);
+        let metrics = Arc::new(metrics);
+        timer::run(metrics.clone(), Arc::new(self.java_home), self.full_path);
+        JvmCollector { registry, metrics }
+    }
+}
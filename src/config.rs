@@ -1,17 +1,121 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::sync::{Arc, RwLock};
 use warp::Filter;
 use ureq;
 
-#[derive(Deserialize, Serialize, Clone)]
+pub const DEFAULT_CONTAINER_LIST_TTL_SECS: u64 = 30;
+pub const DEFAULT_COLLECTION_INTERVAL_SECS: u64 = 15;
+pub const DEFAULT_JSTAT_RETRIES: u32 = 2;
+pub const DEFAULT_MAX_SERIES: usize = 100_000;
+pub const DEFAULT_GC_PAUSE_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+pub const DEFAULT_METRICS_PATH: &str = "metrics";
+pub const DEFAULT_WORKER_THREADS: usize = 2;
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerRuntime {
+    // Use every available runtime, deduplicating containers seen by more than one
+    #[default]
+    Auto,
+    Docker,
+    Crictl,
+    Podman,
+}
+
+impl ContainerRuntime {
+    // CLI commands to query for containers, in order of preference
+    pub fn commands(self) -> &'static [&'static str] {
+        match self {
+            ContainerRuntime::Auto => &["docker", "podman", "crictl"],
+            ContainerRuntime::Docker => &["docker"],
+            ContainerRuntime::Crictl => &["crictl"],
+            ContainerRuntime::Podman => &["podman"],
+        }
+    }
+}
+
+// How the patterns of system_processes are matched against process names
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcessMatchType {
+    #[default]
+    Regex,
+    // Shell wildcards such as "myapp*", matching the whole name
+    Glob,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, for log pipelines
+    Json,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Default)]
 pub struct Config {
     pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
     pub java_home: Option<String>,
     pub configuration_service_url: Option<String>,
     pub system_processes: Option<Vec<String>>,
+    pub system_process_match_type: Option<ProcessMatchType>,
+    pub process_name_strip_prefixes: Option<Vec<String>>, // e.g. "com.example.services."
+    pub dedup_host_container_by_name: Option<bool>,
+    pub target_pids: Option<Vec<u32>>, // Monitored instead of discovering processes
     pub detect_docker_processes: Option<bool>,
+    pub docker_sudo: Option<bool>,
+    pub detect_crictl_processes: Option<bool>,
+    pub container_list_ttl_secs: Option<u64>,
+    pub container_runtime: Option<ContainerRuntime>,
+    pub collection_interval_secs: Option<u64>,
+    pub scrape_deadline_ms: Option<u64>,
+    pub container_exec_timeout_ms: Option<u64>, // Bounds each command run in a container
+    pub container_java_homes: Option<HashMap<String, String>>, // Key: container ID, name or image, value: JDK in the container
+    pub aggregate_by_process: Option<bool>,
+    pub tcp_address_family: Option<bool>,
+    pub max_series: Option<usize>,
+    pub collect_system_metrics: Option<bool>,
+    pub disabled_metrics: Option<Vec<String>>, // Metric names such as "process_open_file"
+    pub run_jstat_as_owner: Option<bool>,
+    pub jstat_retries: Option<u32>,
+    pub jstat_columns: Option<HashMap<String, Vec<String>>>, // Key: jstat command such as "-gc"
+    pub jstat_extra_commands: Option<Vec<String>>, // Optional jstat commands to run, such as "-gccapacity"
+    pub jstat_single_snapshot: Option<bool>,
+    pub gc_rates: Option<bool>,
+    pub enable_jcmd: Option<bool>,
+    pub capture_process_args: Option<bool>,
+    pub jolokia_urls: Option<HashMap<String, String>>, // Key: container#pid, host PID or process_name, value: Jolokia agent URL
+    pub jolokia_mbeans: Option<Vec<String>>, // Application MBeans read through Jolokia, patterns allowed
+    pub collect_tcp_states: Option<bool>,
+    pub per_core_cpu: Option<bool>,
+    pub gc_log_paths: Option<HashMap<String, Vec<String>>>, // Key: process_name, value: GC log file globs
+    pub gc_pause_buckets: Option<Vec<f64>>, // Upper bounds of jvm_gc_pause_seconds in seconds
+    pub listen_address: Option<String>,
+    pub unix_socket_path: Option<String>,
+    pub textfile_output_dir: Option<String>, // node_exporter textfile collector directory
+    pub run_as_user: Option<String>, // User name or numeric ID
+    pub run_as_group: Option<String>,
+    pub worker_threads: Option<usize>,
+    pub metrics_path: Option<String>,
+    pub cors_allowed_origins: Option<Vec<String>>,
+    pub remote_hosts: Option<Vec<RemoteHost>>,
+}
+
+// A host without the exporter whose JVMs are monitored by running jps and jstat over ssh
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct RemoteHost {
+    pub host: String,
+    pub user: Option<String>,
+    pub key: Option<String>, // Private key file, passed to ssh -i
+    pub port: Option<u16>,
+    pub java_home: Option<String>, // JDK on the remote host, the remote PATH is used when not set
 }
 
 impl Config {
@@ -21,8 +125,92 @@ impl Config {
         if config.detect_docker_processes.is_none() {
             config.detect_docker_processes = Some(false);
         }
+        if config.detect_crictl_processes.is_none() {
+            config.detect_crictl_processes = Some(false);
+        }
+        if config.container_list_ttl_secs.is_none() {
+            config.container_list_ttl_secs = Some(DEFAULT_CONTAINER_LIST_TTL_SECS);
+        }
+        if config.collection_interval_secs.is_none() {
+            config.collection_interval_secs = Some(DEFAULT_COLLECTION_INTERVAL_SECS);
+        }
+        if config.collect_tcp_states.is_none() {
+            config.collect_tcp_states = Some(true);
+        }
+        if config.enable_jcmd.is_none() {
+            config.enable_jcmd = Some(false);
+        }
+        if config.jstat_retries.is_none() {
+            config.jstat_retries = Some(DEFAULT_JSTAT_RETRIES);
+        }
+        if config.worker_threads.is_none() {
+            config.worker_threads = Some(DEFAULT_WORKER_THREADS);
+        }
         Ok(config)
     }
+
+    // Whether containers of the given runtime command are discovered,
+    // crictl has its own flag and docker/podman share detect_docker_processes
+    pub fn detects_runtime(&self, runtime: &str) -> bool {
+        match runtime {
+            "crictl" => self.detect_crictl_processes.unwrap_or_default(),
+            _ => self.detect_docker_processes.unwrap_or_default(),
+        }
+    }
+
+    // Path serving the metrics without surrounding slashes, e.g. "jvm/metrics"
+    pub fn metrics_path(&self) -> String {
+        let path = self
+            .metrics_path
+            .as_deref()
+            .unwrap_or(DEFAULT_METRICS_PATH)
+            .trim_matches('/');
+        if path.is_empty() {
+            DEFAULT_METRICS_PATH.to_string()
+        } else {
+            path.to_string()
+        }
+    }
+
+    // Whether any container runtime is enabled
+    pub fn detects_containers(&self) -> bool {
+        self.detect_docker_processes.unwrap_or_default()
+            || self.detect_crictl_processes.unwrap_or_default()
+    }
+
+    // JDK inside a container from container_java_homes, matched by container ID or a prefix of
+    // it such as the short ID, then by container name, then by image with or without its tag.
+    // The longest ID wins.
+    pub fn container_java_home(
+        &self,
+        container: &str,
+        name: Option<&str>,
+        image: Option<&str>,
+    ) -> Option<String> {
+        let java_homes = self.container_java_homes.as_ref()?;
+        let by_id = java_homes
+            .iter()
+            .filter(|(key, _)| !key.is_empty() && container.starts_with(key.as_str()))
+            .max_by_key(|(key, _)| key.len())
+            .map(|(_, java_home)| java_home);
+        let by_name = || java_homes.get(name?);
+        let by_image = || {
+            let image = image?;
+            java_homes
+                .get(image)
+                .or_else(|| java_homes.get(image_repository(image)))
+        };
+        by_id.or_else(by_name).or_else(by_image).cloned()
+    }
+}
+
+// Image reference without its tag or digest, e.g. "registry:5000/app" for "registry:5000/app:1.2"
+fn image_repository(image: &str) -> &str {
+    let image = image.split('@').next().unwrap_or(image);
+    match image.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => image,
+    }
 }
 
 pub fn with_config(
@@ -37,9 +225,105 @@ pub async fn fetch_and_merge_config(url: &str, config: &mut Config) -> Result<()
     if remote_config.log_level.is_some() {
         config.log_level = remote_config.log_level;
     }
+    if remote_config.log_format.is_some() {
+        config.log_format = remote_config.log_format;
+    }
     if remote_config.java_home.is_some() {
         config.java_home = remote_config.java_home;
     }
+    if remote_config.system_process_match_type.is_some() {
+        config.system_process_match_type = remote_config.system_process_match_type;
+    }
+    if remote_config.docker_sudo.is_some() {
+        config.docker_sudo = remote_config.docker_sudo;
+    }
+    if remote_config.container_runtime.is_some() {
+        config.container_runtime = remote_config.container_runtime;
+    }
+    if remote_config.run_jstat_as_owner.is_some() {
+        config.run_jstat_as_owner = remote_config.run_jstat_as_owner;
+    }
+    if remote_config.remote_hosts.is_some() {
+        config.remote_hosts = remote_config.remote_hosts;
+    }
+    if remote_config.gc_log_paths.is_some() {
+        config.gc_log_paths = remote_config.gc_log_paths;
+    }
+    if remote_config.gc_pause_buckets.is_some() {
+        config.gc_pause_buckets = remote_config.gc_pause_buckets;
+    }
+    if remote_config.collect_tcp_states.is_some() {
+        config.collect_tcp_states = remote_config.collect_tcp_states;
+    }
+    if remote_config.per_core_cpu.is_some() {
+        config.per_core_cpu = remote_config.per_core_cpu;
+    }
+    if remote_config.enable_jcmd.is_some() {
+        config.enable_jcmd = remote_config.enable_jcmd;
+    }
+    if remote_config.capture_process_args.is_some() {
+        config.capture_process_args = remote_config.capture_process_args;
+    }
+    if remote_config.jolokia_urls.is_some() {
+        config.jolokia_urls = remote_config.jolokia_urls;
+    }
+    if remote_config.jolokia_mbeans.is_some() {
+        config.jolokia_mbeans = remote_config.jolokia_mbeans;
+    }
+    if remote_config.gc_rates.is_some() {
+        config.gc_rates = remote_config.gc_rates;
+    }
+    if remote_config.jstat_single_snapshot.is_some() {
+        config.jstat_single_snapshot = remote_config.jstat_single_snapshot;
+    }
+    if remote_config.jstat_columns.is_some() {
+        config.jstat_columns = remote_config.jstat_columns;
+    }
+    if remote_config.jstat_extra_commands.is_some() {
+        config.jstat_extra_commands = remote_config.jstat_extra_commands;
+    }
+    if remote_config.jstat_retries.is_some() {
+        config.jstat_retries = remote_config.jstat_retries;
+    }
+    if remote_config.process_name_strip_prefixes.is_some() {
+        config.process_name_strip_prefixes = remote_config.process_name_strip_prefixes;
+    }
+    if remote_config.dedup_host_container_by_name.is_some() {
+        config.dedup_host_container_by_name = remote_config.dedup_host_container_by_name;
+    }
+    if remote_config.target_pids.is_some() {
+        config.target_pids = remote_config.target_pids;
+    }
+    if remote_config.aggregate_by_process.is_some() {
+        config.aggregate_by_process = remote_config.aggregate_by_process;
+    }
+    if remote_config.tcp_address_family.is_some() {
+        config.tcp_address_family = remote_config.tcp_address_family;
+    }
+    if remote_config.max_series.is_some() {
+        config.max_series = remote_config.max_series;
+    }
+    if remote_config.collect_system_metrics.is_some() {
+        config.collect_system_metrics = remote_config.collect_system_metrics;
+    }
+    if remote_config.disabled_metrics.is_some() {
+        config.disabled_metrics = remote_config.disabled_metrics;
+    }
+    if remote_config.scrape_deadline_ms.is_some() {
+        config.scrape_deadline_ms = remote_config.scrape_deadline_ms;
+    }
+    if remote_config.container_exec_timeout_ms.is_some() {
+        config.container_exec_timeout_ms = remote_config.container_exec_timeout_ms;
+    }
+    if remote_config.container_java_homes.is_some() {
+        config.container_java_homes = remote_config.container_java_homes;
+    }
+    if remote_config.collection_interval_secs.is_some() {
+        config.collection_interval_secs = remote_config.collection_interval_secs;
+    }
+    if remote_config.container_list_ttl_secs.is_some() {
+        config.container_list_ttl_secs = remote_config.container_list_ttl_secs;
+    }
     if let Some(remote_processes) = remote_config.system_processes {
         let mut local_processes: HashSet<String> = config.system_processes.clone().unwrap_or_default().into_iter().collect();
         local_processes.extend(remote_processes.into_iter());
@@ -0,0 +1,46 @@
+//! JVM, process and system metrics for Prometheus.
+//!
+//! The collection logic of jvm-exporter is available as a [`JvmCollector`] to register
+//! into an existing `prometheus::Registry`:
+//!
+//! ```no_run
+//! # #[tokio::main]
+//! # async fn main() {
+//! use jvm_exporter::{Config, JvmCollector};
+//!
+//! let collector = JvmCollector::builder()
+//!     .java_home(Some("/usr/lib/jvm/java-17".to_string()))
+//!     .config(Config::default())
+//!     .build();
+//! prometheus::default_registry()
+//!     .register(Box::new(collector))
+//!     .unwrap();
+//! # }
+//! ```
+
+mod collector;
+pub mod config;
+mod monitor;
+mod routes;
+
+mod deploy {
+    pub mod deploy;
+}
+mod metrics {
+    pub mod cgroup;
+    pub mod collect;
+    pub mod gclog;
+    pub mod jolokia;
+    pub mod metrics;
+    pub mod runner;
+    pub mod timer;
+}
+
+pub use collector::{JvmCollector, JvmCollectorBuilder};
+pub use config::Config;
+pub use metrics::runner::{CommandFuture, CommandRunner, TokioCommandRunner};
+
+// Run the exporter, parsing the command-line arguments and serving /metrics
+pub fn run() {
+    monitor::main()
+}
@@ -1,16 +1,3 @@
-mod monitor;
-mod routes;
-mod config;
-
-mod deploy {
-    pub mod deploy;
-}
-mod metrics {
-    pub mod collect;
-    pub mod metrics;
-    pub mod timer;
-}
-
 fn main() {
-    monitor::main()
-}
\ No newline at end of file
+    jvm_exporter::run()
+}
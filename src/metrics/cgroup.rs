@@ -0,0 +1,185 @@
+use std::fs;
+use std::path::Path;
+
+// Mount point of the cgroup filesystem, inside a container it shows the container's own cgroup
+pub(crate) const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// cgroup v1 reports "no limit" as a page-aligned i64::MAX, anything this large is not a real limit
+const UNLIMITED_BYTES: u64 = 1 << 60;
+
+// CPU and memory limits of a cgroup, None when unlimited
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct CgroupLimits {
+    pub(crate) cpu_quota_cores: Option<f64>,
+    pub(crate) memory_limit_bytes: Option<u64>,
+}
+
+// Memory limit in bytes of the cgroup mounted at `root`, from memory.max (cgroup v2) or
+// memory/memory.limit_in_bytes (cgroup v1). None when the cgroup has no memory limit.
+pub(crate) fn memory_limit(root: &Path) -> Option<u64> {
+    read_memory_limit(&root.join("memory.max"))
+        .or_else(|| read_memory_limit(&root.join("memory/memory.limit_in_bytes")))
+}
+
+// Limits of the cgroup a host process belongs to, e.g. the init process of a container.
+// The process has to be visible in /proc, so an exporter running in a container needs the host PID namespace.
+pub(crate) fn process_limits(pid: &str) -> CgroupLimits {
+    match fs::read_to_string(format!("/proc/{}/cgroup", pid)) {
+        Ok(proc_cgroup) => limits_from(Path::new(CGROUP_ROOT), &proc_cgroup),
+        Err(_) => CgroupLimits::default(),
+    }
+}
+
+// Resolve the lines of /proc/<pid>/cgroup, "<id>:<controllers>:<path>", against the cgroup mount.
+// cgroup v2 has a single line with no controllers, cgroup v1 has one hierarchy per controller.
+fn limits_from(root: &Path, proc_cgroup: &str) -> CgroupLimits {
+    let mut limits = CgroupLimits::default();
+    for line in proc_cgroup.lines() {
+        let mut parts = line.splitn(3, ':');
+        let (Some(_), Some(controllers), Some(path)) = (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let path = path.trim_start_matches('/');
+        if controllers.is_empty() {
+            let dir = root.join(path);
+            limits.cpu_quota_cores = limits.cpu_quota_cores.or_else(|| {
+                fs::read_to_string(dir.join("cpu.max"))
+                    .ok()
+                    .and_then(|cpu_max| parse_cpu_max(&cpu_max))
+            });
+            limits.memory_limit_bytes = limits
+                .memory_limit_bytes
+                .or_else(|| read_memory_limit(&dir.join("memory.max")));
+            continue;
+        }
+        for controller in controllers.split(',') {
+            match controller {
+                "cpu" => {
+                    let dir = root.join("cpu").join(path);
+                    limits.cpu_quota_cores = read_cfs_quota(&dir);
+                }
+                "memory" => {
+                    let dir = root.join("memory").join(path);
+                    limits.memory_limit_bytes =
+                        read_memory_limit(&dir.join("memory.limit_in_bytes"));
+                }
+                _ => {}
+            }
+        }
+    }
+    limits
+}
+
+fn read_memory_limit(file: &Path) -> Option<u64> {
+    parse_memory_limit(&fs::read_to_string(file).ok()?)
+}
+
+fn parse_memory_limit(value: &str) -> Option<u64> {
+    let limit = value.trim().parse::<u64>().ok()?; // "max" when unlimited in cgroup v2
+    if limit == 0 || limit >= UNLIMITED_BYTES {
+        return None;
+    }
+    Some(limit)
+}
+
+// cgroup v2 cpu.max is "<quota> <period>" in microseconds, quota is "max" when unlimited
+fn parse_cpu_max(value: &str) -> Option<f64> {
+    let mut parts = value.split_whitespace();
+    let quota = parts.next()?.parse::<f64>().ok()?;
+    let period = parts.next()?.parse::<f64>().ok()?;
+    cpu_cores(quota, period)
+}
+
+// cgroup v1 splits the quota and period in two files, a quota of -1 means unlimited
+fn read_cfs_quota(dir: &Path) -> Option<f64> {
+    let read = |file: &str| -> Option<f64> {
+        fs::read_to_string(dir.join(file)).ok()?.trim().parse::<f64>().ok()
+    };
+    cpu_cores(read("cpu.cfs_quota_us")?, read("cpu.cfs_period_us")?)
+}
+
+fn cpu_cores(quota: f64, period: f64) -> Option<f64> {
+    if quota <= 0.0 || period <= 0.0 {
+        return None;
+    }
+    Some(quota / period)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_root(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("jvm-exporter-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn parses_memory_limits() {
+        assert_eq!(parse_memory_limit("536870912\n"), Some(536870912));
+        assert_eq!(parse_memory_limit("max\n"), None);
+        assert_eq!(parse_memory_limit("9223372036854771712\n"), None);
+    }
+
+    #[test]
+    fn parses_cpu_quotas() {
+        assert_eq!(parse_cpu_max("150000 100000\n"), Some(1.5));
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(cpu_cores(-1.0, 100000.0), None);
+    }
+
+    #[test]
+    fn reads_cgroup_v1_and_v2_limits() {
+        let root = temp_root("cgroup");
+        fs::create_dir_all(root.join("memory")).unwrap();
+        assert_eq!(memory_limit(&root), None);
+
+        fs::write(root.join("memory/memory.limit_in_bytes"), "1073741824\n").unwrap();
+        assert_eq!(memory_limit(&root), Some(1073741824));
+
+        fs::write(root.join("memory.max"), "268435456\n").unwrap();
+        assert_eq!(memory_limit(&root), Some(268435456));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn resolves_process_cgroups() {
+        let root = temp_root("cgroup-v2");
+        let dir = root.join("system.slice/docker-abc.scope");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cpu.max"), "200000 100000\n").unwrap();
+        fs::write(dir.join("memory.max"), "536870912\n").unwrap();
+        assert_eq!(
+            limits_from(&root, "0::/system.slice/docker-abc.scope\n"),
+            CgroupLimits {
+                cpu_quota_cores: Some(2.0),
+                memory_limit_bytes: Some(536870912),
+            }
+        );
+        fs::remove_dir_all(&root).unwrap();
+
+        let root = temp_root("cgroup-v1");
+        fs::create_dir_all(root.join("cpu/docker/abc")).unwrap();
+        fs::create_dir_all(root.join("memory/docker/abc")).unwrap();
+        fs::write(root.join("cpu/docker/abc/cpu.cfs_quota_us"), "50000\n").unwrap();
+        fs::write(root.join("cpu/docker/abc/cpu.cfs_period_us"), "100000\n").unwrap();
+        fs::write(
+            root.join("memory/docker/abc/memory.limit_in_bytes"),
+            "9223372036854771712\n",
+        )
+        .unwrap();
+        assert_eq!(
+            limits_from(
+                &root,
+                "12:memory:/docker/abc\n4:cpu,cpuacct:/docker/abc\n1:name=systemd:/docker/abc\n"
+            ),
+            CgroupLimits {
+                cpu_quota_cores: Some(0.5),
+                memory_limit_bytes: None,
+            }
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
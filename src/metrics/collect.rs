@@ -1,70 +1,346 @@
 pub use crate::metrics::metrics::{Metrics, ProcessInfo, EXCLUDED_PROCESSES, JSTAT_COMMANDS, TCP_STATES};
-use log::{error, info, warn};
-use netstat::{get_sockets_info, AddressFamilyFlags, ProtocolFlags, ProtocolSocketInfo};
+use crate::metrics::metrics::{JSTAT_GCUTIL_PERCENT_METRICS, JSTAT_OPTIONAL_COMMANDS};
+use crate::config::{ContainerRuntime, ProcessMatchType, RemoteHost, DEFAULT_JSTAT_RETRIES};
+use crate::metrics::cgroup;
+use crate::metrics::gclog::remove_gc_log_metrics;
+use crate::metrics::jolokia::{fetch_jolokia, jolokia_url, THREAD_ATTRIBUTES};
+use crate::metrics::metrics::{Container, GaugeUpdates, Pod, ADDRESS_FAMILIES};
+use crate::metrics::runner::{
+    ssh_destination_error, CommandRunner, SshCommandRunner, SudoCommandRunner,
+    TimeoutCommandRunner, SSH_RUNTIME,
+};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use netstat::{
+    get_sockets_info, AddressFamilyFlags, ProtocolFlags, ProtocolSocketInfo, SocketInfo,
+};
 use prometheus::{Encoder, GaugeVec, Registry};
 use regex::Regex;
 use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use sysinfo::{Disks, Pid, System};
+use std::time::{Duration, Instant};
+use sysinfo::{Disks, Pid, System, Users};
 use tokio::process::Command;
 
+// Header of the JVM uptime column printed first by jstat -t
+const JSTAT_TIMESTAMP_HEADER: &str = "Timestamp";
+// Image, name and the pod labels the kubelet sets on the containers it starts, read with a
+// single crictl inspect
+const CRICTL_INSPECT_TEMPLATE: &str = r#"{{.status.image.image}}|{{.status.metadata.name}}|{{index .status.labels "io.kubernetes.pod.name"}}|{{index .status.labels "io.kubernetes.pod.namespace"}}|{{index .status.labels "io.kubernetes.pod.uid"}}"#;
+// Delay before retrying jstat after a transient attach failure, multiplied by the attempt number
+const JSTAT_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+// Time one jstat command may take for a process, its retries included
+const JSTAT_TIMEOUT: Duration = Duration::from_secs(15);
+// Consecutive failed collections after which jstat is skipped for a process during JSTAT_COOLDOWN,
+// it is tried again once the cooldown is over
+const JSTAT_FAILURE_THRESHOLD: u32 = 5;
+const JSTAT_COOLDOWN: Duration = Duration::from_secs(300);
+// How long the containers of a runtime that stopped answering, e.g. a restarting docker daemon,
+// are kept along with their series before they are dropped
+const RUNTIME_OUTAGE_GRACE: Duration = Duration::from_secs(300);
+// File written in textfile_output_dir
+const TEXTFILE_NAME: &str = "jvm-exporter.prom";
+
+// Serve the metrics gathered by the latest background collection, see timer::run.
+// The response is 304 Not Modified when If-None-Match has the ETag of the same payload.
 pub(crate) async fn handle_metrics(
-    metrics: Arc<Metrics>,
     registry: Arc<Registry>,
-    java_home: Arc<Option<String>>,
-    full_path: bool,
+    metrics: Arc<Metrics>,
+    accept_encoding: Option<String>,
+    if_none_match: Option<String>,
+    container: Option<String>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    if let Err(err) = update_metrics(metrics.clone(), java_home.as_deref(), full_path).await {
-        error!("Failed to update metrics: {}", err);
-    }
-
     let mut buffer = Vec::new();
     let encoder = prometheus::TextEncoder::new();
-    let metric_families = registry.gather();
+    let mut metric_families = registry.gather();
+    if let Some(container) = container {
+        let ids = container_ids(&metrics.containers.lock().await, &container);
+        metric_families = filter_by_container(metric_families, &ids);
+    }
     encoder
         .encode(&metric_families, &mut buffer)
         .expect("Failed to encode metrics");
 
+    let gzip = accepts_gzip(accept_encoding.as_deref());
+    let etag = metrics_etag(&buffer, gzip);
     let response = warp::http::Response::builder()
-        .header("Content-Type", encoder.format_type())
-        .body(String::from_utf8(buffer).expect("Failed to convert buffer to String"));
-    Ok(response)
+        .header("ETag", &etag)
+        .header("Vary", "Accept-Encoding");
+    if etag_matches(if_none_match.as_deref(), &etag) {
+        return Ok(response
+            .status(warp::http::StatusCode::NOT_MODIFIED)
+            .body(Vec::new()));
+    }
+
+    let mut response = response.header("Content-Type", encoder.format_type());
+    if gzip {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        match gzip.write_all(&buffer).and_then(|_| gzip.finish()) {
+            Ok(compressed) => {
+                buffer = compressed;
+                response = response.header("Content-Encoding", "gzip");
+            }
+            Err(e) => warn!("Failed to gzip metrics, sending them uncompressed: {}", e),
+        }
+    }
+    Ok(response.body(buffer))
+}
+
+// Whether the Accept-Encoding header allows a gzip response. A q-value of 0, in any notation
+// such as "q=0.0", or one that is not a number turns the encoding off.
+fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding
+        .unwrap_or_default()
+        .split(',')
+        .any(|encoding| {
+            let mut parts = encoding.split(';').map(|p| p.trim());
+            let name = parts.next().unwrap_or_default();
+            let disabled = parts.any(|p| match p.split_once('=') {
+                Some((key, q)) if key.trim().eq_ignore_ascii_case("q") => {
+                    !matches!(q.trim().parse::<f64>(), Ok(q) if q > 0.0)
+                }
+                _ => false,
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !disabled
+        })
+}
+
+// Strong ETag of the encoded metrics, the gzip representation gets its own
+fn metrics_etag(buffer: &[u8], gzip: bool) -> String {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    buffer.hash(&mut hasher);
+    let suffix = if gzip { "-gzip" } else { "" };
+    format!("\"{:016x}{}\"", hasher.finish(), suffix)
+}
+
+// Whether If-None-Match, a list of ETags or "*", matches the ETag. GET uses the weak
+// comparison, so a W/ prefix is ignored.
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    if_none_match
+        .unwrap_or_default()
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// Values of the container label a ?container= query matches: the value itself, e.g. a container
+// ID, "host" or a remote host, and the IDs of the containers with that name
+fn container_ids(containers: &[Container], container: &str) -> Vec<String> {
+    std::iter::once(container.to_string())
+        .chain(
+            containers
+                .iter()
+                .filter(|c| c.name.as_deref() == Some(container))
+                .map(|c| c.id.clone()),
+        )
+        .collect()
+}
+
+// Keep the series whose container label is one of the given containers. Families left without
+// series are dropped, the text encoder rejects them.
+fn filter_by_container(
+    metric_families: Vec<prometheus::proto::MetricFamily>,
+    containers: &[String],
+) -> Vec<prometheus::proto::MetricFamily> {
+    metric_families
+        .into_iter()
+        .filter_map(|mut family| {
+            let metrics: Vec<prometheus::proto::Metric> = family
+                .take_metric()
+                .into_iter()
+                .filter(|metric| {
+                    metric.get_label().iter().any(|label| {
+                        label.get_name() == "container"
+                            && containers.iter().any(|c| c == label.get_value())
+                    })
+                })
+                .collect();
+            if metrics.is_empty() {
+                return None;
+            }
+            family.set_metric(metrics.into());
+            Some(family)
+        })
+        .collect()
+}
+
+// Same metrics as handle_metrics, rendered as JSON for tooling that cannot parse the text format
+pub(crate) async fn handle_metrics_json(
+    registry: Arc<Registry>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let metric_families: Vec<serde_json::Value> = registry
+        .gather()
+        .iter()
+        .map(metric_family_to_json)
+        .collect();
+    Ok(warp::reply::json(&metric_families))
+}
+
+fn metric_family_to_json(family: &prometheus::proto::MetricFamily) -> serde_json::Value {
+    let metric_type = family.get_field_type();
+    let samples: Vec<serde_json::Value> = family
+        .get_metric()
+        .iter()
+        .map(|metric| {
+            let labels: serde_json::Map<String, serde_json::Value> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name().to_string(), label.get_value().into()))
+                .collect();
+            let value = match metric_type {
+                prometheus::proto::MetricType::COUNTER => metric.get_counter().get_value(),
+                prometheus::proto::MetricType::GAUGE => metric.get_gauge().get_value(),
+                prometheus::proto::MetricType::HISTOGRAM => {
+                    return histogram_to_json(labels, metric.get_histogram())
+                }
+                prometheus::proto::MetricType::SUMMARY => metric.get_summary().get_sample_sum(),
+                prometheus::proto::MetricType::UNTYPED => metric.get_untyped().get_value(),
+            };
+            serde_json::json!({ "labels": labels, "value": value })
+        })
+        .collect();
+
+    serde_json::json!({
+        "name": family.get_name(),
+        "help": family.get_help(),
+        "type": format!("{:?}", metric_type).to_lowercase(),
+        "metrics": samples,
+    })
+}
+
+// Cumulative bucket counts of a histogram series by upper bound, as in the text format, with the
+// sum and count of the observations
+fn histogram_to_json(
+    labels: serde_json::Map<String, serde_json::Value>,
+    histogram: &prometheus::proto::Histogram,
+) -> serde_json::Value {
+    let mut buckets: Vec<serde_json::Value> = histogram
+        .get_bucket()
+        .iter()
+        .map(|bucket| {
+            serde_json::json!({
+                "le": bucket.get_upper_bound().to_string(),
+                "count": bucket.get_cumulative_count(),
+            })
+        })
+        .collect();
+    buckets.push(serde_json::json!({ "le": "+Inf", "count": histogram.get_sample_count() }));
+    serde_json::json!({
+        "labels": labels,
+        "buckets": buckets,
+        "sum": histogram.get_sample_sum(),
+        "count": histogram.get_sample_count(),
+    })
+}
+
+// Write the same metrics as handle_metrics to <dir>/jvm-exporter.prom for the node_exporter
+// textfile collector. The file is written next to its final name then renamed, so that
+// node_exporter never reads a partial file.
+pub(crate) fn write_textfile(registry: &Registry, dir: &Path) -> std::io::Result<()> {
+    let mut buffer = Vec::new();
+    prometheus::TextEncoder::new()
+        .encode(&registry.gather(), &mut buffer)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+    // node_exporter only reads *.prom files, the temporary file is skipped
+    let temp_path = dir.join(format!(".{}.tmp", TEXTFILE_NAME));
+    std::fs::write(&temp_path, &buffer)?;
+    std::fs::rename(&temp_path, dir.join(TEXTFILE_NAME))
 }
-async fn update_metrics(
+
+pub(crate) async fn update_metrics(
     metrics: Arc<Metrics>,
     java_home: Option<&str>,
     full_path: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    // Collections run one at a time, an overlapping one would update the same series and caches
+    let _collection = metrics.collection.lock().await;
+    let started = tokio::time::Instant::now();
     let mut all_processes = Vec::new();
-    let mut host_process_names: HashSet<String> = HashSet::new();
+    // (container, process name) of host processes, the container being the one they run in
+    let mut host_process_keys: HashSet<(String, String)> = HashSet::new();
+
+    // target_pids replaces the discovery of host, container and remote processes
+    let target_pids = metrics.config.read().unwrap().target_pids.clone();
 
     // 1. Collect Host Processes
-    let host_processes = get_java_processes(java_home, full_path, "host".to_string()).await?;
+    let strip_prefixes = strip_prefixes(&metrics);
+    let host_processes = match &target_pids {
+        Some(target_pids) => {
+            let mut processes = get_target_processes(target_pids, full_path);
+            strip_process_name_prefixes(&mut processes, &strip_prefixes);
+            processes
+        }
+        None => {
+            get_java_processes(
+                metrics.runner.as_ref(),
+                java_home,
+                full_path,
+                &strip_prefixes,
+                "host".to_string(),
+                None,
+                &metrics.system_metrics.dependency_available,
+                &metrics.jps_ok,
+            )
+            .await?
+        }
+    };
     info!(
         "Detect and Collect Host Processes: {}",
         host_processes.len()
     );
+    let containers = metrics.containers.lock().await.clone();
     for (pid, pname) in host_processes {
+        host_process_keys.insert((host_process_container(&pid, &containers), pname.clone()));
         all_processes.push(ProcessInfo {
             container: "host".to_string(),
+            runtime: None,
+            image: None,
+            container_name: None,
+            pod: None,
             pid,
             process: pname.clone(),
+            user: None,
+            runner: None,
+            java_home: None,
         });
-        host_process_names.insert(pname.clone());
     }
 
     // 2. Detect and Collect Container Processes
-    let container_processes =
-        get_container_java_processes(metrics.clone(), java_home, full_path).await?;
+    let container_processes = if target_pids.is_some() {
+        Vec::new()
+    } else {
+        get_container_java_processes(metrics.clone(), full_path).await?
+    };
     info!(
         "Detect and Collect Container Processes: {}",
         container_processes.len()
     );
+    // dedup_host_container_by_name also skips container processes named like any host process
+    let dedup_by_name = metrics
+        .config
+        .read()
+        .unwrap()
+        .dedup_host_container_by_name
+        .unwrap_or(false);
     let filtered_container_processes: Vec<ProcessInfo> = container_processes
         .into_iter()
         .filter(|proc_info| {
-            if host_process_names.contains(&proc_info.process) {
-                info!(
+            let key = (proc_info.container.clone(), proc_info.process.clone());
+            let duplicate = host_process_keys.contains(&key)
+                || (dedup_by_name
+                    && host_process_keys
+                        .iter()
+                        .any(|(_, name)| *name == proc_info.process));
+            if duplicate {
+                debug!(
                     "Skipping container process '{}' in '{}': already exists on host.",
                     proc_info.process, proc_info.container
                 );
@@ -81,21 +357,72 @@ async fn update_metrics(
     );
     all_processes.extend(filtered_container_processes);
 
+    // Detect and Collect Processes of Remote Hosts over ssh
+    let remote_processes = if target_pids.is_some() {
+        Vec::new()
+    } else {
+        get_remote_java_processes(&metrics, full_path).await
+    };
+    info!(
+        "Detect and Collect Remote Processes: {}",
+        remote_processes.len()
+    );
+    all_processes.extend(remote_processes);
+
+    // jps cannot read the main class of JVMs that are starting up or owned by another user.
+    // Keep the name already known for such a PID, and skip PIDs that never had one, so that
+    // their series do not flap between "unknown" and the real name.
+    {
+        let active_pids = metrics.active_pids.lock().await;
+        all_processes.retain_mut(|proc_info| {
+            if proc_info.process != UNKNOWN_PROCESS_NAME {
+                return true;
+            }
+            let key = format!("{}#{}", proc_info.container, proc_info.pid);
+            match active_pids.get(&key) {
+                Some(previous) if previous != UNKNOWN_PROCESS_NAME => {
+                    proc_info.process = previous.clone();
+                    true
+                }
+                _ => {
+                    info!(
+                        "Skipping PID {} in {}: process information unavailable",
+                        proc_info.pid, proc_info.container
+                    );
+                    false
+                }
+            }
+        });
+    }
+
+    // Enumerate all processes once per collection, for the system_processes scan and the CPU
+    // and memory metrics, which is expensive on hosts with thousands of processes
+    metrics.system.lock().await.refresh_all();
+
     // 3. Collect System Processes from Config
     let config = metrics.config.read().unwrap().clone();
     if let Some(system_processes) = &config.system_processes {
-        let system_processes_regex: Vec<Regex> = system_processes
-            .iter()
-            .filter_map(|pattern| Regex::new(pattern).ok())
-            .collect();
+        // Compiled again only when the patterns change, so that an invalid one is logged once
+        let match_type = config.system_process_match_type.unwrap_or_default();
+        let mut compiled = metrics.system_process_patterns.lock().await;
+        let changed = compiled
+            .as_ref()
+            .is_none_or(|(patterns, compiled_type, _)| {
+                patterns != system_processes || *compiled_type != match_type
+            });
+        if changed {
+            let patterns = compile_system_processes(system_processes, match_type);
+            *compiled = Some((system_processes.clone(), match_type, patterns));
+        }
+        let system_process_patterns = compiled.as_ref().map_or(&[][..], |(_, _, p)| p.as_slice());
 
-        let system = System::new_all();
+        let system = metrics.system.lock().await;
         for (pid, process) in system.processes() {
-            let process_name = process.name().to_str().unwrap_or_default().to_string();
+            let process_name = sanitize_process_name(process.name().to_str().unwrap_or_default());
             let ppid = process.parent().unwrap_or(Pid::from_u32(0)).as_u32();
-            if system_processes_regex
+            if system_process_patterns
                 .iter()
-                .any(|re| re.is_match(&process_name))
+                .any(|pattern| pattern.matches(&process_name))
                 && ppid == 1u32
             {
                 info!(
@@ -104,28 +431,99 @@ async fn update_metrics(
                 );
                 all_processes.push(ProcessInfo {
                     container: "system".to_string(),
+                    runtime: None,
+                    image: None,
+                    container_name: None,
+                    pod: None,
                     pid: pid.to_string(),
                     process: process_name,
+                    user: None,
+                    runner: None,
+                    java_home: None,
                 });
             }
         }
     }
 
+    // Resolve the owner of host processes, used for the user label and run_jstat_as_owner.
+    // Container PIDs and UIDs are namespace-local, so container processes keep no owner.
+    let owners = process_owners(
+        all_processes
+            .iter()
+            .filter(|p| p.runtime.is_none())
+            .map(|p| p.pid.as_str())
+            .chain(std::iter::once(std::process::id().to_string().as_str())),
+    );
+    for proc_info in all_processes.iter_mut().filter(|p| p.runtime.is_none()) {
+        proc_info.user = owners.get(&proc_info.pid).cloned();
+    }
+    let exporter_user = owners.get(&std::process::id().to_string()).cloned();
+    let run_jstat_as_owner = config.run_jstat_as_owner.unwrap_or_default();
+    let jstat_retries = config.jstat_retries.unwrap_or(DEFAULT_JSTAT_RETRIES);
+    let jstat_columns = config.jstat_columns.clone().unwrap_or_default();
+    let jstat_extra_commands = config.jstat_extra_commands.clone().unwrap_or_default();
+    let jstat_single_snapshot = config.jstat_single_snapshot.unwrap_or_default();
+    let gc_rates = config.gc_rates.unwrap_or_default();
+    let deadline = config
+        .scrape_deadline_ms
+        .map(|ms| started + Duration::from_millis(ms));
+
+    // Over max_series, only the PIDs already exported keep being collected
+    let series_capped = metrics.series_capped.load(Ordering::Relaxed);
+    if series_capped {
+        let active_pids = metrics.active_pids.lock().await;
+        all_processes.retain(|p| {
+            let key = format!("{}#{}", p.container, p.pid);
+            let known = active_pids.contains_key(&key);
+            if !known {
+                debug!("Skipping PID {} ({}): over max_series", key, p.process);
+            }
+            known
+        });
+    }
+
     // Create a unique key for each process as "container#pid"
     let current_pids: HashMap<String, String> = all_processes
         .iter()
         .map(|p| (format!("{}#{}", p.container, p.pid), p.process.clone()))
         .collect();
 
+    // A PID whose start time changed was reused by a new process, e.g. a JVM restarted in
+    // place. It is removed like a PID that is gone, so that its counters start from zero.
+    // Container and remote PIDs are not host PIDs, their start time is unknown.
+    let start_times: HashMap<String, u64> = {
+        let host_processes: Vec<&ProcessInfo> = all_processes
+            .iter()
+            .filter(|p| p.runtime.is_none())
+            .collect();
+        let by_pid = process_start_times(host_processes.iter().map(|p| p.pid.as_str()));
+        host_processes
+            .iter()
+            .filter_map(|p| {
+                let start_time = by_pid.get(&p.pid)?;
+                Some((format!("{}#{}", p.container, p.pid), *start_time))
+            })
+            .collect()
+    };
+    let restarted = {
+        let mut known_start_times = metrics.start_times.lock().await;
+        let restarted = restarted_pids(&known_start_times, &start_times);
+        *known_start_times = start_times;
+        restarted
+    };
+
     // Identify removed PIDs
     let removed_pids: Vec<(String, String)> = {
         let active_pids = metrics.active_pids.lock().await;
         active_pids
             .iter()
-            .filter(|(key, _)| !current_pids.contains_key(*key))
+            .filter(|(key, _)| !current_pids.contains_key(*key) || restarted.contains(*key))
             .map(|(key, pname)| (key.clone(), pname.clone()))
             .collect()
     };
+    for key in &restarted {
+        info!("PID {} was restarted, resetting its metrics", key);
+    }
 
     // Remove metrics for removed PIDs
     if !removed_pids.is_empty() {
@@ -135,7 +533,96 @@ async fn update_metrics(
         }
         info!("Removed PIDs from active_pids");
 
+        {
+            let mut non_hotspot_pids = metrics.non_hotspot_pids.lock().await;
+            let mut nmt_disabled_pids = metrics.nmt_disabled_pids.lock().await;
+            let mut jstat_version_mismatches = metrics.jstat_version_mismatches.lock().await;
+            let mut jstat_failures = metrics.jstat_failures.lock().await;
+            let mut java_versions = metrics.java_versions.lock().await;
+            let mut process_args = metrics.process_args.lock().await;
+            let mut process_args_retries = metrics.process_args_retries.lock().await;
+            let mut java_runtimes = metrics.java_runtimes.lock().await;
+            let mut detected_java_homes = metrics.detected_java_homes.lock().await;
+            let mut disk_io_totals = metrics.disk_io_totals.lock().await;
+            let mut ctxt_switch_totals = metrics.ctxt_switch_totals.lock().await;
+            let mut page_fault_totals = metrics.page_fault_totals.lock().await;
+            let mut gc_counts = metrics.gc_counts.lock().await;
+            let mut process_info_labels = metrics.process_info_labels.lock().await;
+            let mut process_states = metrics.process_states.lock().await;
+            let mut gc_causes = metrics.gc_causes.lock().await;
+            for process_name in removed_pids
+                .iter()
+                .map(|(_, process_name)| process_name)
+                .filter(|process_name| !current_pids.values().any(|p| p == *process_name))
+                .collect::<HashSet<_>>()
+            {
+                remove_gc_log_metrics(&metrics, process_name);
+            }
+            for (key, process_name) in &removed_pids {
+                non_hotspot_pids.remove(key);
+                nmt_disabled_pids.remove(key);
+                jstat_version_mismatches.remove(key);
+                jstat_failures.remove(key);
+                java_versions.remove(key);
+                process_args.remove(key);
+                process_args_retries.remove(key);
+                detected_java_homes.remove(key);
+                if let Some((vendor, version)) = java_runtimes.remove(key) {
+                    // Set again below when aggregating and another PID has the same runtime
+                    if let Some((container, pid)) = key.split_once('#') {
+                        let labels = metrics.process_label_values(
+                            container,
+                            pid,
+                            process_name,
+                            &[&vendor, &version],
+                        );
+                        let _ = metrics
+                            .process_metrics
+                            .version_info
+                            .remove_label_values(&labels);
+                    }
+                }
+                disk_io_totals.remove(key);
+                ctxt_switch_totals.remove(key);
+                page_fault_totals.remove(key);
+                gc_counts.remove(key);
+                if let Some(labels) = process_info_labels.remove(key) {
+                    let labels: Vec<&str> = labels.iter().map(|l| l.as_str()).collect();
+                    let _ = metrics.process_metrics.info.remove_label_values(&labels);
+                }
+                if let Some(cause) = gc_causes.remove(key) {
+                    if let Some((container, pid)) = key.split_once('#') {
+                        let labels =
+                            metrics.process_label_values(container, pid, process_name, &[&cause]);
+                        let _ = metrics
+                            .process_metrics
+                            .last_gc_cause_info
+                            .remove_label_values(&labels);
+                    }
+                }
+                if let Some(state) = process_states.remove(key) {
+                    if let Some((container, pid)) = key.split_once('#') {
+                        let labels =
+                            metrics.process_label_values(container, pid, process_name, &[state]);
+                        let _ = metrics
+                            .process_metrics
+                            .state_info
+                            .remove_label_values(&labels);
+                    }
+                }
+            }
+        }
+
+        // With aggregate_by_process, series of a process that still has other PIDs are kept
+        let reported_processes: HashSet<(&str, &str)> = all_processes
+            .iter()
+            .map(|p| (p.container.as_str(), p.process.as_str()))
+            .collect();
+
         let mut jstat_labels = metrics.jstat_labels.lock().await;
+        let mut jcmd_labels = metrics.jcmd_labels.lock().await;
+        let mut jolokia_labels = metrics.jolokia_labels.lock().await;
+        let mut listening_ports = metrics.listening_ports.lock().await;
         for (key, process_name) in &removed_pids {
             let parts: Vec<&str> = key.split('#').collect();
             if parts.len() != 2 {
@@ -143,49 +630,141 @@ async fn update_metrics(
             }
             let container = parts[0];
             let pid = parts[1];
+            let still_reported = metrics.aggregate_by_process
+                && reported_processes.contains(&(container, process_name.as_str()));
 
-            // Remove CPU and Memory metrics
-            let _ = metrics.process_metrics.cpu_usage.remove_label_values(&[
-                container,
-                pid,
-                process_name,
-            ]);
-            let _ = metrics.process_metrics.memory_usage.remove_label_values(&[
-                container,
-                pid,
-                process_name,
-            ]);
-            let _ = metrics
-                .process_metrics
-                .memory_usage_percentage
-                .remove_label_values(&[container, pid, process_name]);
-            let _ = metrics.process_metrics.start_time.remove_label_values(&[
-                container,
-                pid,
-                process_name,
-            ]);
-            let _ = metrics.process_metrics.up_time.remove_label_values(&[
-                container,
-                pid,
-                process_name,
-            ]);
+            if !still_reported {
+                let labels = metrics.process_label_values(container, pid, process_name, &[]);
 
-            let _ = metrics.process_metrics.open_file.remove_label_values(&[
-                container,
-                pid,
-                process_name,
-            ]);
+                let _ = metrics
+                    .process_metrics
+                    .code_cache_used
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .code_cache_max
+                    .remove_label_values(&labels);
+                for (_, state) in THREAD_ATTRIBUTES {
+                    let _ = metrics.process_metrics.threads.remove_label_values(
+                        &metrics.process_label_values(container, pid, process_name, &[state]),
+                    );
+                }
 
-            let _ = metrics
-                .process_metrics
-                .open_file_limit
-                .remove_label_values(&[container, pid, process_name]);
+                // Remove CPU and Memory metrics
+                let _ = metrics
+                    .process_metrics
+                    .cpu_usage
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .cpu_usage_normalized
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .memory_usage
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .memory_usage_percentage
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .virtual_memory
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .disk_read_bytes
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .disk_write_bytes
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .voluntary_ctxt_switches
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .nonvoluntary_ctxt_switches
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .major_page_faults
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .minor_page_faults
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .start_time
+                    .remove_label_values(&labels);
+                let _ = metrics.process_metrics.up_time.remove_label_values(&labels);
+                let _ = metrics.process_metrics.up.remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .jstat_version_mismatch
+                    .remove_label_values(&labels);
+                for metric in metrics.process_metrics.jstat_gc_bytes_metrics.values() {
+                    let _ = metric.remove_label_values(&labels);
+                }
+                for metric in metrics
+                    .process_metrics
+                    .jstat_gcutil_percent_metrics
+                    .values()
+                {
+                    let _ = metric.remove_label_values(&labels);
+                }
+                let _ = metrics
+                    .process_metrics
+                    .heap_used_percentage
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .gc_time_percentage
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .gc_young_per_sec
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .jstat_sample_timestamp
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .gc_full_per_sec
+                    .remove_label_values(&labels);
+
+                let _ = metrics
+                    .process_metrics
+                    .open_file
+                    .remove_label_values(&labels);
 
-            for state in TCP_STATES {
                 let _ = metrics
                     .process_metrics
-                    .tcp_connection_states
-                    .remove_label_values(&[container, pid, process_name, state]);
+                    .open_file_limit
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .open_file_soft_limit
+                    .remove_label_values(&labels);
+                let _ = metrics
+                    .process_metrics
+                    .open_file_hard_limit
+                    .remove_label_values(&labels);
+
+                for state_labels in tcp_state_label_values(metrics.tcp_address_family) {
+                    let _ = metrics
+                        .process_metrics
+                        .tcp_connection_states
+                        .remove_label_values(&metrics.process_label_values(
+                            container,
+                            pid,
+                            process_name,
+                            &state_labels,
+                        ));
+                }
             }
 
             // Remove jstat metrics
@@ -198,19 +777,59 @@ async fn update_metrics(
                 );
                 if let Some(metric_names) = jstat_labels.get(&key_jstat) {
                     if let Some(metric) = metrics.process_metrics.jstat_metrics_map.get(command) {
-                        for metric_name in metric_names.iter() {
-                            let _ = metric.remove_label_values(&[
+                        for metric_name in metric_names.iter().filter(|_| !still_reported) {
+                            let _ = metric.remove_label_values(&metrics.process_label_values(
                                 container,
                                 pid,
                                 process_name,
-                                metric_name,
-                            ]);
+                                &[metric_name],
+                            ));
                         }
                     }
                 }
                 // Remove recorded metric_names
                 jstat_labels.remove(&key_jstat);
             }
+
+            // Remove jcmd metrics
+            if let Some(categories) = jcmd_labels.remove(key) {
+                for category in categories.iter().filter(|_| !still_reported) {
+                    let labels =
+                        metrics.process_label_values(container, pid, process_name, &[category]);
+                    let _ = metrics
+                        .process_metrics
+                        .native_memory_reserved
+                        .remove_label_values(&labels);
+                    let _ = metrics
+                        .process_metrics
+                        .native_memory_committed
+                        .remove_label_values(&labels);
+                }
+            }
+
+            // Remove Jolokia MBean metrics
+            if let Some(attributes) = jolokia_labels.remove(key) {
+                for (mbean, attribute) in attributes.iter().filter(|_| !still_reported) {
+                    let labels = metrics.process_label_values(
+                        container,
+                        pid,
+                        process_name,
+                        &[mbean, attribute],
+                    );
+                    let _ = metrics
+                        .process_metrics
+                        .jolokia_mbean_value
+                        .remove_label_values(&labels);
+                }
+            }
+
+            if let Some(ports) = listening_ports.remove(key) {
+                for port in ports.iter().filter(|_| !still_reported) {
+                    let _ = metrics.process_metrics.listening_ports.remove_label_values(
+                        &metrics.process_label_values(container, pid, process_name, &[port]),
+                    );
+                }
+            }
         }
     }
 
@@ -220,64 +839,246 @@ async fn update_metrics(
         *active_pids = current_pids.clone();
     }
 
+    detect_java_homes(&metrics, &mut all_processes, java_home, deadline).await;
+
+    // Look up the Java version of newly discovered JVMs
+    let run_as = |proc_info: &ProcessInfo| -> Option<String> {
+        if run_jstat_as_owner && proc_info.container == "host" {
+            proc_info
+                .user
+                .clone()
+                .filter(|user| Some(user) != exporter_user.as_ref())
+        } else {
+            None
+        }
+    };
+    update_java_versions(
+        Arc::clone(&metrics),
+        &all_processes,
+        java_home,
+        &run_as,
+        deadline,
+    )
+    .await;
+    update_process_args(&metrics, &all_processes, java_home, &run_as, deadline).await;
+    // java -version forks a JVM for every new PID, disabling jvm_version_info skips it
+    if metrics.exports("jvm_version_info") {
+        update_java_runtimes(Arc::clone(&metrics), &all_processes, &run_as, deadline).await;
+    }
+
+    // Update jcmd native memory metrics
+    if config.enable_jcmd.unwrap_or_default() {
+        update_jcmd_metrics(
+            Arc::clone(&metrics),
+            &all_processes,
+            java_home,
+            &run_as,
+            deadline,
+        )
+        .await;
+    }
+
     // Update CPU and Memory metrics
     if let Err(e) = update_cpu_memory_metrics(Arc::clone(&metrics), &all_processes).await {
         error!("Failed to update CPU and memory metrics: {}", e);
     }
 
     // Update System metrics
-    if let Err(e) = update_system_metrics(Arc::clone(&metrics)).await {
-        error!("Failed to update system metrics: {}", e);
+    if metrics.collect_system_metrics {
+        if let Err(e) = update_system_metrics(Arc::clone(&metrics)).await {
+            error!("Failed to update system metrics: {}", e);
+        }
     }
 
-    // Update jstat metrics
-    let tasks: Vec<_> = all_processes
+    // Skip jstat for processes it keeps failing for, until their cooldown is over, and for
+    // containers whose runtime is not answering
+    let now = Instant::now();
+    let runtime_outages: HashSet<&'static str> = metrics
+        .runtime_outages
+        .lock()
+        .await
+        .keys()
+        .copied()
+        .collect();
+    let open_circuits: HashSet<String> = metrics
+        .jstat_failures
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, failures)| failures.skip_until.is_some_and(|until| until > now))
+        .map(|(key, _)| key.clone())
+        .collect();
+    let discovered_processes = all_processes.len();
+    let (skipped_processes, jstat_processes): (Vec<ProcessInfo>, Vec<ProcessInfo>) = all_processes
         .into_iter()
         .filter(|proc_info| proc_info.container != "system")
+        .partition(|proc_info| {
+            open_circuits.contains(&format!("{}#{}", proc_info.container, proc_info.pid))
+                || proc_info
+                    .runtime
+                    .is_some_and(|runtime| runtime_outages.contains(runtime))
+        });
+
+    // JVMs with a Jolokia agent in jolokia_urls are read over HTTP instead of running jstat.
+    // A process_name key is only used when a single JVM has that name, they cannot share an agent.
+    let mut jolokia_urls = config.jolokia_urls.clone().unwrap_or_default();
+    let mut named: HashMap<&str, usize> = HashMap::new();
+    for proc_info in &jstat_processes {
+        *named.entry(proc_info.process.as_str()).or_default() += 1;
+    }
+    jolokia_urls.retain(|key, _| match named.get(key.as_str()) {
+        Some(&count) if count > 1 => {
+            warn!(
+                "Ignoring jolokia_urls entry {}, {} JVMs have that process_name, key them by container#pid instead",
+                key, count
+            );
+            false
+        }
+        _ => true,
+    });
+    let jolokia_mbeans = config.jolokia_mbeans.clone().unwrap_or_default();
+    let mut jolokia_processes = Vec::new();
+    let jstat_processes: Vec<ProcessInfo> = jstat_processes
+        .into_iter()
+        .filter_map(|proc_info| {
+            match jolokia_url(
+                &jolokia_urls,
+                &proc_info.container,
+                &proc_info.pid,
+                &proc_info.process,
+            ) {
+                Some(url) => {
+                    jolokia_processes.push((url.to_string(), proc_info));
+                    None
+                }
+                None => Some(proc_info),
+            }
+        })
+        .collect();
+    let jolokia_tasks: Vec<_> = jolokia_processes
+        .into_iter()
+        .map(|(url, proc_info)| {
+            let mbeans = jolokia_mbeans.clone();
+            tokio::spawn(async move {
+                let sample = match fetch_jolokia(url.clone(), mbeans).await {
+                    Ok(sample) => Some(sample),
+                    Err(err) => {
+                        warn!(
+                            "Failed to read Jolokia metrics from {} for PID {} ({} in {}): {}",
+                            url, proc_info.pid, proc_info.process, proc_info.container, err
+                        );
+                        None
+                    }
+                };
+                (
+                    proc_info.container,
+                    proc_info.pid,
+                    proc_info.process,
+                    sample,
+                )
+            })
+        })
+        .collect();
+
+    // Update jstat metrics
+    let tasks: Vec<_> = jstat_processes
+        .into_iter()
         .flat_map(|proc_info| {
             let metrics = Arc::clone(&metrics);
-            let java_home = java_home.map(|s| s.to_string());
+            // The JDK jstat runs from for this process, not necessarily the global java_home
+            let jstat_java_home = process_java_home(&metrics, &proc_info, java_home);
             let container = proc_info.container.clone();
+            let runtime = proc_info.runtime;
             let pid = proc_info.pid.clone();
             let process = proc_info.process.clone();
-            JSTAT_COMMANDS
+            let run_as = run_as(&proc_info);
+            let runner = proc_info
+                .runner
+                .clone()
+                .unwrap_or_else(|| Arc::clone(&metrics.runner));
+            let gc_cause = metrics.exports("jvm_last_gc_cause_info")
+                && jstat_extra_commands.iter().any(|c| c == "-gccause");
+            let mut commands: Vec<&'static str> = JSTAT_COMMANDS
                 .iter()
-                .map(move |&command| {
+                .copied()
+                .filter(|command| runs_jstat_command(&metrics, &jstat_extra_commands, command))
+                .collect();
+            // -gccause prints the -gcutil columns followed by the GC causes, so when both are
+            // listed it is run once in place of -gcutil
+            if gc_cause && !commands.contains(&"-gcutil") {
+                commands.push("-gccause");
+            }
+            commands
+                .into_iter()
+                .map(move |command| {
                     let metrics = Arc::clone(&metrics);
-                    let java_home = java_home.clone();
+                    let runner = Arc::clone(&runner);
+                    let jstat_java_home = jstat_java_home.clone();
                     let container = container.clone();
                     let pid = pid.clone();
                     let process = process.clone();
+                    let run_as = run_as.clone();
 
                     tokio::spawn(async move {
-                        if let Some(metric) = metrics.process_metrics.jstat_metrics_map.get(command)
-                        {
-                            match fetch_and_update_jstat(
-                                &container,
-                                &pid,
-                                &process,
-                                command,
-                                metric,
-                                java_home.as_deref(),
-                            )
-                            .await
-                            {
-                                Ok(metric_names) => {
-                                    // Record metric_names
-                                    let mut jstat_labels = metrics.jstat_labels.lock().await;
-                                    let key =
-                                        (command, container.clone(), pid.clone(), process.clone());
-                                    jstat_labels
-                                        .entry(key)
-                                        .or_insert_with(HashSet::new)
-                                        .extend(metric_names);
-                                }
-                                Err(err) => {
-                                    warn!(
-                                        "Failed to update {} metrics for PID {} ({} in {}): {}",
-                                        command, pid, process, container, err
-                                    );
-                                }
+                        // Skip processes without a HotSpot JVM, e.g. GraalVM native images
+                        let key = format!("{}#{}", container, pid);
+                        if metrics.non_hotspot_pids.lock().await.contains(&key) {
+                            return None;
+                        }
+                        if container == "host" && !has_hsperfdata(&pid) {
+                            mark_non_hotspot(&metrics, key, &pid, &process, &container).await;
+                            return None;
+                        }
+
+                        let jstat_command = if command == "-gcutil" && gc_cause {
+                            "-gccause"
+                        } else {
+                            command
+                        };
+
+                        let result = fetch_jstat_with_retries(
+                            &metrics,
+                            runner.as_ref(),
+                            &container,
+                            runtime,
+                            &pid,
+                            &process,
+                            jstat_command,
+                            jstat_single_snapshot,
+                            jstat_java_home.as_deref(),
+                            run_as.as_deref(),
+                            jstat_retries,
+                            JSTAT_TIMEOUT,
+                        )
+                        .await;
+
+                        match result {
+                            Ok(sample) => Some((command, container, pid, process, Some(sample))),
+                            Err(err) if is_non_hotspot_error(&err.to_string()) => {
+                                mark_non_hotspot(&metrics, key, &pid, &process, &container).await;
+                                None
+                            }
+                            Err(err) if is_version_mismatch_error(&err.to_string()) => {
+                                // Explained once per PID, jvm_jstat_version_mismatch reports it after
+                                if metrics.jstat_version_mismatches.lock().await.insert(key) {
+                                    warn!(
+                                        "jstat {} for PID {} ({} in {}) failed, the JDK jstat ran from ({}) does not seem to match the JVM's. Leave java_home and --java-home unset so that jstat runs from the JDK of each JVM, or set container_java_homes for containers: {}",
+                                        command,
+                                        pid,
+                                        process,
+                                        container,
+                                        jstat_java_home.as_deref().unwrap_or("the PATH"),
+                                        err
+                                    );
+                                }
+                                Some((command, container, pid, process, None))
+                            }
+                            Err(err) => {
+                                warn!(
+                                    "Failed to update {} metrics for PID {} ({} in {}): {}",
+                                    command, pid, process, container, err
+                                );
+                                Some((command, container, pid, process, None))
                             }
                         }
                     })
@@ -285,662 +1086,4717 @@ async fn update_metrics(
                 .collect::<Vec<_>>()
         })
         .collect();
+    // Dropping the collection, e.g. when the exporter shuts down, aborts the tasks still running
+    // instead of leaving jstat and Jolokia requests running detached
+    let _abort_tasks = (AbortOnDrop::new(&tasks), AbortOnDrop::new(&jolokia_tasks));
 
-    futures::future::join_all(tasks).await;
-
-    Ok(())
-}
+    let (results, timed_out) = join_until(tasks, deadline).await;
+    let (jolokia_results, jolokia_timed_out) = join_until(jolokia_tasks, deadline).await;
+    // The jcmd, java -version and JDK lookups before jstat are bounded by the deadline too
+    let past_deadline = deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline);
+    if timed_out || jolokia_timed_out || past_deadline {
+        warn!("Collection hit scrape_deadline_ms, keeping the results collected so far");
+        metrics.system_metrics.scrape_timeouts.inc();
+    }
 
-async fn fetch_and_update_jstat(
-    container: &String,
-    pid: &String,
-    process: &String,
-    command: &str,
-    jstat_metrics: &GaugeVec,
-    java_home: Option<&str>,
-) -> Result<HashSet<String>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut cmd = if container == "host" {
-        let mut command_host = Command::new("jstat");
-        command_host.args(&[command, pid, "1000", "1"]);
-        if let Some(jh) = java_home {
-            command_host.env("JAVA_HOME", jh);
-            command_host.env(
-                "PATH",
-                format!("{}/bin:{}", jh, std::env::var("PATH").unwrap_or_default()),
+    let mut updates = GaugeUpdates::default();
+    let up_times = metrics.up_times.lock().await.clone();
+    let mut jstat_labels = metrics.jstat_labels.lock().await;
+    // Jolokia samples feed the jstat -gc metrics like a jstat run, their threads and MBeans are
+    // set after the jstat results
+    let mut results: Vec<_> = results.into_iter().flatten().flatten().collect();
+    // jstat is available in a scope when one of its runs there succeeded, scopes without JVMs
+    // keep their last value instead of running jstat just to check it
+    let mut jstat_available: HashMap<String, bool> = HashMap::new();
+    for (_, container, _, _, values) in &results {
+        *jstat_available.entry(container.clone()).or_default() |= values.is_some();
+    }
+    for (container, available) in jstat_available {
+        set_dependency_available(
+            &metrics.system_metrics.dependency_available,
+            "jstat",
+            &container,
+            available,
+        );
+    }
+    let mut jolokia_samples = Vec::new();
+    for (container, pid, process, sample) in jolokia_results.into_iter().flatten() {
+        let values = sample.map(|sample| {
+            jolokia_samples.push((
+                container.clone(),
+                pid.clone(),
+                process.clone(),
+                sample.threads,
+                sample.mbeans,
+            ));
+            (sample.gc_values, None)
+        });
+        results.push(("-gc", container, pid, process, values));
+    }
+    // A process is up when at least one jstat command, or its Jolokia request, succeeded for it
+    let mut jstat_up: HashMap<(String, String, String), bool> = HashMap::new();
+    for (command, container, pid, process, values) in results {
+        *jstat_up
+            .entry((container.clone(), pid.clone(), process.clone()))
+            .or_default() |= values.is_some();
+        let Some((mut values, gc_cause)) = values else {
+            continue;
+        };
+        // The uptime printed by -t is exported on its own, not as a jstat column
+        if let Some(index) = values.iter().position(|(h, _)| h == JSTAT_TIMESTAMP_HEADER) {
+            let (_, timestamp) = values.remove(index);
+            let labels = metrics.process_label_values(&container, &pid, &process, &[]);
+            updates.max(
+                &metrics.process_metrics.jstat_sample_timestamp,
+                &labels,
+                timestamp,
             );
         }
-        command_host
-    } else {
-        // Execute jstat inside the container
-        if is_docker_available().await {
-            let mut cmd_docker = Command::new("docker");
-            cmd_docker.args(&["exec", container, "jstat", command, pid, "1000", "1"]);
-            if let Some(jh) = java_home {
-                cmd_docker.env("JAVA_HOME", jh);
-                cmd_docker.env(
-                    "PATH",
-                    format!("{}/bin:{}", jh, std::env::var("PATH").unwrap_or_default()),
+        if command == "-gc" {
+            let labels = metrics.process_label_values(&container, &pid, &process, &[]);
+            for (header, value) in values.iter() {
+                if let Some(metric) = metrics
+                    .process_metrics
+                    .jstat_gc_bytes_metrics
+                    .get(header.as_str())
+                {
+                    // jstat reports capacities and usage in KB
+                    updates.sum(metric, &labels, *value * 1024.0);
+                }
+            }
+            // When aggregating, report the most utilized heap
+            if let Some(percentage) = heap_used_percentage(&values) {
+                updates.max(
+                    &metrics.process_metrics.heap_used_percentage,
+                    &labels,
+                    percentage,
                 );
             }
-            cmd_docker
-        } else if is_crictl_available().await {
-            let mut cmd_crictl = Command::new("crictl");
-            cmd_crictl.args(&["exec", container, "jstat", command, pid, "1000", "1"]);
-            if let Some(jh) = java_home {
-                cmd_crictl.env("JAVA_HOME", jh);
-                cmd_crictl.env(
-                    "PATH",
-                    format!("{}/bin:{}", jh, std::env::var("PATH").unwrap_or_default()),
+            for &(column, _, _) in JSTAT_GCUTIL_PERCENT_METRICS.iter() {
+                if let Some(percentage) = space_utilization(&values, column) {
+                    updates.max(
+                        &metrics.process_metrics.jstat_gcutil_percent_metrics[column],
+                        &labels,
+                        percentage,
+                    );
+                }
+            }
+            // Uptime comes from update_cpu_memory_metrics, only for host processes
+            let up_time = up_times.get(&format!("{}#{}", container, pid)).copied();
+            if let Some(percentage) = up_time.and_then(|up| gc_time_percentage(&values, up)) {
+                updates.max(
+                    &metrics.process_metrics.gc_time_percentage,
+                    &labels,
+                    percentage,
                 );
             }
-            cmd_crictl
-        } else {
-            return Err(
-                "Neither Docker nor crictl is available to execute commands in containers".into(),
+            if gc_rates {
+                let key = format!("{}#{}", container, pid);
+                if let Some((young_per_sec, full_per_sec)) =
+                    gc_rates_since_last_sample(&mut *metrics.gc_counts.lock().await, key, &values)
+                {
+                    updates.sum(
+                        &metrics.process_metrics.gc_young_per_sec,
+                        &labels,
+                        young_per_sec,
+                    );
+                    updates.sum(
+                        &metrics.process_metrics.gc_full_per_sec,
+                        &labels,
+                        full_per_sec,
+                    );
+                }
+            }
+        }
+        // The series of the previous cause is removed when the cause changes
+        if let Some(cause) = gc_cause {
+            let previous_cause = metrics
+                .gc_causes
+                .lock()
+                .await
+                .insert(format!("{}#{}", container, pid), cause.clone());
+            if let Some(previous_cause) = previous_cause.filter(|c| *c != cause) {
+                let previous =
+                    metrics.process_label_values(&container, &pid, &process, &[&previous_cause]);
+                let _ = metrics
+                    .process_metrics
+                    .last_gc_cause_info
+                    .remove_label_values(&previous);
+            }
+            updates.max(
+                &metrics.process_metrics.last_gc_cause_info,
+                &metrics.process_label_values(&container, &pid, &process, &[&cause]),
+                1.0,
             );
         }
-    };
-
-    let output = cmd.output().await?;
-
-    if !output.status.success() {
-        return Err(format!(
-            "jstat {} failed for PID {} in container {}: {}",
-            command,
-            pid,
-            container,
-            String::from_utf8_lossy(&output.stderr)
-        )
-        .into());
+        // Derived metrics above use every column, only listed ones are exported as is
+        let values: Vec<(String, f64)> = match jstat_columns.get(command) {
+            Some(columns) => values
+                .into_iter()
+                .filter(|(header, _)| columns.contains(header))
+                .collect(),
+            None => values,
+        };
+        // Over max_series, columns not exported before for the process are not added
+        let values: Vec<(String, f64)> = if series_capped {
+            let known =
+                jstat_labels.get(&(command, container.clone(), pid.clone(), process.clone()));
+            values
+                .into_iter()
+                .filter(|(header, _)| known.is_some_and(|known| known.contains(header)))
+                .collect()
+        } else {
+            values
+        };
+        // -gccause on its own only reports the GC cause
+        if let Some(metric) = metrics.process_metrics.jstat_metrics_map.get(command) {
+            for (header, value) in values.iter() {
+                let labels = metrics.process_label_values(&container, &pid, &process, &[header]);
+                combine_jstat_column(&mut updates, metric, &labels, command, header, *value);
+            }
+            // Record metric_names
+            jstat_labels
+                .entry((command, container, pid, process))
+                .or_insert_with(HashSet::new)
+                .extend(values.into_iter().map(|(header, _)| header));
+        }
     }
+    drop(jstat_labels);
 
-    let stdout = String::from_utf8(output.stdout)?;
-    let lines: Vec<&str> = stdout.lines().collect();
-    if lines.len() < 2 {
-        return Err("Unexpected jstat output".into());
+    let mut jolokia_labels = metrics.jolokia_labels.lock().await;
+    for (container, pid, process, threads, mbean_values) in &jolokia_samples {
+        for (state, value) in threads {
+            updates.sum(
+                &metrics.process_metrics.threads,
+                &metrics.process_label_values(container, pid, process, &[state]),
+                *value,
+            );
+        }
+        let known = jolokia_labels
+            .entry(format!("{}#{}", container, pid))
+            .or_default();
+        for (mbean, attribute, value) in mbean_values {
+            // Over max_series, attributes not exported before for the process are not added
+            let label = (mbean.clone(), attribute.clone());
+            if series_capped && !known.contains(&label) {
+                continue;
+            }
+            updates.sum(
+                &metrics.process_metrics.jolokia_mbean_value,
+                &metrics.process_label_values(container, pid, process, &[mbean, attribute]),
+                *value,
+            );
+            known.insert(label);
+        }
     }
+    drop(jolokia_labels);
 
-    let headers: Vec<&str> = lines[0].split_whitespace().collect();
-    let values: Vec<&str> = lines[1].split_whitespace().collect();
-
-    let mut metric_names = HashSet::new();
-
-    if headers.len() != values.len() {
-        warn!(
-           "Mismatch in headers and values count for command {} for PID {} in container {}: headers = {:?}, values = {:?}",
-           command, pid, container, headers, values
-       );
-        // Only process matching header-value pairs
-        let min_len = std::cmp::min(headers.len(), values.len());
-        for i in 0..min_len {
-            let header = headers[i];
-            let value = values[i];
-            let parsed_value = value.parse::<f64>().unwrap_or(0.0);
-            jstat_metrics
-                .with_label_values(&[container, pid, process, header])
-                .set(parsed_value);
-            metric_names.insert(header.to_string());
+    let mut jstat_failures = metrics.jstat_failures.lock().await;
+    let mut jstat_version_mismatches = metrics.jstat_version_mismatches.lock().await;
+    for ((container, pid, process), up) in &jstat_up {
+        let key = format!("{}#{}", container, pid);
+        if *up {
+            jstat_version_mismatches.remove(&key);
         }
-    } else {
-        for (header, value) in headers.iter().zip(values.iter()) {
-            let parsed_value = if *value == "-" {
-                0.0
+        updates.max(
+            &metrics.process_metrics.jstat_version_mismatch,
+            &metrics.process_label_values(container, pid, process, &[]),
+            if jstat_version_mismatches.contains(&key) {
+                1.0
             } else {
-                match value.parse::<f64>() {
-                    Ok(v) => v,
-                    Err(_) => {
-                        warn!(
-                           "Failed to parse value for {}: {} in PID {} and Process {} in container {}",
-                           header, value, pid, process, container
-                       );
-                        continue;
-                    }
-                }
-            };
-
-            jstat_metrics
-                .with_label_values(&[container, pid, process, header])
-                .set(parsed_value);
-            metric_names.insert(header.to_string());
+                0.0
+            },
+        );
+        if *up {
+            jstat_failures.remove(&key);
+        } else {
+            let failures = jstat_failures.entry(key).or_default();
+            failures.consecutive += 1;
+            if failures.consecutive >= JSTAT_FAILURE_THRESHOLD {
+                failures.skip_until = Some(now + JSTAT_COOLDOWN);
+                warn!(
+                    "jstat failed {} times in a row for PID {} ({} in {}), skipping it for {}s",
+                    failures.consecutive,
+                    pid,
+                    process,
+                    container,
+                    JSTAT_COOLDOWN.as_secs()
+                );
+            }
         }
+        // When aggregating, the process is up if any of its PIDs is
+        updates.max(
+            &metrics.process_metrics.up,
+            &metrics.process_label_values(container, pid, process, &[]),
+            if *up { 1.0 } else { 0.0 },
+        );
+    }
+    for proc_info in &skipped_processes {
+        updates.max(
+            &metrics.process_metrics.up,
+            &metrics.process_label_values(
+                &proc_info.container,
+                &proc_info.pid,
+                &proc_info.process,
+                &[],
+            ),
+            0.0,
+        );
+    }
+    updates.apply();
+
+    let containers_scanned = if config.detects_containers() {
+        metrics.containers.lock().await.len()
+    } else {
+        0
+    };
+    metrics
+        .system_metrics
+        .java_processes
+        .set(discovered_processes as f64);
+    metrics
+        .system_metrics
+        .containers_scanned
+        .set(containers_scanned as f64);
+    // Counting the series collects every metric, it is only done when series were added or removed
+    let fingerprint = series_fingerprint(&metrics, config.max_series).await;
+    if metrics
+        .series_fingerprint
+        .swap(fingerprint, Ordering::Relaxed)
+        != fingerprint
+    {
+        metrics.check_series_limit();
     }
-    Ok(metric_names)
+
+    Ok(())
 }
 
-// Update CPU and Memory metrics
-async fn update_cpu_memory_metrics(
-    metrics: Arc<Metrics>,
-    processes: &[ProcessInfo],
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut system = System::new_all();
-    system.refresh_all();
+// Hash of the number of PIDs and of the labels in the per-PID caches, which change when series
+// are added or removed, and of max_series
+async fn series_fingerprint(metrics: &Metrics, max_series: Option<usize>) -> u64 {
+    use std::hash::{Hash, Hasher};
 
-    tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+    let counts = [
+        metrics.active_pids.lock().await.len(),
+        metrics.containers.lock().await.len(),
+        label_count(&metrics.jstat_labels.lock().await),
+        label_count(&metrics.jcmd_labels.lock().await),
+        label_count(&metrics.jolokia_labels.lock().await),
+        label_count(&metrics.listening_ports.lock().await),
+    ];
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (max_series, counts).hash(&mut hasher);
+    hasher.finish()
+}
 
-    system.refresh_processes_specifics(
-        sysinfo::ProcessesToUpdate::All,
-        true,
-        sysinfo::ProcessRefreshKind::nothing().with_cpu(),
-    );
-    let total_memory_kb = system.total_memory() as f64;
+fn label_count<K, T>(cache: &HashMap<K, HashSet<T>>) -> usize {
+    cache.values().map(HashSet::len).sum()
+}
 
-    let af_flags = AddressFamilyFlags::IPV4 | AddressFamilyFlags::IPV6;
-    let proto_flags = ProtocolFlags::TCP;
+// A compiled pattern of system_processes
+pub(crate) enum ProcessPattern {
+    Regex(Regex),
+    Glob(glob::Pattern),
+}
 
-    let sockets = get_sockets_info(af_flags, proto_flags)?;
+impl ProcessPattern {
+    // Regexes match anywhere in the name, globs the whole name
+    fn matches(&self, process_name: &str) -> bool {
+        match self {
+            ProcessPattern::Regex(regex) => regex.is_match(process_name),
+            ProcessPattern::Glob(glob) => glob.matches(process_name),
+        }
+    }
+}
 
-    for proc_info in processes.iter() {
-        let pid_str = &proc_info.pid;
-        let container = &proc_info.container;
-        let process = &proc_info.process;
+// Compile the system_processes patterns, invalid ones are logged and ignored
+fn compile_system_processes(
+    patterns: &[String],
+    match_type: ProcessMatchType,
+) -> Vec<ProcessPattern> {
+    patterns
+        .iter()
+        .filter_map(|pattern| {
+            compile_process_pattern(pattern, match_type)
+                .map_err(|e| warn!("Invalid system_processes pattern {}: {}", pattern, e))
+                .ok()
+        })
+        .collect()
+}
 
-        // Extract class name (last part of the package path)
-        let class_name = process.split('.').last().unwrap_or(process);
+// Errors of the system_processes patterns that would be ignored, for --check-config
+pub(crate) fn system_process_pattern_errors(
+    patterns: &[String],
+    match_type: ProcessMatchType,
+) -> Vec<String> {
+    patterns
+        .iter()
+        .filter_map(|pattern| {
+            let e = compile_process_pattern(pattern, match_type).err()?;
+            Some(format!("invalid pattern {}: {}", pattern, e))
+        })
+        .collect()
+}
 
-        // Check if class name is in the exclusion list
-        if EXCLUDED_PROCESSES
-            .iter()
-            .any(|&excluded| excluded.eq_ignore_ascii_case(class_name))
-        {
-            warn!("Excluding process PID {}: {}", pid_str, class_name);
-            continue;
-        }
+fn compile_process_pattern(
+    pattern: &str,
+    match_type: ProcessMatchType,
+) -> Result<ProcessPattern, String> {
+    match match_type {
+        ProcessMatchType::Regex => Regex::new(pattern)
+            .map(ProcessPattern::Regex)
+            .map_err(|e| e.to_string()),
+        ProcessMatchType::Glob => glob::Pattern::new(pattern)
+            .map(ProcessPattern::Glob)
+            .map_err(|e| e.to_string()),
+    }
+}
 
-        if let Ok(pid) = pid_str.parse::<usize>() {
-            if let Some(process_info) = system.process(sysinfo::Pid::from(pid)) {
-                // Update CPU usage
-                metrics
-                    .process_metrics
-                    .cpu_usage
-                    .with_label_values(&[container, pid_str, process])
-                    .set(process_info.cpu_usage() as f64);
+// Heap usage percentage from the survivor, eden and old generation columns of jstat -gc,
+// None when a column is missing or the capacity is zero
+fn heap_used_percentage(values: &[(String, f64)]) -> Option<f64> {
+    let column = |name: &str| values.iter().find(|(h, _)| h == name).map(|(_, v)| *v);
+    let mut used = 0.0;
+    let mut capacity = 0.0;
+    for space in ["S0", "S1", "E", "O"] {
+        used += column(&format!("{}U", space))?;
+        capacity += column(&format!("{}C", space))?;
+    }
+    if capacity > 0.0 {
+        Some(used / capacity * 100.0)
+    } else {
+        None
+    }
+}
 
-                // Update Memory usage (in bytes)
-                metrics
-                    .process_metrics
-                    .memory_usage
-                    .with_label_values(&[container, pid_str, process])
-                    .set(process_info.memory() as f64); // Convert KB to Bytes
+// Utilization percentage of a space, e.g. "O" from the OU and OC columns of jstat -gc, the
+// same as its column of jstat -gcutil
+fn space_utilization(values: &[(String, f64)], space: &str) -> Option<f64> {
+    let column = |name: String| values.iter().find(|(h, _)| *h == name).map(|(_, v)| *v);
+    let used = column(format!("{}U", space))?;
+    let capacity = column(format!("{}C", space))?;
+    if capacity > 0.0 {
+        Some(used / capacity * 100.0)
+    } else {
+        None
+    }
+}
 
-                let process_memory_kb = process_info.memory() as f64;
-                let memory_usage_percentage = if total_memory_kb > 0.0 {
-                    (process_memory_kb / total_memory_kb) * 100.0
-                } else {
-                    0.0
-                };
+// Whether a jstat command runs for each JVM. -gc also feeds the heap, metaspace, utilization and
+// GC rate metrics, it always runs. The others run while their metrics are exported, those of
+// JSTAT_OPTIONAL_COMMANDS only once listed in jstat_extra_commands.
+fn runs_jstat_command(metrics: &Metrics, extra_commands: &[String], command: &str) -> bool {
+    command == "-gc"
+        || ((!JSTAT_OPTIONAL_COMMANDS.contains(&command)
+            || extra_commands.iter().any(|c| c == command))
+            && metrics.exports(&format!("jstat_{}_metrics", &command[1..])))
+}
 
-                metrics
-                    .process_metrics
-                    .memory_usage_percentage
-                    .with_label_values(&[container, pid_str, process])
-                    .set(memory_usage_percentage);
+// Percentage of the uptime spent in GC, from the total GC time column of jstat -gc
+fn gc_time_percentage(values: &[(String, f64)], up_time_secs: f64) -> Option<f64> {
+    let gc_time_secs = values.iter().find(|(h, _)| h == "GCT").map(|(_, v)| *v)?;
+    if up_time_secs > 0.0 {
+        Some((gc_time_secs / up_time_secs * 100.0).clamp(0.0, 100.0))
+    } else {
+        None
+    }
+}
 
-                let start_time_secs = process_info.start_time() as f64;
-                let up_time_secs = process_info.run_time() as f64;
+// Wait for spawned tasks until the deadline, if any. Tasks still running at the deadline are aborted
+// and left out of the results, returns whether that happened.
+async fn join_until<T>(
+    tasks: Vec<tokio::task::JoinHandle<T>>,
+    deadline: Option<tokio::time::Instant>,
+) -> (Vec<Result<T, tokio::task::JoinError>>, bool) {
+    let Some(deadline) = deadline else {
+        return (futures::future::join_all(tasks).await, false);
+    };
+    let mut pending: futures::stream::FuturesUnordered<_> = tasks.into_iter().collect();
+    let mut results = Vec::new();
+    loop {
+        match tokio::time::timeout_at(deadline, pending.next()).await {
+            Ok(Some(result)) => results.push(result),
+            Ok(None) => return (results, false),
+            Err(_) => {
+                pending.iter().for_each(|task| task.abort());
+                return (results, true);
+            }
+        }
+    }
+}
 
-                metrics
-                    .process_metrics
-                    .start_time
-                    .with_label_values(&[container, pid_str, process])
-                    .set(start_time_secs);
+// Wait for futures until the deadline, if any. Futures still pending at the deadline are dropped,
+// which kills the commands they run, and left out of the results.
+async fn join_all_until<F: std::future::Future>(
+    futures: impl IntoIterator<Item = F>,
+    deadline: Option<tokio::time::Instant>,
+) -> Vec<F::Output> {
+    let Some(deadline) = deadline else {
+        return futures::future::join_all(futures).await;
+    };
+    let mut pending: futures::stream::FuturesUnordered<F> = futures.into_iter().collect();
+    let mut results = Vec::new();
+    while let Ok(Some(result)) = tokio::time::timeout_at(deadline, pending.next()).await {
+        results.push(result);
+    }
+    results
+}
 
-                metrics
-                    .process_metrics
-                    .up_time
-                    .with_label_values(&[container, pid_str, process])
-                    .set(up_time_secs);
+// Aborts spawned tasks when dropped, tying them to the collection that spawned them. Tasks
+// that already finished are not affected.
+struct AbortOnDrop(Vec<tokio::task::AbortHandle>);
 
-                let open_file = process_info.open_files().unwrap_or(0) as f64;
-                let open_file_limit = process_info.open_files_limit().unwrap_or(0) as f64;
-                metrics
-                    .process_metrics
-                    .open_file
-                    .with_label_values(&[container, pid_str, process])
-                    .set(open_file);
+impl AbortOnDrop {
+    fn new<T>(tasks: &[tokio::task::JoinHandle<T>]) -> Self {
+        AbortOnDrop(tasks.iter().map(|task| task.abort_handle()).collect())
+    }
+}
 
-                metrics
-                    .process_metrics
-                    .open_file_limit
-                    .with_label_values(&[container, pid_str, process])
-                    .set(open_file_limit);
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|task| task.abort());
+    }
+}
+
+// Young and full collections per second since the previous jstat -gc sample of a process.
+// None for the first sample, and when the counts went down, which means the PID was reused.
+fn gc_rates_since_last_sample(
+    gc_counts: &mut HashMap<String, (f64, f64, Instant)>,
+    key: String,
+    values: &[(String, f64)],
+) -> Option<(f64, f64)> {
+    let column = |name: &str| values.iter().find(|(h, _)| h == name).map(|(_, v)| *v);
+    let young = column("YGC")?;
+    let full = column("FGC")?;
+    let now = Instant::now();
+    let (last_young, last_full, last_time) = gc_counts.insert(key, (young, full, now))?;
+    let elapsed = now.duration_since(last_time).as_secs_f64();
+    if elapsed <= 0.0 || young < last_young || full < last_full {
+        return None;
+    }
+    Some(((young - last_young) / elapsed, (full - last_full) / elapsed))
+}
 
-                let mut state_counts: HashMap<String, usize> = HashMap::new();
+// Combine a raw jstat column of PIDs aggregated under one process name. Counts, times and sizes
+// add up, while utilization percentages and the -compiler FailedType code would not mean anything
+// summed, so the highest is reported like for jvm_heap_used_percentage.
+fn combine_jstat_column<'a>(
+    updates: &mut GaugeUpdates<'a>,
+    metric: &'a GaugeVec,
+    labels: &[&str],
+    command: &str,
+    header: &str,
+    value: f64,
+) {
+    let is_ratio = match command {
+        "-gcutil" => ["S0", "S1", "E", "O", "M", "CCS"].contains(&header),
+        "-compiler" => header == "FailedType",
+        _ => false,
+    };
+    if is_ratio {
+        updates.max(metric, labels, value);
+    } else {
+        updates.sum(metric, labels, value);
+    }
+}
 
-                for state in TCP_STATES {
-                    state_counts.insert(state.to_string(), 0);
-                }
-                for socket in sockets.iter() {
-                    let associated_pids = &socket.associated_pids;
-                    if let ProtocolSocketInfo::Tcp(tcp_info) = &socket.protocol_socket_info {
-                        // 过滤指定进程的连接
-                        if associated_pids.contains(&pid_str.parse::<u32>().unwrap_or(0)) {
-                            *state_counts.entry(tcp_info.state.to_string()).or_insert(0) += 1;
-                        }
-                    }
-                }
-                for (state, count) in state_counts.iter() {
-                    metrics
-                        .process_metrics
-                        .tcp_connection_states
-                        .with_label_values(&[container, pid_str, process, state])
-                        .set(*count as f64);
-                }
+// Retry attach failures of a momentarily busy JVM. Every run, retries included, has to finish
+// within the timeout so that a hung jstat does not hold the collection up once per retry. A run
+// that times out is not retried.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_jstat_with_retries(
+    metrics: &Metrics,
+    runner: &dyn CommandRunner,
+    container: &String,
+    runtime: Option<&str>,
+    pid: &String,
+    process: &String,
+    command: &'static str,
+    single_snapshot: bool,
+    java_home: Option<&str>,
+    run_as: Option<&str>,
+    retries: u32,
+    timeout: Duration,
+) -> Result<(Vec<(String, f64)>, Option<String>), Box<dyn std::error::Error + Send + Sync>> {
+    let deadline = Instant::now() + timeout;
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let result = tokio::time::timeout(
+            deadline.saturating_duration_since(started),
+            fetch_jstat(
+                runner,
+                container,
+                runtime,
+                pid,
+                process,
+                command,
+                single_snapshot,
+                java_home,
+                run_as,
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(format!(
+                "jstat {} for PID {} ({} in {}) timed out after {}ms",
+                command,
+                pid,
+                process,
+                container,
+                timeout.as_millis()
+            )
+            .into())
+        });
+        metrics
+            .system_metrics
+            .jstat_duration_seconds
+            .with_label_values(&[command])
+            .observe(started.elapsed().as_secs_f64());
+        match result {
+            Err(err)
+                if attempt < retries
+                    && is_transient_jstat_error(&err.to_string(), pid)
+                    && Instant::now() + JSTAT_RETRY_BACKOFF * (attempt + 1) < deadline =>
+            {
+                attempt += 1;
+                info!(
+                    "Retrying jstat {} for PID {} ({} in {}), attempt {}: {}",
+                    command, pid, process, container, attempt, err
+                );
+                tokio::time::sleep(JSTAT_RETRY_BACKOFF * attempt).await;
             }
+            result => return result,
         }
     }
+}
 
-    Ok(())
+// Remember a process that has no HotSpot JVM so jstat is not attempted on every scrape.
+// CPU, memory, open file and TCP metrics are still collected for it.
+async fn mark_non_hotspot(
+    metrics: &Metrics,
+    key: String,
+    pid: &str,
+    process: &str,
+    container: &str,
+) {
+    if metrics.non_hotspot_pids.lock().await.insert(key) {
+        info!(
+            "PID {} ({} in {}) is not a HotSpot JVM, skipping jstat metrics",
+            pid, process, container
+        );
+    }
 }
 
-async fn update_system_metrics(metrics: Arc<Metrics>) -> Result<(), Box<dyn std::error::Error>> {
-    let mut system = System::new_all();
-    system.refresh_all();
-    // Update Memory usage
-    metrics
-        .system_metrics
-        .memory_usage
-        .with_label_values(&["used"])
-        .set(system.used_memory() as f64);
+// jstat reports "not a HotSpot process" for JVMs it cannot attach to. "<pid> not found" is not
+// enough, jstat also reports it for a JVM that exited or was restarted under a new PID.
+fn is_non_hotspot_error(message: &str) -> bool {
+    message.contains("not a HotSpot")
+}
 
-    metrics
-        .system_metrics
-        .total_memory
-        .with_label_values(&["total"])
-        .set(system.total_memory() as f64);
+// jstat of another JDK than the JVM's cannot read its hsperfdata counters or attach to it, e.g.
+// jstat of JDK 8 against a JDK 17 JVM
+fn is_version_mismatch_error(message: &str) -> bool {
+    [
+        "version mismatch",
+        "perfdata version",
+        "Unsupported perfdata",
+        "PerfDataBuffer implementation",
+        "UnsupportedClassVersionError",
+        "Unsupported major.minor version",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
 
-    // Update Disk usage
-    for disk in &Disks::new_with_refreshed_list() {
-        let disk_name = disk.name().to_str().unwrap_or("unknown").to_string();
-        let mount_point = disk.mount_point().to_str().unwrap_or("/").to_string();
-        if mount_point.contains("docker")
-            || mount_point.contains("containerd")
-            || mount_point.contains("kubelet")
-        {
-            continue;
-        }
-        let total_space = disk.total_space() as f64;
-        let available_space = disk.available_space() as f64;
-        let used_space = total_space - available_space;
+// Attach failures that can succeed on a later try, e.g. while the JVM is busy in a safepoint.
+// Errors of processes that are gone or have no HotSpot JVM are not retried.
+fn is_transient_jstat_error(message: &str, pid: &str) -> bool {
+    !is_non_hotspot_error(message)
+        && !message.contains(&format!("{} not found", pid))
+        && !message.contains("No such process")
+        && (message.contains("Could not attach")
+            || message.contains("AttachNotSupportedException")
+            || message.contains("Unable to open socket file"))
+}
 
+// Check whether the process exposes a hsperfdata file, which jstat needs to read JVM counters.
+// The process's own view of /tmp is used so that PrivateTmp services are handled as well.
+#[cfg(target_os = "linux")]
+fn has_hsperfdata(pid: &str) -> bool {
+    let tmp = format!("/proc/{}/root/tmp", pid);
+    let entries = match std::fs::read_dir(&tmp) {
+        Ok(entries) => entries,
+        // Not allowed to look, assume a HotSpot JVM and let jstat decide
+        Err(_) => return true,
+    };
+    entries.flatten().any(|entry| {
+        entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with("hsperfdata_")
+            && entry.path().join(pid).exists()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_hsperfdata(_pid: &str) -> bool {
+    true
+}
+
+// Run jstat for a process and return its columns and values, and the cause of the last GC
+// for -gccause
+#[allow(clippy::too_many_arguments)]
+async fn fetch_jstat(
+    runner: &dyn CommandRunner,
+    container: &String,
+    runtime: Option<&str>,
+    pid: &String,
+    process: &String,
+    command: &str,
+    single_snapshot: bool,
+    java_home: Option<&str>,
+    run_as: Option<&str>,
+) -> Result<(Vec<(String, f64)>, Option<String>), Box<dyn std::error::Error + Send + Sync>> {
+    let args: &[&str] = if single_snapshot {
+        &[command, pid]
+    } else {
+        &[command, pid, "1000", "1"]
+    };
+    let cmd = jdk_tool_command(container, runtime, "jstat", args, java_home, run_as)?;
+
+    let output = runner.run(cmd).await?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "jstat {} failed for PID {} in container {}: {}",
+            command,
+            pid,
+            container,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    let mut stdout = String::from_utf8(output.stdout)?;
+    let mut gc_cause = None;
+    if command == "-gccause" {
+        let (columns, cause) = split_gc_cause(&stdout).ok_or("Unexpected jstat -gccause output")?;
+        stdout = columns;
+        gc_cause = Some(cause);
+    }
+    let context = format!(
+        "jstat {} for PID {} ({} in {})",
+        command, pid, process, container
+    );
+    let values = parse_jstat_output(&stdout, &context).ok_or("Unexpected jstat output")?;
+    Ok((values, gc_cause))
+}
+
+// Split jstat -gccause output into its numeric columns, the same as -gcutil, and the last GC
+// cause. The LGCC and GCC columns are left-aligned text with spaces, e.g.
+// "Allocation Failure   No GC": the current cause is "No GC" outside of a collection, otherwise
+// LGCC is as wide as its header column, or up to the next space when a longer cause overflows it.
+fn split_gc_cause(stdout: &str) -> Option<(String, String)> {
+    let mut lines = stdout.lines().filter(|l| !l.trim().is_empty());
+    let header = lines.next()?;
+    let headers: Vec<&str> = header.split_whitespace().collect();
+    let columns = headers.iter().position(|h| *h == "LGCC")?;
+    // Width of the LGCC column, including the space before GCC
+    let width = header.find(" GCC")?.checked_sub(header.find(" LGCC")?)?;
+    let mut values = lines.next()?.trim();
+    let mut numbers = Vec::new();
+    for _ in 0..columns {
+        let (number, rest) = values.split_once(char::is_whitespace)?;
+        numbers.push(number);
+        values = rest.trim_start();
+    }
+    let last_cause = match values.trim_end().strip_suffix("No GC") {
+        Some(last_cause) if !last_cause.trim().is_empty() => last_cause,
+        _ => values
+            .get(width - 1..)
+            .and_then(|rest| rest.find(' '))
+            .map_or(values, |end| &values[..width - 1 + end]),
+    };
+    Some((
+        format!("{}\n{}\n", headers[..columns].join(" "), numbers.join(" ")),
+        last_cause.trim().to_string(),
+    ))
+}
+
+// Pair the header and value rows of jstat output. When their counts differ, columns are
+// aligned from the right, which handles an uptime printed by -t without its header as well
+// as a header containing a space. "-" means not applicable and is reported as 0.
+// A leading Timestamp column, printed by -t, is paired with the first value and returned first.
+fn parse_jstat_output(stdout: &str, context: &str) -> Option<Vec<(String, f64)>> {
+    let mut lines = stdout.lines().filter(|l| !l.trim().is_empty());
+    let mut headers: Vec<&str> = lines.next()?.split_whitespace().collect();
+    let mut values: Vec<&str> = lines.next()?.split_whitespace().collect();
+
+    let mut metric_values = Vec::new();
+    if headers.first() == Some(&JSTAT_TIMESTAMP_HEADER) && !values.is_empty() {
+        headers.remove(0);
+        let timestamp = values.remove(0);
+        match timestamp.parse::<f64>() {
+            Ok(v) => metric_values.push((JSTAT_TIMESTAMP_HEADER.to_string(), v)),
+            Err(_) => warn!("Failed to parse Timestamp for {}: {}", context, timestamp),
+        }
+    }
+
+    // FailedMethod of -compiler is the class and method of the last failed compilation, empty
+    // until one fails and two words afterwards. It is not a value, FailedType still is.
+    if headers.last() == Some(&"FailedMethod") {
+        headers.pop();
+        while values
+            .last()
+            .is_some_and(|v| *v != "-" && v.parse::<f64>().is_err())
+        {
+            values.pop();
+        }
+    }
+
+    let skip_headers = headers.len().saturating_sub(values.len());
+    let skip_values = values.len().saturating_sub(headers.len());
+    if headers.len() != values.len() {
+        // An extra leading value is the uptime printed by -t, expected when its header is missing
+        let timestamp_prefix = skip_values == 1 && values[0].parse::<f64>().is_ok();
+        if !timestamp_prefix {
+            warn!(
+                "Mismatch in headers and values count of {}, aligning them from the right: headers = {:?}, values = {:?}",
+                context, headers, values
+            );
+        }
+    }
+
+    for (header, value) in headers[skip_headers..].iter().zip(&values[skip_values..]) {
+        let parsed_value = if *value == "-" {
+            0.0
+        } else {
+            match value.parse::<f64>() {
+                Ok(v) => v,
+                Err(_) => {
+                    warn!("Failed to parse value of {} for {}: {}", header, context, value);
+                    continue;
+                }
+            }
+        };
+        metric_values.push((header.to_string(), parsed_value));
+    }
+    Some(metric_values)
+}
+
+// Build a command running a JDK tool such as jstat or jcmd on the host,
+// or inside a container through its runtime. Host commands can be run as another
+// user through sudo, since the attach mechanism only works for the JVM's owner.
+fn jdk_tool_command(
+    container: &str,
+    runtime: Option<&str>,
+    tool: &str,
+    args: &[&str],
+    java_home: Option<&str>,
+    run_as: Option<&str>,
+) -> Result<Command, Box<dyn std::error::Error + Send + Sync>> {
+    let mut cmd = if container == "host" {
+        if let Some(user) = run_as {
+            // sudo resets PATH, so use the full path of the tool when JAVA_HOME is known
+            let tool_path = match java_home {
+                Some(jh) => format!("{}/bin/{}", jh, tool),
+                None => tool.to_string(),
+            };
+            let mut command_sudo = Command::new("sudo");
+            command_sudo
+                .args(["-n", "-u", user, "--", &tool_path])
+                .args(args);
+            command_sudo
+        } else {
+            let mut command_host = Command::new(tool);
+            command_host.args(args);
+            command_host
+        }
+    } else if runtime == Some(SSH_RUNTIME) {
+        // Wrapped into ssh by the process's SshCommandRunner, which also locates the remote JDK
+        let mut command_remote = Command::new(tool);
+        command_remote.args(args);
+        return Ok(command_remote);
+    } else if let Some(runtime) = runtime {
+        // The environment of the runtime command does not reach the container, java_home is a
+        // JDK inside it, see process_java_home. The container's PATH is used otherwise.
+        let tool_path = match java_home {
+            Some(jh) => format!("{}/bin/{}", jh, tool),
+            None => tool.to_string(),
+        };
+        let mut cmd_container = Command::new(runtime);
+        cmd_container
+            .args(["exec", container, &tool_path])
+            .args(args);
+        return Ok(cmd_container);
+    } else {
+        return Err(format!("No container runtime known for container {}", container).into());
+    };
+
+    if let Some(jh) = java_home {
+        cmd.env("JAVA_HOME", jh);
+        cmd.env(
+            "PATH",
+            format!("{}/bin:{}", jh, std::env::var("PATH").unwrap_or_default()),
+        );
+    }
+    Ok(cmd)
+}
+
+// JAVA_HOME to run the JDK tools of a process with: the JDK configured for its container in
+// container_java_homes, the global java_home on the host and on remote hosts, or else the JDK
+// the JVM runs from, see detect_java_homes. The global java_home is a host path, it is never
+// used inside containers.
+fn process_java_home(
+    metrics: &Metrics,
+    proc_info: &ProcessInfo,
+    java_home: Option<&str>,
+) -> Option<String> {
+    let configured = match proc_info.runtime {
+        Some(runtime) if runtime != SSH_RUNTIME => {
+            metrics.config.read().unwrap().container_java_home(
+                &proc_info.container,
+                proc_info.container_name.as_deref(),
+                proc_info.image.as_deref(),
+            )
+        }
+        _ => java_home.map(|jh| jh.to_string()),
+    };
+    configured.or_else(|| proc_info.java_home.clone())
+}
+
+// Find the JDK of JVMs without a configured JAVA_HOME from their executable, once per PID, so
+// that the jstat and jcmd matching each JVM are used on hosts with several JDKs. Processes whose
+// JDK is not found keep using the tools on the PATH.
+async fn detect_java_homes(
+    metrics: &Metrics,
+    processes: &mut [ProcessInfo],
+    java_home: Option<&str>,
+    deadline: Option<tokio::time::Instant>,
+) {
+    let pending: Vec<&ProcessInfo> = {
+        let detected = metrics.detected_java_homes.lock().await;
+        processes
+            .iter()
+            .filter(|p| p.container != "system" && p.runtime != Some(SSH_RUNTIME))
+            .filter(|p| !detected.contains_key(&format!("{}#{}", p.container, p.pid)))
+            .filter(|p| process_java_home(metrics, p, java_home).is_none())
+            .collect()
+    };
+    let lookups = pending.into_iter().map(|proc_info| async move {
+        let runner = proc_info
+            .runner
+            .as_deref()
+            .unwrap_or(metrics.runner.as_ref());
+        (
+            format!("{}#{}", proc_info.container, proc_info.pid),
+            detect_java_home(runner, proc_info).await,
+        )
+    });
+    let found = join_all_until(lookups, deadline).await;
+
+    let mut detected = metrics.detected_java_homes.lock().await;
+    detected.extend(found);
+    for proc_info in processes.iter_mut() {
+        let key = format!("{}#{}", proc_info.container, proc_info.pid);
+        proc_info.java_home = detected.get(&key).cloned().flatten();
+    }
+}
+
+// Resolve /proc/<pid>/exe, on the host or with readlink in the container
+async fn detect_java_home(runner: &dyn CommandRunner, proc_info: &ProcessInfo) -> Option<String> {
+    let exe = format!("/proc/{}/exe", proc_info.pid);
+    if proc_info.container == "host" {
+        let java = std::fs::read_link(&exe).ok()?;
+        let java_home = java_home_of(&java.to_string_lossy())?;
+        // A JRE has no jstat, the PATH may have one
+        return Path::new(&java_home)
+            .join("bin/jstat")
+            .exists()
+            .then_some(java_home);
+    }
+    let cmd = jdk_tool_command(
+        &proc_info.container,
+        proc_info.runtime,
+        "readlink",
+        &[exe.as_str()],
+        None,
+        None,
+    )
+    .ok()?;
+    let output = runner.run(cmd).await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let java_home = java_home_of(String::from_utf8_lossy(&output.stdout).trim())?;
+    // Same check as on the host, run in the container
+    let jstat = format!("{}/bin/jstat", java_home);
+    let cmd = jdk_tool_command(
+        &proc_info.container,
+        proc_info.runtime,
+        "test",
+        &["-x", jstat.as_str()],
+        None,
+        None,
+    )
+    .ok()?;
+    let output = runner.run(cmd).await.ok()?;
+    output.status.success().then_some(java_home)
+}
+
+// JAVA_HOME of a java executable, <java_home>/bin/java or <java_home>/jre/bin/java on JDK 8
+fn java_home_of(java: &str) -> Option<String> {
+    let bin = java.strip_suffix("/bin/java")?;
+    let java_home = bin.strip_suffix("/jre").unwrap_or(bin);
+    (!java_home.is_empty()).then(|| java_home.to_string())
+}
+
+// Look up the user names owning the given host PIDs
+fn process_owners<'a>(pids: impl Iterator<Item = &'a str>) -> HashMap<String, String> {
+    let pids: Vec<Pid> = pids
+        .filter_map(|pid| pid.parse::<u32>().ok().map(Pid::from_u32))
+        .collect();
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        sysinfo::ProcessesToUpdate::Some(&pids),
+        true,
+        sysinfo::ProcessRefreshKind::nothing().with_user(sysinfo::UpdateKind::Always),
+    );
+    let users = Users::new_with_refreshed_list();
+
+    pids.iter()
+        .filter_map(|pid| {
+            let uid = system.process(*pid)?.user_id()?;
+            let name = users
+                .get_user_by_id(uid)
+                .map(|user| user.name().to_string())
+                // sudo accepts numeric user IDs prefixed with '#'
+                .unwrap_or_else(|| String::from("#") + &uid.to_string());
+            Some((pid.to_string(), name))
+        })
+        .collect()
+}
+
+// Start time of host processes in seconds since the epoch, by PID
+fn process_start_times<'a>(pids: impl Iterator<Item = &'a str>) -> HashMap<String, u64> {
+    let pids: Vec<Pid> = pids
+        .filter_map(|pid| pid.parse::<u32>().ok().map(Pid::from_u32))
+        .collect();
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        sysinfo::ProcessesToUpdate::Some(&pids),
+        true,
+        sysinfo::ProcessRefreshKind::nothing(),
+    );
+
+    pids.iter()
+        .filter_map(|pid| Some((pid.to_string(), system.process(*pid)?.start_time())))
+        .collect()
+}
+
+// Keys seen in both collections whose process start time changed
+fn restarted_pids(
+    previous: &HashMap<String, u64>,
+    current: &HashMap<String, u64>,
+) -> HashSet<String> {
+    current
+        .iter()
+        .filter(|(key, start_time)| {
+            previous
+                .get(*key)
+                .is_some_and(|previous| previous != *start_time)
+        })
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+// Fetch the Java version of JVMs not seen before, once per PID
+async fn update_java_versions(
+    metrics: Arc<Metrics>,
+    processes: &[ProcessInfo],
+    java_home: Option<&str>,
+    run_as: &impl Fn(&ProcessInfo) -> Option<String>,
+    deadline: Option<tokio::time::Instant>,
+) {
+    let pending: Vec<&ProcessInfo> = {
+        let java_versions = metrics.java_versions.lock().await;
+        processes
+            .iter()
+            .filter(|p| p.container != "system")
+            .filter(|p| !java_versions.contains_key(&format!("{}#{}", p.container, p.pid)))
+            .collect()
+    };
+
+    let default_runner = metrics.runner.as_ref();
+    let lookups = pending.into_iter().map(|proc_info| {
+        let java_home = process_java_home(&metrics, proc_info, java_home);
+        async move {
+            let runner = proc_info.runner.as_deref().unwrap_or(default_runner);
+            let version = match fetch_java_version(
+                runner,
+                &proc_info.container,
+                proc_info.runtime,
+                &proc_info.pid,
+                java_home.as_deref(),
+                run_as(proc_info).as_deref(),
+            )
+            .await
+            {
+                Ok(version) => version,
+                Err(err) => {
+                    info!(
+                        "Failed to get Java version for PID {} ({} in {}): {}",
+                        proc_info.pid, proc_info.process, proc_info.container, err
+                    );
+                    "unknown".to_string()
+                }
+            };
+            (
+                format!("{}#{}", proc_info.container, proc_info.pid),
+                version,
+            )
+        }
+    });
+    let versions = join_all_until(lookups, deadline).await;
+
+    metrics.java_versions.lock().await.extend(versions);
+}
+
+// Read the JVM version with `jcmd <pid> VM.version`
+async fn fetch_java_version(
+    runner: &dyn CommandRunner,
+    container: &str,
+    runtime: Option<&str>,
+    pid: &str,
+    java_home: Option<&str>,
+    run_as: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let cmd = jdk_tool_command(
+        container,
+        runtime,
+        "jcmd",
+        &[pid, "VM.version"],
+        java_home,
+        run_as,
+    )?;
+    let output = runner.run(cmd).await?;
+    if !output.status.success() {
+        return Err(format!(
+            "jcmd VM.version failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_java_version(&stdout).ok_or_else(|| "Unexpected jcmd VM.version output".into())
+}
+
+// Fetch the program arguments of JVMs not seen before with capture_process_args, once per PID.
+// They are kept apart from the process name, which is only the main class or jar.
+async fn update_process_args(
+    metrics: &Metrics,
+    processes: &[ProcessInfo],
+    java_home: Option<&str>,
+    run_as: &impl Fn(&ProcessInfo) -> Option<String>,
+    deadline: Option<tokio::time::Instant>,
+) {
+    if !metrics
+        .config
+        .read()
+        .unwrap()
+        .capture_process_args
+        .unwrap_or(false)
+    {
+        return;
+    }
+    // Failed lookups are run again after JSTAT_COOLDOWN rather than at every collection
+    let now = Instant::now();
+    let pending: Vec<&ProcessInfo> = {
+        let process_args = metrics.process_args.lock().await;
+        let retries = metrics.process_args_retries.lock().await;
+        processes
+            .iter()
+            .filter(|p| p.container != "system")
+            .filter(|p| {
+                let key = format!("{}#{}", p.container, p.pid);
+                !process_args.contains_key(&key)
+                    && retries.get(&key).is_none_or(|retry_at| *retry_at <= now)
+            })
+            .collect()
+    };
+
+    let lookups = pending.into_iter().map(|proc_info| {
+        let java_home = process_java_home(metrics, proc_info, java_home);
+        async move {
+            let runner = proc_info
+                .runner
+                .as_deref()
+                .unwrap_or(metrics.runner.as_ref());
+            let key = format!("{}#{}", proc_info.container, proc_info.pid);
+            let args = fetch_process_args(
+                runner,
+                proc_info,
+                java_home.as_deref(),
+                run_as(proc_info).as_deref(),
+            )
+            .await
+            .map(|args| redact_process_args(&args))
+            .map_err(|err| {
+                format!(
+                    "Failed to get program arguments for PID {} ({} in {}): {}",
+                    proc_info.pid, proc_info.process, proc_info.container, err
+                )
+            });
+            (key, args)
+        }
+    });
+    let results = join_all_until(lookups, deadline).await;
+
+    let mut process_args = metrics.process_args.lock().await;
+    let mut retries = metrics.process_args_retries.lock().await;
+    for (key, args) in results {
+        match args {
+            Ok(args) => {
+                retries.remove(&key);
+                process_args.insert(key, args);
+            }
+            // Logged at the first failure only, the retries are not
+            Err(err) => {
+                if retries.insert(key, now + JSTAT_COOLDOWN).is_none() {
+                    info!("{}, trying again every {}s", err, JSTAT_COOLDOWN.as_secs());
+                }
+            }
+        }
+    }
+}
+
+// Read the program arguments with `jcmd <pid> VM.command_line`
+async fn fetch_process_args(
+    runner: &dyn CommandRunner,
+    proc_info: &ProcessInfo,
+    java_home: Option<&str>,
+    run_as: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let cmd = jdk_tool_command(
+        &proc_info.container,
+        proc_info.runtime,
+        "jcmd",
+        &[proc_info.pid.as_str(), "VM.command_line"],
+        java_home,
+        run_as,
+    )?;
+    let output = runner.run(cmd).await?;
+    if !output.status.success() {
+        return Err(format!(
+            "jcmd VM.command_line failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_process_args(&stdout).ok_or_else(|| "Unexpected jcmd VM.command_line output".into())
+}
+
+// The program arguments of the "java_command: <main class or jar> <args>" line
+fn parse_process_args(output: &str) -> Option<String> {
+    let command = output
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("java_command:"))?
+        .trim();
+    Some(match command.split_once(char::is_whitespace) {
+        Some((_, args)) => args.split_whitespace().collect::<Vec<_>>().join(" "),
+        None => String::new(),
+    })
+}
+
+// Replace the values of arguments whose name looks like a secret, e.g. "--db-password=s3cr3t" or
+// "--api-token abc", with "***", as the args label is readable by anyone scraping the exporter
+fn redact_process_args(args: &str) -> String {
+    const SECRET_NAMES: &[&str] = &[
+        "password",
+        "passwd",
+        "secret",
+        "token",
+        "apikey",
+        "api-key",
+        "api_key",
+        "credential",
+    ];
+    let is_secret = |name: &str| {
+        let name = name.to_ascii_lowercase();
+        SECRET_NAMES.iter().any(|secret| name.contains(secret))
+    };
+    let mut redacted = Vec::new();
+    let mut redact_next = false;
+    for arg in args.split_whitespace() {
+        if redact_next && !arg.starts_with('-') {
+            redacted.push("***".to_string());
+            redact_next = false;
+            continue;
+        }
+        redact_next = false;
+        match arg.split_once('=') {
+            Some((name, _)) if is_secret(name) => redacted.push(format!("{}=***", name)),
+            Some(_) => redacted.push(arg.to_string()),
+            None => {
+                redact_next = arg.starts_with('-') && is_secret(arg);
+                redacted.push(arg.to_string());
+            }
+        }
+    }
+    redacted.join(" ")
+}
+
+// Collect native memory tracking metrics with `jcmd <pid> VM.native_memory summary`.
+// The JVM has to be started with -XX:NativeMemoryTracking=summary or detail.
+async fn update_jcmd_metrics(
+    metrics: Arc<Metrics>,
+    processes: &[ProcessInfo],
+    java_home: Option<&str>,
+    run_as: &impl Fn(&ProcessInfo) -> Option<String>,
+    deadline: Option<tokio::time::Instant>,
+) {
+    let non_hotspot_pids = metrics.non_hotspot_pids.lock().await.clone();
+    let nmt_disabled_pids = metrics.nmt_disabled_pids.lock().await.clone();
+    let java_versions = metrics.java_versions.lock().await.clone();
+    let default_runner = metrics.runner.as_ref();
+    let collect_code_cache =
+        metrics.exports("jvm_code_cache_used_bytes") || metrics.exports("jvm_code_cache_max_bytes");
+    let lookups = processes
+        .iter()
+        .filter(|p| p.container != "system")
+        .filter(|p| !non_hotspot_pids.contains(&format!("{}#{}", p.container, p.pid)))
+        .map(|proc_info| {
+            let java_home = process_java_home(&metrics, proc_info, java_home);
+            let key = format!("{}#{}", proc_info.container, proc_info.pid);
+            // Native memory tracking cannot be turned on without restarting the JVM
+            let tracks_native_memory = !nmt_disabled_pids.contains(&key);
+            async move {
+                let runner = proc_info.runner.as_deref().unwrap_or(default_runner);
+                let run_as = run_as(proc_info);
+                let result = if tracks_native_memory {
+                    Some(
+                        fetch_native_memory(
+                            runner,
+                            &proc_info.container,
+                            proc_info.runtime,
+                            &proc_info.pid,
+                            java_home.as_deref(),
+                            run_as.as_deref(),
+                        )
+                        .await,
+                    )
+                } else {
+                    None
+                };
+                // Compiler.codecache was added in JDK 9, older JVMs would fail it every interval
+                let has_codecache_command = java_versions
+                    .get(&key)
+                    .map(String::as_str)
+                    .and_then(java_major_version)
+                    .is_none_or(|major| major >= 9);
+                let code_cache = if collect_code_cache && has_codecache_command {
+                    Some(
+                        fetch_code_cache(
+                            runner,
+                            &proc_info.container,
+                            proc_info.runtime,
+                            &proc_info.pid,
+                            java_home.as_deref(),
+                            run_as.as_deref(),
+                        )
+                        .await,
+                    )
+                } else {
+                    None
+                };
+                (proc_info, key, result, code_cache)
+            }
+        });
+    let results = join_all_until(lookups, deadline).await;
+
+    let mut updates = GaugeUpdates::default();
+    let mut jcmd_labels = metrics.jcmd_labels.lock().await;
+    for (proc_info, key, result, code_cache) in results {
+        match code_cache {
+            Some(Ok((used, max))) => {
+                let labels = metrics.process_label_values(
+                    &proc_info.container,
+                    &proc_info.pid,
+                    &proc_info.process,
+                    &[],
+                );
+                updates.sum(&metrics.process_metrics.code_cache_used, &labels, used);
+                updates.sum(&metrics.process_metrics.code_cache_max, &labels, max);
+            }
+            Some(Err(err)) => warn!(
+                "Failed to update code cache metrics for PID {} ({} in {}): {}",
+                proc_info.pid, proc_info.process, proc_info.container, err
+            ),
+            None => {}
+        }
+        let categories = match result {
+            Some(Ok(categories)) => categories,
+            Some(Err(err)) => {
+                warn!(
+                    "Failed to update jcmd metrics for PID {} ({} in {}): {}",
+                    proc_info.pid, proc_info.process, proc_info.container, err
+                );
+                // Warned once, VM.native_memory is not run for the process again
+                if err.to_string() == NMT_DISABLED_ERROR {
+                    metrics.nmt_disabled_pids.lock().await.insert(key);
+                }
+                continue;
+            }
+            None => continue,
+        };
+        for (category, reserved, committed) in categories.iter() {
+            let labels = metrics.process_label_values(
+                &proc_info.container,
+                &proc_info.pid,
+                &proc_info.process,
+                &[category],
+            );
+            updates.sum(
+                &metrics.process_metrics.native_memory_reserved,
+                &labels,
+                *reserved,
+            );
+            updates.sum(
+                &metrics.process_metrics.native_memory_committed,
+                &labels,
+                *committed,
+            );
+        }
+        // Record categories for stale series removal
+        jcmd_labels
+            .entry(format!("{}#{}", proc_info.container, proc_info.pid))
+            .or_insert_with(HashSet::new)
+            .extend(categories.into_iter().map(|(category, _, _)| category));
+    }
+    updates.apply();
+}
+
+const NMT_DISABLED_ERROR: &str =
+    "native memory tracking is not enabled, start the JVM with -XX:NativeMemoryTracking=summary";
+
+// Run `jcmd <pid> VM.native_memory summary` and return (category, reserved, committed) in bytes
+async fn fetch_native_memory(
+    runner: &dyn CommandRunner,
+    container: &str,
+    runtime: Option<&str>,
+    pid: &str,
+    java_home: Option<&str>,
+    run_as: Option<&str>,
+) -> Result<Vec<(String, f64, f64)>, Box<dyn std::error::Error + Send + Sync>> {
+    let cmd = jdk_tool_command(
+        container,
+        runtime,
+        "jcmd",
+        &[pid, "VM.native_memory", "summary", "scale=KB"],
+        java_home,
+        run_as,
+    )?;
+    let output = runner.run(cmd).await?;
+    if !output.status.success() {
+        return Err(format!(
+            "jcmd VM.native_memory failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if stdout.contains("Native memory tracking is not enabled") {
+        return Err(NMT_DISABLED_ERROR.into());
+    }
+    let categories = parse_native_memory(&stdout);
+    if categories.is_empty() {
+        return Err("Unexpected jcmd VM.native_memory output".into());
+    }
+    Ok(categories)
+}
+
+// Run `jcmd <pid> Compiler.codecache` and return the used and total size of the code cache in bytes
+async fn fetch_code_cache(
+    runner: &dyn CommandRunner,
+    container: &str,
+    runtime: Option<&str>,
+    pid: &str,
+    java_home: Option<&str>,
+    run_as: Option<&str>,
+) -> Result<(f64, f64), Box<dyn std::error::Error + Send + Sync>> {
+    let cmd = jdk_tool_command(
+        container,
+        runtime,
+        "jcmd",
+        &[pid, "Compiler.codecache"],
+        java_home,
+        run_as,
+    )?;
+    let output = runner.run(cmd).await?;
+    if !output.status.success() {
+        return Err(format!(
+            "jcmd Compiler.codecache failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+    parse_code_cache(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| "Unexpected jcmd Compiler.codecache output".into())
+}
+
+// Sum the "size=..Kb used=..Kb" of the code heaps of a segmented code cache (JDK 9+),
+// "CodeHeap '<name>': size=...", or of the single "CodeCache: size=..." line of older JDKs
+fn parse_code_cache(output: &str) -> Option<(f64, f64)> {
+    let mut code_cache = None;
+    for line in output.lines().map(|line| line.trim()) {
+        if !line.starts_with("CodeHeap") && !line.starts_with("CodeCache:") {
+            continue;
+        }
+        if let (Some(used), Some(size)) = (kb_value(line, " used="), kb_value(line, "size=")) {
+            let (total_used, total_size) = code_cache.unwrap_or((0.0, 0.0));
+            code_cache = Some((total_used + used, total_size + size));
+        }
+    }
+    code_cache
+}
+
+// Bytes of the number of KB following `key` in jcmd output, e.g. "committed=1024KB"
+fn kb_value(line: &str, key: &str) -> Option<f64> {
+    let start = line.find(key)? + key.len();
+    let digits: String = line[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse::<f64>().ok().map(|kb| kb * 1024.0)
+}
+
+// Parse the "Total:" and "- <category> (reserved=..KB, committed=..KB)" lines of a
+// VM.native_memory summary. Category names are lowercased with spaces replaced by underscores.
+fn parse_native_memory(output: &str) -> Vec<(String, f64, f64)> {
+    output
+        .lines()
+        .map(|line| line.trim())
+        .filter_map(|line| {
+            let category = if line.starts_with("Total:") {
+                "total".to_string()
+            } else {
+                let name = line.strip_prefix('-')?.split('(').next()?.trim();
+                if name.is_empty() {
+                    return None;
+                }
+                name.to_lowercase().replace(' ', "_")
+            };
+            let reserved = kb_value(line, "reserved=")?;
+            let committed = kb_value(line, "committed=")?;
+            Some((category, reserved, committed))
+        })
+        .collect()
+}
+
+// Stable label values for sysinfo's process states
+fn process_state_name(status: sysinfo::ProcessStatus) -> &'static str {
+    match status {
+        sysinfo::ProcessStatus::Idle => "idle",
+        sysinfo::ProcessStatus::Run => "running",
+        sysinfo::ProcessStatus::Sleep => "sleeping",
+        sysinfo::ProcessStatus::Stop => "stopped",
+        sysinfo::ProcessStatus::Zombie => "zombie",
+        sysinfo::ProcessStatus::Tracing => "tracing",
+        sysinfo::ProcessStatus::Dead => "dead",
+        sysinfo::ProcessStatus::Wakekill => "wakekill",
+        sysinfo::ProcessStatus::Waking => "waking",
+        sysinfo::ProcessStatus::Parked => "parked",
+        sysinfo::ProcessStatus::LockBlocked => "lock_blocked",
+        sysinfo::ProcessStatus::UninterruptibleDiskSleep => "disk_sleep",
+        sysinfo::ProcessStatus::Unknown(_) => "unknown",
+    }
+}
+
+// Run `java -version` once per JVM and export its vendor and version as jvm_version_info.
+// Results, "unknown" when java -version failed, are cached by PID and dropped with it.
+async fn update_java_runtimes(
+    metrics: Arc<Metrics>,
+    processes: &[ProcessInfo],
+    run_as: &impl Fn(&ProcessInfo) -> Option<String>,
+    deadline: Option<tokio::time::Instant>,
+) {
+    let jvm_processes: Vec<&ProcessInfo> = processes
+        .iter()
+        .filter(|p| p.container != "system")
+        .collect();
+    let pending: Vec<&ProcessInfo> = {
+        let java_runtimes = metrics.java_runtimes.lock().await;
+        jvm_processes
+            .iter()
+            .copied()
+            .filter(|p| !java_runtimes.contains_key(&format!("{}#{}", p.container, p.pid)))
+            .collect()
+    };
+
+    let default_runner = metrics.runner.as_ref();
+    let lookups = pending.into_iter().map(|proc_info| async move {
+        let runner = proc_info.runner.as_deref().unwrap_or(default_runner);
+        let runtime = match fetch_java_runtime(
+            runner,
+            &proc_info.container,
+            proc_info.runtime,
+            &proc_info.pid,
+            run_as(proc_info).as_deref(),
+        )
+        .await
+        {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                info!(
+                    "Failed to run java -version for PID {} ({} in {}): {}",
+                    proc_info.pid, proc_info.process, proc_info.container, err
+                );
+                ("unknown".to_string(), "unknown".to_string())
+            }
+        };
+        (
+            format!("{}#{}", proc_info.container, proc_info.pid),
+            runtime,
+        )
+    });
+    let runtimes = join_all_until(lookups, deadline).await;
+
+    let mut java_runtimes = metrics.java_runtimes.lock().await;
+    java_runtimes.extend(runtimes);
+    let mut updates = GaugeUpdates::default();
+    for proc_info in jvm_processes {
+        let key = format!("{}#{}", proc_info.container, proc_info.pid);
+        if let Some((vendor, version)) = java_runtimes.get(&key) {
+            let labels = metrics.process_label_values(
+                &proc_info.container,
+                &proc_info.pid,
+                &proc_info.process,
+                &[vendor, version],
+            );
+            updates.max(&metrics.process_metrics.version_info, &labels, 1.0);
+        }
+    }
+    drop(java_runtimes);
+    updates.apply();
+}
+
+// Run `java -version` with the executable of the JVM itself, so that the result is the
+// runtime of that JVM rather than the java on the PATH. Works in containers through exec too.
+async fn fetch_java_runtime(
+    runner: &dyn CommandRunner,
+    container: &str,
+    runtime: Option<&str>,
+    pid: &str,
+    run_as: Option<&str>,
+) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>> {
+    let java = format!("/proc/{}/exe", pid);
+    let cmd = jdk_tool_command(container, runtime, &java, &["-version"], None, run_as)?;
+    let output = runner.run(cmd).await?;
+    // java -version prints to stderr
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stderr),
+        String::from_utf8_lossy(&output.stdout)
+    );
+    if !output.status.success() {
+        return Err(format!("java -version failed: {}", text.trim()).into());
+    }
+    parse_java_runtime(&text).ok_or_else(|| "Unexpected java -version output".into())
+}
+
+// Vendor and version from java -version output. Distributions are recognised by the names they
+// print, the most specific first: OpenJ9 and GraalVM also mention OpenJDK or HotSpot.
+fn parse_java_runtime(output: &str) -> Option<(String, String)> {
+    let version = output
+        .lines()
+        .find_map(|line| line.split_once(" version \"").map(|(_, rest)| rest))
+        .and_then(|rest| rest.split('"').next())?
+        .to_string();
+    let vendor = ["OpenJ9", "GraalVM", "Temurin"]
+        .into_iter()
+        .find(|name| output.contains(name))
+        .or_else(|| output.contains("HotSpot(TM)").then_some("HotSpot"))
+        .or_else(|| output.contains("OpenJDK").then_some("OpenJDK"))
+        .unwrap_or("unknown");
+    Some((vendor.to_string(), version))
+}
+
+// Extract the version from jcmd VM.version output, preferring the "JDK x" line
+fn parse_java_version(output: &str) -> Option<String> {
+    let lines: Vec<&str> = output.lines().map(|l| l.trim()).collect();
+    if let Some(jdk) = lines.iter().find_map(|l| l.strip_prefix("JDK ")) {
+        return Some(jdk.trim().to_string());
+    }
+    lines
+        .iter()
+        .find_map(|l| l.split_once(" version ").map(|(_, v)| v.trim().to_string()))
+}
+
+// The major version of a Java version such as "17.0.8", or "1.8.0_381" for Java 8 and older
+fn java_major_version(version: &str) -> Option<u32> {
+    let mut numbers = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>().ok());
+    match numbers.next()?? {
+        1 => numbers.next()?,
+        major => Some(major),
+    }
+}
+
+// Update CPU and Memory metrics
+async fn update_cpu_memory_metrics(
+    metrics: Arc<Metrics>,
+    processes: &[ProcessInfo],
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Refreshed by update_metrics, only the CPU usage is sampled again
+    let mut system = metrics.system.lock().await;
+    tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+
+    system.refresh_processes_specifics(
+        sysinfo::ProcessesToUpdate::All,
+        true,
+        sysinfo::ProcessRefreshKind::nothing().with_cpu(),
+    );
+    let total_memory = system.total_memory();
+    let cpu_count = system.cpus().len().max(1) as f64;
+
+    // Enumerating sockets is expensive on hosts with many connections
+    let enumerate_sockets = metrics
+        .config
+        .read()
+        .unwrap()
+        .collect_tcp_states
+        .unwrap_or(true);
+    let collect_tcp_states = enumerate_sockets && metrics.exports("process_tcp_connection_states");
+    let collect_listening_ports = enumerate_sockets && metrics.exports("process_listening_ports");
+    let sockets = if collect_tcp_states || collect_listening_ports {
+        let af_flags = AddressFamilyFlags::IPV4 | AddressFamilyFlags::IPV6;
+        let proto_flags = ProtocolFlags::TCP;
+        get_sockets_info(af_flags, proto_flags)?
+    } else {
+        Vec::new()
+    };
+
+    let mut updates = GaugeUpdates::default();
+    let mut up_times = HashMap::new();
+    for proc_info in processes.iter() {
+        let pid_str = &proc_info.pid;
+        let container = &proc_info.container;
+        let process = &proc_info.process;
+
+        // Extract class name (last part of the package path)
+        let class_name = process.split('.').last().unwrap_or(process);
+
+        // Check if class name is in the exclusion list
+        if EXCLUDED_PROCESSES
+            .iter()
+            .any(|&excluded| excluded.eq_ignore_ascii_case(class_name))
+        {
+            warn!("Excluding process PID {}: {}", pid_str, class_name);
+            continue;
+        }
+
+        // sysinfo only sees local processes, a remote PID would match an unrelated one
+        if proc_info.runtime == Some(SSH_RUNTIME) {
+            continue;
+        }
+
+        if let Ok(pid) = pid_str.parse::<usize>() {
+            if let Some(process_info) = system.process(sysinfo::Pid::from(pid)) {
+                let labels = metrics.process_label_values(container, pid_str, process, &[]);
+
+                // Update CPU usage, 100 per busy core, and normalized to all cores
+                updates.sum(
+                    &metrics.process_metrics.cpu_usage,
+                    &labels,
+                    process_info.cpu_usage() as f64,
+                );
+                updates.sum(
+                    &metrics.process_metrics.cpu_usage_normalized,
+                    &labels,
+                    process_info.cpu_usage() as f64 / cpu_count,
+                );
+
+                // Update Memory usage (in bytes)
+                updates.sum(
+                    &metrics.process_metrics.memory_usage,
+                    &labels,
+                    process_info.memory() as f64,
+                ); // Convert KB to Bytes
+
+                // Container PIDs are namespace-local, their cgroup cannot be looked up
+                let memory_limit = if proc_info.runtime.is_none() {
+                    process_memory_limit(pid_str, total_memory)
+                } else {
+                    total_memory
+                };
+                let memory_usage_percentage = if memory_limit > 0 {
+                    (process_info.memory() as f64 / memory_limit as f64) * 100.0
+                } else {
+                    0.0
+                };
+
+                updates.sum(
+                    &metrics.process_metrics.memory_usage_percentage,
+                    &labels,
+                    memory_usage_percentage,
+                );
+
+                // Update Virtual memory size (in bytes)
+                updates.sum(
+                    &metrics.process_metrics.virtual_memory,
+                    &labels,
+                    process_info.virtual_memory() as f64,
+                );
+
+                // Increase disk I/O counters by the bytes since the previous collection.
+                // A lower total means the PID was reused, count it from zero.
+                let disk_usage = process_info.disk_usage();
+                let key = format!("{}#{}", container, pid_str);
+                let (read_delta, write_delta) = {
+                    let mut disk_io_totals = metrics.disk_io_totals.lock().await;
+                    let (last_read, last_written) =
+                        disk_io_totals.get(&key).copied().unwrap_or((0, 0));
+                    disk_io_totals.insert(
+                        key,
+                        (disk_usage.total_read_bytes, disk_usage.total_written_bytes),
+                    );
+                    (
+                        counter_delta(last_read, disk_usage.total_read_bytes),
+                        counter_delta(last_written, disk_usage.total_written_bytes),
+                    )
+                };
+                metrics
+                    .process_metrics
+                    .disk_read_bytes
+                    .with_label_values(&labels)
+                    .inc_by(read_delta as f64);
+                metrics
+                    .process_metrics
+                    .disk_write_bytes
+                    .with_label_values(&labels)
+                    .inc_by(write_delta as f64);
+
+                if let Some((voluntary, nonvoluntary)) = context_switches(pid_str) {
+                    let key = format!("{}#{}", container, pid_str);
+                    let (last_voluntary, last_nonvoluntary) = metrics
+                        .ctxt_switch_totals
+                        .lock()
+                        .await
+                        .insert(key, (voluntary, nonvoluntary))
+                        .unwrap_or((0, 0));
+                    metrics
+                        .process_metrics
+                        .voluntary_ctxt_switches
+                        .with_label_values(&labels)
+                        .inc_by(counter_delta(last_voluntary, voluntary) as f64);
+                    metrics
+                        .process_metrics
+                        .nonvoluntary_ctxt_switches
+                        .with_label_values(&labels)
+                        .inc_by(counter_delta(last_nonvoluntary, nonvoluntary) as f64);
+                }
+
+                if let Some((major, minor)) = page_faults(pid_str) {
+                    let key = format!("{}#{}", container, pid_str);
+                    let (last_major, last_minor) = metrics
+                        .page_fault_totals
+                        .lock()
+                        .await
+                        .insert(key, (major, minor))
+                        .unwrap_or((0, 0));
+                    metrics
+                        .process_metrics
+                        .major_page_faults
+                        .with_label_values(&labels)
+                        .inc_by(counter_delta(last_major, major) as f64);
+                    metrics
+                        .process_metrics
+                        .minor_page_faults
+                        .with_label_values(&labels)
+                        .inc_by(counter_delta(last_minor, minor) as f64);
+                }
+
+                let start_time_secs = process_info.start_time() as f64;
+                let up_time_secs = process_info.run_time() as f64;
+
+                // When aggregating, report the most recently started PID
+                updates.max(
+                    &metrics.process_metrics.start_time,
+                    &labels,
+                    start_time_secs,
+                );
+                updates.min(&metrics.process_metrics.up_time, &labels, up_time_secs);
+                // Container PIDs are namespace-local, the host process with that PID is another one
+                if proc_info.runtime.is_none() {
+                    up_times.insert(format!("{}#{}", container, pid_str), up_time_secs);
+                }
+
+                // The series of the previous state is removed when the state changes
+                let state = process_state_name(process_info.status());
+                let previous_state = metrics
+                    .process_states
+                    .lock()
+                    .await
+                    .insert(format!("{}#{}", container, pid_str), state);
+                if let Some(previous_state) = previous_state.filter(|&s| s != state) {
+                    let previous = metrics.process_label_values(
+                        container,
+                        pid_str,
+                        process,
+                        &[previous_state],
+                    );
+                    let _ = metrics
+                        .process_metrics
+                        .state_info
+                        .remove_label_values(&previous);
+                }
+                updates.max(
+                    &metrics.process_metrics.state_info,
+                    &metrics.process_label_values(container, pid_str, process, &[state]),
+                    1.0,
+                );
+
+                if container != "system" {
+                    let key = format!("{}#{}", container, pid_str);
+                    let java_version = metrics
+                        .java_versions
+                        .lock()
+                        .await
+                        .get(&key)
+                        .cloned()
+                        .unwrap_or_else(|| "unknown".to_string());
+                    let args = metrics
+                        .process_args
+                        .lock()
+                        .await
+                        .get(&key)
+                        .cloned()
+                        .unwrap_or_default();
+                    let start_time = process_info.start_time().to_string();
+                    let user = proc_info.user.as_deref().unwrap_or("unknown");
+                    let image = proc_info.image.as_deref().unwrap_or("unknown");
+                    let (pod, namespace, pod_uid) = match &proc_info.pod {
+                        Some(pod) => (pod.name.as_str(), pod.namespace.as_str(), pod.uid.as_str()),
+                        None => ("", "", ""),
+                    };
+                    let labels: Vec<String> = metrics
+                        .process_label_values(
+                            container,
+                            pid_str,
+                            process,
+                            &[
+                                &java_version,
+                                &start_time,
+                                user,
+                                image,
+                                &args,
+                                pod,
+                                namespace,
+                                pod_uid,
+                            ],
+                        )
+                        .into_iter()
+                        .map(|l| l.to_string())
+                        .collect();
+                    let mut process_info_labels = metrics.process_info_labels.lock().await;
+                    if let Some(previous) = process_info_labels.get(&key) {
+                        if *previous != labels {
+                            let previous: Vec<&str> = previous.iter().map(|l| l.as_str()).collect();
+                            let _ = metrics.process_metrics.info.remove_label_values(&previous);
+                        }
+                    }
+                    let label_values: Vec<&str> = labels.iter().map(|l| l.as_str()).collect();
+                    metrics
+                        .process_metrics
+                        .info
+                        .with_label_values(&label_values)
+                        .set(1.0);
+                    process_info_labels.insert(key, labels);
+                }
+
+                let open_file = process_info.open_files().unwrap_or(0) as f64;
+                let open_file_limit = process_info.open_files_limit().unwrap_or(0) as f64;
+                updates.sum(&metrics.process_metrics.open_file, &labels, open_file);
+                // Limits apply to each PID on its own, when aggregating report the highest
+                updates.max(
+                    &metrics.process_metrics.open_file_limit,
+                    &labels,
+                    open_file_limit,
+                );
+                if let Some((soft_limit, hard_limit)) = open_files_limits(pid_str) {
+                    updates.max(
+                        &metrics.process_metrics.open_file_soft_limit,
+                        &labels,
+                        soft_limit,
+                    );
+                    updates.max(
+                        &metrics.process_metrics.open_file_hard_limit,
+                        &labels,
+                        hard_limit,
+                    );
+                }
+
+                // 过滤指定进程的连接
+                let pid = pid as u32;
+                if collect_tcp_states {
+                    let state_counts = count_tcp_states(
+                        sockets.iter().filter(|s| s.associated_pids.contains(&pid)),
+                        metrics.tcp_address_family,
+                    );
+                    for (state_labels, count) in state_counts.iter() {
+                        let state_labels: Vec<&str> =
+                            state_labels.iter().map(String::as_str).collect();
+                        updates.sum(
+                            &metrics.process_metrics.tcp_connection_states,
+                            &metrics.process_label_values(
+                                container,
+                                pid_str,
+                                process,
+                                &state_labels,
+                            ),
+                            *count as f64,
+                        );
+                    }
+                }
+
+                if collect_listening_ports {
+                    let mut ports = listening_ports(
+                        sockets.iter().filter(|s| s.associated_pids.contains(&pid)),
+                    );
+                    let key = format!("{}#{}", container, pid_str);
+                    let mut listening_ports = metrics.listening_ports.lock().await;
+                    if ports.len() > MAX_LISTENING_PORTS {
+                        // Warn once when a process starts listening on too many ports
+                        if !listening_ports
+                            .get(&key)
+                            .is_some_and(|p| p.len() >= MAX_LISTENING_PORTS)
+                        {
+                            warn!(
+                                "PID {} ({} in {}) listens on {} ports, only the lowest {} are exported",
+                                pid_str,
+                                process,
+                                container,
+                                ports.len(),
+                                MAX_LISTENING_PORTS
+                            );
+                        }
+                        ports.truncate(MAX_LISTENING_PORTS);
+                    }
+                    let ports: HashSet<String> = ports.iter().map(|p| p.to_string()).collect();
+                    // Series of ports that are closed since the last collection are removed
+                    if let Some(previous) = listening_ports.insert(key, ports.clone()) {
+                        for port in previous.difference(&ports) {
+                            let _ = metrics.process_metrics.listening_ports.remove_label_values(
+                                &metrics.process_label_values(container, pid_str, process, &[port]),
+                            );
+                        }
+                    }
+                    for port in &ports {
+                        updates.max(
+                            &metrics.process_metrics.listening_ports,
+                            &metrics.process_label_values(container, pid_str, process, &[port]),
+                            1.0,
+                        );
+                    }
+                }
+            }
+        }
+    }
+    updates.apply();
+    *metrics.up_times.lock().await = up_times;
+
+    Ok(())
+}
+
+// Increase of a cumulative value since the last collection, the whole value after a reset
+pub(crate) fn counter_delta(last: u64, current: u64) -> u64 {
+    if current >= last {
+        current - last
+    } else {
+        current
+    }
+}
+
+// Read the soft and hard limits of open file descriptors, which sysinfo reports as a single value
+#[cfg(target_os = "linux")]
+fn open_files_limits(pid: &str) -> Option<(f64, f64)> {
+    let limits = std::fs::read_to_string(format!("/proc/{}/limits", pid)).ok()?;
+    parse_open_files_limits(&limits)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_files_limits(_pid: &str) -> Option<(f64, f64)> {
+    None
+}
+
+// Voluntary and involuntary context switches of a process, which sysinfo does not report
+#[cfg(target_os = "linux")]
+fn context_switches(pid: &str) -> Option<(u64, u64)> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    parse_context_switches(&status)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn context_switches(_pid: &str) -> Option<(u64, u64)> {
+    None
+}
+
+// Parse the voluntary_ctxt_switches and nonvoluntary_ctxt_switches lines of /proc/<pid>/status
+fn parse_context_switches(status: &str) -> Option<(u64, u64)> {
+    let value = |key: &str| {
+        status.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            (name == key).then(|| value.trim().parse::<u64>().ok())?
+        })
+    };
+    Some((
+        value("voluntary_ctxt_switches")?,
+        value("nonvoluntary_ctxt_switches")?,
+    ))
+}
+
+// Major and minor page faults of a process, which sysinfo does not report
+#[cfg(target_os = "linux")]
+fn page_faults(pid: &str) -> Option<(u64, u64)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_page_faults(&stat)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn page_faults(_pid: &str) -> Option<(u64, u64)> {
+    None
+}
+
+// Parse the majflt and minflt fields of /proc/<pid>/stat. The fields are counted from the end of
+// the command name, which is in parentheses and may contain spaces.
+fn parse_page_faults(stat: &str) -> Option<(u64, u64)> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // state is field 3, minflt field 10 and majflt field 12
+    let minor = fields.get(7)?.parse().ok()?;
+    let major = fields.get(9)?.parse().ok()?;
+    Some((major, minor))
+}
+
+// Allocated and maximum file handles of the whole system
+#[cfg(target_os = "linux")]
+fn system_file_handles() -> Option<(f64, f64)> {
+    let file_nr = std::fs::read_to_string("/proc/sys/fs/file-nr").ok()?;
+    parse_file_nr(&file_nr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn system_file_handles() -> Option<(f64, f64)> {
+    None
+}
+
+// Parse /proc/sys/fs/file-nr, "<allocated> <allocated but unused> <max>"
+fn parse_file_nr(file_nr: &str) -> Option<(f64, f64)> {
+    let values = file_nr
+        .split_whitespace()
+        .map(|v| v.parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match values[..] {
+        [allocated, unused, max] => Some((allocated - unused, max)),
+        _ => None,
+    }
+}
+
+// Parse the "Max open files" line of /proc/<pid>/limits, "unlimited" becomes +Inf
+fn parse_open_files_limits(limits: &str) -> Option<(f64, f64)> {
+    let line = limits.lines().find(|l| l.starts_with("Max open files"))?;
+    let mut values = line["Max open files".len()..].split_whitespace().map(|v| {
+        if v == "unlimited" {
+            Some(f64::INFINITY)
+        } else {
+            v.parse::<f64>().ok()
+        }
+    });
+    let soft_limit = values.next()??;
+    let hard_limit = values.next()??;
+    Some((soft_limit, hard_limit))
+}
+
+// Total and used inodes of a mounted filesystem, sysinfo only reports bytes.
+// Mount points that cannot be queried, and filesystems without a fixed inode count, are skipped.
+#[cfg(unix)]
+fn disk_inodes(mount_point: &std::path::Path) -> Option<(f64, f64)> {
+    let stat = nix::sys::statvfs::statvfs(mount_point).ok()?;
+    let total = stat.files() as f64;
+    if total == 0.0 {
+        return None;
+    }
+    Some((total, total - stat.files_free() as f64))
+}
+
+#[cfg(not(unix))]
+fn disk_inodes(_mount_point: &std::path::Path) -> Option<(f64, f64)> {
+    None
+}
+
+// Memory a host process can use, the limit of its own cgroup, e.g. the container or systemd
+// service it runs in, or else the memory of the host
+fn process_memory_limit(pid: &str, total_memory: u64) -> u64 {
+    match cgroup::process_limits(pid).memory_limit_bytes {
+        Some(limit) => limit.min(total_memory),
+        None => total_memory,
+    }
+}
+
+async fn update_system_metrics(metrics: Arc<Metrics>) -> Result<(), Box<dyn std::error::Error>> {
+    // Memory and swap only, without enumerating processes
+    let mut system = System::new();
+    system.refresh_memory();
+    // Update Memory usage
+    metrics
+        .system_metrics
+        .memory_usage
+        .with_label_values(&["used"])
+        .set(system.used_memory() as f64);
+
+    metrics
+        .system_metrics
+        .total_memory
+        .with_label_values(&["total"])
+        .set(system.total_memory() as f64);
+
+    metrics
+        .system_metrics
+        .available_memory
+        .with_label_values(&["available"])
+        .set(system.available_memory() as f64);
+
+    if system.total_memory() > 0 {
+        metrics
+            .system_metrics
+            .memory_usage_percentage
+            .with_label_values(&["used"])
+            .set(system.used_memory() as f64 / system.total_memory() as f64 * 100.0);
+    }
+
+    match cgroup::memory_limit(std::path::Path::new(cgroup::CGROUP_ROOT)) {
+        Some(limit) => metrics
+            .system_metrics
+            .cgroup_memory_limit
+            .with_label_values(&["limit"])
+            .set(limit as f64),
+        None => {
+            let _ = metrics
+                .system_metrics
+                .cgroup_memory_limit
+                .remove_label_values(&["limit"]);
+        }
+    }
+
+    // Update Disk usage
+    for disk in &Disks::new_with_refreshed_list() {
+        let disk_name = disk.name().to_str().unwrap_or("unknown").to_string();
+        let mount_point = disk.mount_point().to_str().unwrap_or("/").to_string();
+        if mount_point.contains("docker")
+            || mount_point.contains("containerd")
+            || mount_point.contains("kubelet")
+        {
+            continue;
+        }
+        let total_space = disk.total_space() as f64;
+        let available_space = disk.available_space() as f64;
+        let used_space = total_space - available_space;
+
+        metrics
+            .system_metrics
+            .disk_usage
+            .with_label_values(&[&disk_name, &mount_point])
+            .set(used_space);
+
+        metrics
+            .system_metrics
+            .total_disk
+            .with_label_values(&[&disk_name, &mount_point])
+            .set(total_space);
+
+        if let Some((inodes_total, inodes_used)) = disk_inodes(disk.mount_point()) {
+            metrics
+                .system_metrics
+                .disk_inodes_total
+                .with_label_values(&[&disk_name, &mount_point])
+                .set(inodes_total);
+
+            metrics
+                .system_metrics
+                .disk_inodes_used
+                .with_label_values(&[&disk_name, &mount_point])
+                .set(inodes_used);
+        }
+    }
+
+    // Update System uptime
+    let uptime = System::uptime() as f64; // uptime is in seconds
+    metrics
+        .system_metrics
+        .uptime
+        .with_label_values(&["system"])
+        .set(uptime);
+
+    // Update Swap memory
+    metrics
+        .system_metrics
+        .total_swap
+        .with_label_values(&["total"])
+        .set(system.total_swap() as f64);
+
+    metrics
+        .system_metrics
+        .swap_usage
+        .with_label_values(&["used"])
+        .set(system.used_swap() as f64);
+
+    // File handles of the kernel, process limits are per process and do not add up
+    if let Some((open_file, open_file_limit)) = system_file_handles() {
         metrics
             .system_metrics
-            .disk_usage
-            .with_label_values(&[&disk_name, &mount_point])
-            .set(used_space);
+            .open_file
+            .with_label_values(&["system"])
+            .set(open_file);
+
+        metrics
+            .system_metrics
+            .open_file_limit
+            .with_label_values(&["system"])
+            .set(open_file_limit);
+    }
+
+    if !metrics
+        .config
+        .read()
+        .unwrap()
+        .collect_tcp_states
+        .unwrap_or(true)
+    {
+        return Ok(());
+    }
+
+    let af_flags = AddressFamilyFlags::IPV4 | AddressFamilyFlags::IPV6;
+    let proto_flags = ProtocolFlags::TCP;
+
+    let sockets = get_sockets_info(af_flags, proto_flags)?;
+    let state_counts = count_tcp_states(sockets.iter(), metrics.tcp_address_family);
+
+    for (state_labels, count) in state_counts.iter() {
+        let mut labels = vec!["system"];
+        labels.extend(state_labels.iter().map(String::as_str));
+        metrics
+            .system_metrics
+            .tcp_connection_states
+            .with_label_values(&labels)
+            .set(*count as f64);
+    }
+
+    Ok(())
+}
+
+// Most ports exported by process_listening_ports for each process
+const MAX_LISTENING_PORTS: usize = 32;
+
+// Ports of the TCP sockets in LISTEN state, sorted, a port listened on over IPv4 and IPv6 once
+fn listening_ports<'a>(sockets: impl Iterator<Item = &'a SocketInfo>) -> Vec<u16> {
+    let ports: std::collections::BTreeSet<u16> = sockets
+        .filter_map(|socket| match &socket.protocol_socket_info {
+            ProtocolSocketInfo::Tcp(tcp_info) if tcp_info.state.to_string() == "LISTEN" => {
+                Some(tcp_info.local_port)
+            }
+            _ => None,
+        })
+        .collect();
+    ports.into_iter().collect()
+}
+
+// Label values of tcp_connection_states after the process or type labels, the state and,
+// with tcp_address_family, the address family
+fn tcp_state_label_values(by_address_family: bool) -> Vec<Vec<&'static str>> {
+    TCP_STATES
+        .iter()
+        .flat_map(|state| {
+            if by_address_family {
+                ADDRESS_FAMILIES
+                    .iter()
+                    .map(|family| vec![*state, *family])
+                    .collect()
+            } else {
+                vec![vec![*state]]
+            }
+        })
+        .collect()
+}
+
+// Count TCP sockets by the label values of tcp_connection_states, every state starting at 0
+fn count_tcp_states<'a>(
+    sockets: impl Iterator<Item = &'a SocketInfo>,
+    by_address_family: bool,
+) -> HashMap<Vec<String>, usize> {
+    let mut counts: HashMap<Vec<String>, usize> = tcp_state_label_values(by_address_family)
+        .into_iter()
+        .map(|labels| (labels.iter().map(|l| l.to_string()).collect(), 0))
+        .collect();
+    for socket in sockets {
+        if let ProtocolSocketInfo::Tcp(tcp_info) = &socket.protocol_socket_info {
+            let mut labels = vec![tcp_info.state.to_string()];
+            if by_address_family {
+                let family = if tcp_info.local_addr.is_ipv4() {
+                    "ipv4"
+                } else {
+                    "ipv6"
+                };
+                labels.push(family.to_string());
+            }
+            *counts.entry(labels).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+// Get Java processes on the host or within containers. Whether jps is available there is
+// recorded in `dependencies`.
+#[allow(clippy::too_many_arguments)]
+async fn get_java_processes(
+    runner: &dyn CommandRunner,
+    java_home: Option<&str>,
+    full_path: bool,
+    strip_prefixes: &[String],
+    container: String,
+    runtime: Option<&str>,
+    dependencies: &GaugeVec,
+    jps_ok: &AtomicBool,
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let mut processes = HashMap::new();
+
+    if container == "host" {
+        let jps_available = is_jps_available(runner).await;
+        set_dependency_available(dependencies, "jps", &container, jps_available);
+        if !jps_available {
+            warn!("jps command not found, falling back to scanning /proc for Java processes. Install a JDK and set JAVA_HOME for full JVM metrics.");
+            let mut processes = get_java_processes_from_proc(full_path);
+            strip_process_name_prefixes(&mut processes, strip_prefixes);
+            return Ok(processes);
+        }
+        let mut command = Command::new("jps");
+        command.arg("-l");
+        merge_java_home(java_home, &mut command)?;
+        let output = runner.run(command).await?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "jps failed for host: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+
+        let stdout = String::from_utf8(output.stdout)?;
+        info!("Host jps output:\n{}", stdout);
+        jps_ok.store(true, Ordering::Relaxed);
+
+        for line in stdout.lines() {
+            if let Some((pid, name)) = parse_jps_line(line) {
+                if let Some(process_name) = java_process_name(name, full_path) {
+                    processes.insert(pid.to_string(), process_name);
+                }
+            }
+        }
+    } else {
+        // jps is run once, whether it succeeds tells whether it is available, so that remote
+        // hosts and containers do not pay for a second run per collection
+        let output = match jdk_tool_command(&container, runtime, "jps", &["-l"], java_home, None) {
+            Ok(cmd) => {
+                info!(
+                    "Executing jps inside {} container: {}",
+                    runtime.unwrap_or_default(),
+                    container
+                );
+                runner
+                    .run(cmd)
+                    .await
+                    .ok()
+                    .filter(|output| output.status.success())
+            }
+            Err(_) => None,
+        };
+        set_dependency_available(dependencies, "jps", &container, output.is_some());
+        let Some(output) = output else {
+            if runtime == Some(SSH_RUNTIME) {
+                return Err(format!(
+                    "jps cannot be run over ssh, check the connection and the JDK of {}",
+                    container
+                )
+                .into());
+            }
+            error!("jps command not found inside container {}. Please ensure that JDK is installed in the container.", container);
+            return Ok(processes); // Return empty if jps is not available inside the container
+        };
+
+        let stdout = String::from_utf8(output.stdout)?;
+        info!("Container {} jps output:\n{}", container, stdout);
+        jps_ok.store(true, Ordering::Relaxed);
+
+        for line in stdout.lines() {
+            if let Some((pid, name)) = parse_jps_line(line) {
+                if let Some(process_name) = java_process_name(name, full_path) {
+                    processes.insert(pid.to_string(), process_name);
+                }
+            }
+        }
+    }
+
+    strip_process_name_prefixes(&mut processes, strip_prefixes);
+    Ok(processes)
+}
+
+// The container a host process runs in, found by the container ID in its /proc/<pid>/cgroup.
+// jps on the host also lists JVMs of containers sharing its /tmp, other processes are "host".
+fn host_process_container(pid: &str, containers: &[Container]) -> String {
+    std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .ok()
+        .and_then(|proc_cgroup| cgroup_container(&proc_cgroup, containers))
+        .unwrap_or_else(|| "host".to_string())
+}
+
+fn cgroup_container(proc_cgroup: &str, containers: &[Container]) -> Option<String> {
+    containers
+        .iter()
+        .find(|c| !c.id.is_empty() && proc_cgroup.contains(c.id.as_str()))
+        .map(|c| c.id.clone())
+}
+
+// Prefixes stripped from process names, process_name_strip_prefixes
+fn strip_prefixes(metrics: &Metrics) -> Vec<String> {
+    metrics
+        .config
+        .read()
+        .unwrap()
+        .process_name_strip_prefixes
+        .clone()
+        .unwrap_or_default()
+}
+
+// Strip the longest matching prefix from each process name, unless nothing would be left
+fn strip_process_name_prefixes(processes: &mut HashMap<String, String>, prefixes: &[String]) {
+    for name in processes.values_mut() {
+        if let Some(stripped) = strip_name_prefix(name, prefixes) {
+            *name = stripped.to_string();
+        }
+    }
+}
+
+fn strip_name_prefix<'a>(name: &'a str, prefixes: &[String]) -> Option<&'a str> {
+    prefixes
+        .iter()
+        .filter(|prefix| !prefix.is_empty())
+        .filter_map(|prefix| name.strip_prefix(prefix.as_str()))
+        .filter(|stripped| !stripped.is_empty())
+        .min_by_key(|stripped| stripped.len())
+}
+
+// Split a `jps -l` line into the PID and the reported main class or jar.
+// Lines without a numeric PID or without a name are ignored.
+fn parse_jps_line(line: &str) -> Option<(&str, &str)> {
+    let (pid, name) = line.trim().split_once(char::is_whitespace)?;
+    if pid.is_empty() || !pid.chars().all(|c| c.is_ascii_digit()) || name.trim().is_empty() {
+        return None;
+    }
+    Some((pid, name))
+}
+
+// Name of processes whose main class or jar could not be determined
+const UNKNOWN_PROCESS_NAME: &str = "unknown";
+
+// Make a process name usable as a label value: control characters are stripped, whitespace is
+// trimmed and collapsed, and the "process information unavailable" placeholder of jps becomes "unknown".
+fn sanitize_process_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .filter(|c| !c.is_control() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if name.is_empty() || name.trim_start_matches('-').trim() == "process information unavailable" {
+        UNKNOWN_PROCESS_NAME.to_string()
+    } else {
+        name
+    }
+}
+
+// Map the main class or jar reported for a JVM to the process name used as a label.
+// Returns None for excluded processes such as jps itself.
+fn java_process_name(process_name_original: &str, full_path: bool) -> Option<String> {
+    let process_name_original = sanitize_process_name(process_name_original);
+    let process_name_original = process_name_original.as_str();
+    let class_name = process_name_original
+        .split('.')
+        .last()
+        .unwrap_or(process_name_original);
+
+    if EXCLUDED_PROCESSES
+        .iter()
+        .any(|&excluded| excluded.eq_ignore_ascii_case(class_name))
+    {
+        return None;
+    }
+
+    if full_path {
+        Some(process_name_original.to_string())
+    } else {
+        Some(class_name.to_string())
+    }
+}
+
+// Host processes of target_pids, named from their command line like jps does.
+// PIDs that do not exist are skipped.
+fn get_target_processes(target_pids: &[u32], full_path: bool) -> HashMap<String, String> {
+    let pids: Vec<Pid> = target_pids.iter().map(|&pid| Pid::from_u32(pid)).collect();
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        sysinfo::ProcessesToUpdate::Some(&pids),
+        true,
+        sysinfo::ProcessRefreshKind::nothing().with_cmd(sysinfo::UpdateKind::Always),
+    );
+
+    pids.iter()
+        .filter_map(|pid| {
+            let Some(process) = system.process(*pid) else {
+                warn!("Skipping target PID {}: no such process", pid);
+                return None;
+            };
+            let args: Vec<String> = process
+                .cmd()
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            let main = java_main_from_args(&args)
+                .unwrap_or_else(|| process.name().to_string_lossy().into_owned());
+            let process_name = java_process_name(&main, full_path)?;
+            Some((pid.to_string(), process_name))
+        })
+        .collect()
+}
+
+// Find Java processes on the host by scanning /proc, used when jps is not available.
+// Only processes whose executable is `java` are considered.
+fn get_java_processes_from_proc(full_path: bool) -> HashMap<String, String> {
+    let mut processes = HashMap::new();
+
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to read /proc: {}", e);
+            return processes;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_string_lossy().to_string();
+        if pid.is_empty() || !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+
+        let is_java = std::fs::read_link(entry.path().join("exe"))
+            .ok()
+            .and_then(|exe| exe.file_name().map(|name| name == "java"))
+            .unwrap_or(false);
+        if !is_java {
+            continue;
+        }
+
+        let cmdline = match std::fs::read(entry.path().join("cmdline")) {
+            Ok(cmdline) => cmdline,
+            Err(_) => continue,
+        };
+        let args: Vec<String> = cmdline
+            .split(|&b| b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect();
+
+        if let Some(main) = java_main_from_args(&args) {
+            if let Some(process_name) = java_process_name(&main, full_path) {
+                processes.insert(pid, process_name);
+            }
+        }
+    }
+
+    info!("Found {} Java processes in /proc", processes.len());
+    processes
+}
+
+// Extract the main class or jar from a java command line, the same way `jps -l` reports it.
+fn java_main_from_args(args: &[String]) -> Option<String> {
+    // Launcher options that consume the following argument
+    const OPTIONS_WITH_VALUE: &[&str] = &[
+        "-cp",
+        "-classpath",
+        "--class-path",
+        "-p",
+        "--module-path",
+        "--upgrade-module-path",
+        "--add-modules",
+        "--add-exports",
+        "--add-opens",
+        "--add-reads",
+        "--limit-modules",
+        "--patch-module",
+        "--enable-native-access",
+    ];
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-jar" => return iter.next().cloned(),
+            "-m" | "--module" => {
+                return iter
+                    .next()
+                    .map(|module| module.rsplit('/').next().unwrap_or(module).to_string())
+            }
+            a if OPTIONS_WITH_VALUE.contains(&a) => {
+                iter.next();
+            }
+            a if a.starts_with('-') => {}
+            a => return Some(a.to_string()),
+        }
+    }
+    None
+}
+
+#[derive(Debug, PartialEq)]
+enum RuntimeAvailability {
+    Available,
+    PermissionDenied, // Installed, but the exporter user cannot reach its daemon or socket
+    Unavailable,
+}
+
+// Detect if a container runtime CLI (docker, crictl or podman) is available
+async fn runtime_availability(runner: &dyn CommandRunner, runtime: &str) -> RuntimeAvailability {
+    let mut cmd = Command::new(runtime);
+    cmd.arg("ps");
+    match runner.run(cmd).await {
+        Ok(output) if output.status.success() => RuntimeAvailability::Available,
+        Ok(output)
+            if String::from_utf8_lossy(&output.stderr)
+                .to_lowercase()
+                .contains("permission denied") =>
+        {
+            RuntimeAvailability::PermissionDenied
+        }
+        _ => RuntimeAvailability::Unavailable,
+    }
+}
+
+// Runner for the commands of a container runtime, docker goes through sudo with docker_sudo
+fn runtime_runner(metrics: &Metrics, runtime: &str) -> Option<Arc<dyn CommandRunner>> {
+    let docker_sudo = metrics
+        .config
+        .read()
+        .unwrap()
+        .docker_sudo
+        .unwrap_or_default();
+    if runtime == "docker" && docker_sudo {
+        let runner = SudoCommandRunner::new(Arc::clone(&metrics.runner));
+        Some(Arc::new(runner))
+    } else {
+        None
+    }
+}
+
+// How to give the exporter access to a runtime that denied it. docker_sudo only applies to docker,
+// crictl talks to the socket of the CRI runtime and podman only lists the containers of its user.
+fn permission_denied_hint(runtime: &str) -> String {
+    match runtime {
+        "docker" => "Add the exporter user to the docker group, or set docker_sudo: true to run docker through sudo -n".to_string(),
+        "crictl" => "Give the exporter user access to the CRI socket set as runtime-endpoint in /etc/crictl.yaml, e.g. by running it as root".to_string(),
+        _ => format!("Run the exporter as the user owning the {} containers, or as root", runtime),
+    }
+}
+
+// Get Java processes from all containers
+async fn get_container_java_processes(
+    metrics: Arc<Metrics>,
+    full_path: bool,
+) -> Result<Vec<ProcessInfo>, Box<dyn std::error::Error>> {
+    let mut container_processes = Vec::new();
+    if !metrics.config.read().unwrap().detects_containers() {
+        return Ok(container_processes);
+    }
+    let containers = metrics.containers.lock().await.clone();
+    let runtime_outages: HashSet<&'static str> = metrics
+        .runtime_outages
+        .lock()
+        .await
+        .keys()
+        .copied()
+        .collect();
+
+    let exec_timeout = metrics
+        .config
+        .read()
+        .unwrap()
+        .container_exec_timeout_ms
+        .map(Duration::from_millis);
+    let strip_prefixes = strip_prefixes(&metrics);
+
+    for container in containers {
+        let runner = runtime_runner(&metrics, container.runtime);
+        // jstat, jcmd and java -version in the container are bounded by container_exec_timeout_ms
+        let process_runner = match exec_timeout {
+            Some(timeout) => Some(Arc::new(TimeoutCommandRunner::new(
+                runner
+                    .clone()
+                    .unwrap_or_else(|| Arc::clone(&metrics.runner)),
+                timeout,
+            )) as Arc<dyn CommandRunner>),
+            None => runner.clone(),
+        };
+        // jps cannot run while the runtime is not answering, keep the processes last seen
+        if runtime_outages.contains(container.runtime) {
+            container_processes
+                .extend(last_seen_processes(&metrics, &container, &process_runner).await);
+            continue;
+        }
+        let java_home = metrics.config.read().unwrap().container_java_home(
+            &container.id,
+            container.name.as_deref(),
+            container.image.as_deref(),
+        );
+        let discovery = get_java_processes(
+            runner.as_deref().unwrap_or(metrics.runner.as_ref()),
+            java_home.as_deref(),
+            full_path,
+            &strip_prefixes,
+            container.id.clone(),
+            Some(container.runtime),
+            &metrics.system_metrics.dependency_available,
+            &metrics.jps_ok,
+        );
+        let result = match exec_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, discovery).await {
+                Ok(result) => result,
+                Err(_) => {
+                    // Skip the container for this collection, without dropping its series
+                    warn!(
+                        "Listing Java processes in {} container {} timed out after {}ms, skipping it",
+                        container.runtime,
+                        container.id,
+                        timeout.as_millis()
+                    );
+                    container_processes
+                        .extend(last_seen_processes(&metrics, &container, &process_runner).await);
+                    continue;
+                }
+            },
+            None => discovery.await,
+        };
+        match result {
+            Ok(procs) => {
+                for (pid, pname) in procs {
+                    container_processes.push(ProcessInfo {
+                        container: container.id.clone(),
+                        runtime: Some(container.runtime),
+                        image: container.image.clone(),
+                        container_name: container.name.clone(),
+                        pod: container.pod.clone(),
+                        pid,
+                        process: pname,
+                        user: None,
+                        runner: process_runner.clone(),
+                        java_home: None,
+                    });
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to get Java processes for {} container {}: {}",
+                    container.runtime, container.id, e
+                );
+            }
+        }
+    }
+
+    Ok(container_processes)
+}
+
+// Processes of the container found by the previous collection, used when jps cannot run in it
+async fn last_seen_processes(
+    metrics: &Metrics,
+    container: &Container,
+    runner: &Option<Arc<dyn CommandRunner>>,
+) -> Vec<ProcessInfo> {
+    let active_pids = metrics.active_pids.lock().await;
+    active_pids
+        .iter()
+        .filter_map(|(key, pname)| {
+            let (id, pid) = key.split_once('#')?;
+            (id == container.id).then(|| ProcessInfo {
+                container: container.id.clone(),
+                runtime: Some(container.runtime),
+                image: container.image.clone(),
+                container_name: container.name.clone(),
+                pod: container.pod.clone(),
+                pid: pid.to_string(),
+                process: pname.clone(),
+                user: None,
+                runner: runner.clone(),
+                java_home: None,
+            })
+        })
+        .collect()
+}
+
+// Get Java processes from the configured remote hosts. Hosts that cannot be reached are
+// logged and skipped, the container label of their processes is the host.
+async fn get_remote_java_processes(metrics: &Arc<Metrics>, full_path: bool) -> Vec<ProcessInfo> {
+    let remote_hosts = metrics
+        .config
+        .read()
+        .unwrap()
+        .remote_hosts
+        .clone()
+        .unwrap_or_default();
+    let strip_prefixes = strip_prefixes(metrics);
+
+    // Hosts are queried at the same time, so that unreachable ones each cost one ConnectTimeout
+    // instead of adding up
+    let lookups = remote_hosts.into_iter().map(|remote| {
+        let strip_prefixes = &strip_prefixes;
+        async move {
+            if let Some(e) = ssh_destination_error(&remote) {
+                warn!("Skipping remote host {}: {}", remote.host.escape_debug(), e);
+                return Vec::new();
+            }
+            let runner: Arc<dyn CommandRunner> = Arc::new(SshCommandRunner::new(
+                remote.clone(),
+                Arc::clone(&metrics.runner),
+            ));
+            match get_java_processes(
+                runner.as_ref(),
+                None,
+                full_path,
+                strip_prefixes,
+                remote.host.clone(),
+                Some(SSH_RUNTIME),
+                &metrics.system_metrics.dependency_available,
+                &metrics.jps_ok,
+            )
+            .await
+            {
+                Ok(procs) => procs
+                    .into_iter()
+                    .map(|(pid, pname)| ProcessInfo {
+                        container: remote.host.clone(),
+                        runtime: Some(SSH_RUNTIME),
+                        image: None,
+                        container_name: None,
+                        pod: None,
+                        pid,
+                        process: pname,
+                        user: None,
+                        runner: Some(Arc::clone(&runner)),
+                        java_home: None,
+                    })
+                    .collect(),
+                Err(e) => {
+                    warn!(
+                        "Failed to get Java processes of remote host {}: {}",
+                        remote.host, e
+                    );
+                    Vec::new()
+                }
+            }
+        }
+    });
+    futures::future::join_all(lookups)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+// Refresh the cached container list, called from the background timer so that
+// scrapes do not have to query the container runtimes
+pub(crate) async fn refresh_container_list(metrics: &Metrics) {
+    let config = metrics.config.read().unwrap().clone();
+    let container_runtime = config.container_runtime.unwrap_or_default();
+    let previous = metrics.containers.lock().await.clone();
+
+    let mut containers: Vec<Container> = Vec::new();
+    let mut seen_pids: HashSet<String> = HashSet::new();
+    for &runtime in container_runtime.commands() {
+        if !config.detects_runtime(runtime) {
+            continue;
+        }
+        let runner =
+            runtime_runner(metrics, runtime).unwrap_or_else(|| Arc::clone(&metrics.runner));
+        let runner = runner.as_ref();
+        let availability = runtime_availability(runner, runtime).await;
+        set_dependency_available(
+            &metrics.system_metrics.dependency_available,
+            runtime,
+            "host",
+            availability == RuntimeAvailability::Available,
+        );
+        match availability {
+            RuntimeAvailability::Available => {}
+            RuntimeAvailability::PermissionDenied => {
+                // Warn once, this would otherwise repeat on every refresh
+                if metrics.denied_runtimes.lock().await.insert(runtime) {
+                    warn!(
+                        "{} ps failed with permission denied, its containers are not discovered. {}",
+                        runtime,
+                        permission_denied_hint(runtime)
+                    );
+                }
+                continue;
+            }
+            RuntimeAvailability::Unavailable => {
+                keep_during_outage(metrics, runtime, &previous, &mut containers).await;
+                continue;
+            }
+        }
+        let listed = match list_containers(runner, runtime).await {
+            Ok(listed) => listed,
+            Err(e) => {
+                warn!("{}", e);
+                keep_during_outage(metrics, runtime, &previous, &mut containers).await;
+                continue;
+            }
+        };
+        if metrics
+            .runtime_outages
+            .lock()
+            .await
+            .remove(runtime)
+            .is_some()
+        {
+            info!("{} is available again", runtime);
+        }
+        for (id, name, image) in listed {
+            let host_pid = container_host_pid(runner, runtime, &id).await;
+            // The same container can be visible through more than one runtime,
+            // e.g. docker and crictl sharing containerd. Keep the first one by host PID.
+            if container_runtime == ContainerRuntime::Auto {
+                if let Some(host_pid) = &host_pid {
+                    if !seen_pids.insert(host_pid.clone()) {
+                        info!(
+                            "Skipping {} container {}: already seen through another runtime",
+                            runtime, id
+                        );
+                        continue;
+                    }
+                }
+            }
+            update_container_limits(metrics, &id, host_pid.as_deref());
+            // crictl ps only lists IDs. A container keeps its image, name and pod, so crictl
+            // containers are inspected once, and again while inspect fails: a container that was
+            // inspected has a name, also when it has no pod because the kubelet did not start it.
+            let (image, name, pod) = if runtime == "crictl" {
+                match previous.iter().find(|c| c.id == id && c.name.is_some()) {
+                    Some(known) => (known.image.clone(), known.name.clone(), known.pod.clone()),
+                    None => crictl_container_details(runner, &id)
+                        .await
+                        .unwrap_or_default(),
+                }
+            } else {
+                let image = match image {
+                    Some(image) => Some(image),
+                    None => container_image(runner, runtime, &id).await,
+                };
+                (image, name, None)
+            };
+            containers.push(Container {
+                id,
+                runtime,
+                name,
+                image,
+                pod,
+            });
+        }
+    }
+
+    info!("Refreshed container list: {} containers", containers.len());
+    let mut cached = metrics.containers.lock().await;
+    for gone in cached.iter().filter(|old| !containers.iter().any(|c| c.id == old.id)) {
+        update_container_limits(metrics, &gone.id, None);
+        for dependency in ["jps", "jstat"] {
+            let _ = metrics
+                .system_metrics
+                .dependency_available
+                .remove_label_values(&[dependency, &gone.id]);
+        }
+    }
+    *cached = containers;
+}
+
+// Keep the containers a runtime listed before it stopped answering, e.g. during a docker daemon
+// restart, so that their series are not dropped. They are dropped after RUNTIME_OUTAGE_GRACE.
+// A runtime that had no containers, e.g. one that is not installed, is not an outage.
+async fn keep_during_outage(
+    metrics: &Metrics,
+    runtime: &'static str,
+    previous: &[Container],
+    containers: &mut Vec<Container>,
+) {
+    let mut runtime_outages = metrics.runtime_outages.lock().await;
+    let kept: Vec<Container> = previous
+        .iter()
+        .filter(|container| container.runtime == runtime)
+        .cloned()
+        .collect();
+    let Some(since) = runtime_outages.get(runtime).copied() else {
+        if !kept.is_empty() {
+            // Warn once, the processes of these containers are skipped until it is back
+            warn!(
+                "{} is not available, keeping its {} containers for up to {}s",
+                runtime,
+                kept.len(),
+                RUNTIME_OUTAGE_GRACE.as_secs()
+            );
+            runtime_outages.insert(runtime, Instant::now());
+            containers.extend(kept);
+        }
+        return;
+    };
+    if since.elapsed() < RUNTIME_OUTAGE_GRACE {
+        containers.extend(kept);
+    }
+}
+
+// Export the CPU quota and memory limit of a container's cgroup, found through its init process.
+// Limits that are not set, or cannot be read, are removed.
+fn update_container_limits(metrics: &Metrics, container: &str, host_pid: Option<&str>) {
+    let limits = host_pid.map(cgroup::process_limits).unwrap_or_default();
+    let cpu_quota = &metrics.container_metrics.cpu_quota;
+    match limits.cpu_quota_cores {
+        Some(cores) => cpu_quota.with_label_values(&[container]).set(cores),
+        None => {
+            let _ = cpu_quota.remove_label_values(&[container]);
+        }
+    }
+    let memory_limit = &metrics.container_metrics.memory_limit;
+    match limits.memory_limit_bytes {
+        Some(bytes) => memory_limit.with_label_values(&[container]).set(bytes as f64),
+        None => {
+            let _ = memory_limit.remove_label_values(&[container]);
+        }
+    }
+}
+
+// Host PID of the container's init process, used to recognise the same container across runtimes
+async fn container_host_pid(
+    runner: &dyn CommandRunner,
+    runtime: &str,
+    container: &str,
+) -> Option<String> {
+    let mut cmd = Command::new(runtime);
+    if runtime == "crictl" {
+        cmd.args(&[
+            "inspect",
+            "--output",
+            "go-template",
+            "--template",
+            "{{.info.pid}}",
+            container,
+        ]);
+    } else {
+        cmd.args(&["inspect", "--format", "{{.State.Pid}}", container]);
+    }
+    let output = runner.run(cmd).await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let pid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if pid.is_empty() || pid == "0" {
+        None
+    } else {
+        Some(pid)
+    }
+}
+
+// Record whether a tool is usable in a scope: "host", a container or a remote host
+fn set_dependency_available(
+    dependencies: &GaugeVec,
+    dependency: &str,
+    scope: &str,
+    available: bool,
+) {
+    let value = if available { 1.0 } else { 0.0 };
+    dependencies
+        .with_label_values(&[dependency, scope])
+        .set(value);
+}
+
+async fn is_jps_available(runner: &dyn CommandRunner) -> bool {
+    let mut cmd = Command::new("jps");
+    cmd.arg("-l");
+    runner
+        .run(cmd)
+        .await
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+// List running containers of a runtime with their name and image, when the runtime prints them
+async fn list_containers(
+    runner: &dyn CommandRunner,
+    runtime: &str,
+) -> Result<Vec<(String, Option<String>, Option<String>)>, Box<dyn std::error::Error>> {
+    let mut cmd = Command::new(runtime);
+    if runtime == "crictl" {
+        cmd.args(&["ps", "-q"]);
+    } else {
+        cmd.args(&["ps", "--format", "{{.ID}} {{.Names}} {{.Image}}"]);
+    }
+    let output = runner.run(cmd).await?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to list {} containers: {}",
+            runtime,
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let containers = stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let id = fields.next()?.to_string();
+            let name = fields.next().map(|name| name.to_string());
+            let image = fields.next().map(|image| image.to_string());
+            Some((id, name, image))
+        })
+        .collect();
+    Ok(containers)
+}
+
+// Image reference (repo:tag) of a docker or podman container, from inspect
+async fn container_image(
+    runner: &dyn CommandRunner,
+    runtime: &str,
+    container: &str,
+) -> Option<String> {
+    let mut cmd = Command::new(runtime);
+    cmd.args(&["inspect", "--format", "{{.Config.Image}}", container]);
+    let output = runner.run(cmd).await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let image = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if image.is_empty() || image == "<no value>" {
+        None
+    } else {
+        Some(image)
+    }
+}
+
+// Image, name and pod of a crictl container, which crictl ps -q does not print. None when inspect
+// fails, the pod is None for containers not started by the kubelet.
+async fn crictl_container_details(
+    runner: &dyn CommandRunner,
+    container: &str,
+) -> Option<(Option<String>, Option<String>, Option<Pod>)> {
+    let mut cmd = Command::new("crictl");
+    cmd.args(&[
+        "inspect",
+        "--output",
+        "go-template",
+        "--template",
+        CRICTL_INSPECT_TEMPLATE,
+        container,
+    ]);
+    let output = runner.run(cmd).await.ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_crictl_details(&String::from_utf8_lossy(&output.stdout))
+}
+
+// Parse the output of CRICTL_INSPECT_TEMPLATE: image, name and pod labels separated by '|'
+fn parse_crictl_details(output: &str) -> Option<(Option<String>, Option<String>, Option<Pod>)> {
+    let (image, rest) = output.trim().split_once('|')?;
+    let (name, pod_labels) = rest.split_once('|')?;
+    let value = |value: &str| {
+        let value = value.trim();
+        (!value.is_empty() && value != "<no value>").then(|| value.to_string())
+    };
+    Some((value(image), value(name), parse_pod_labels(pod_labels)))
+}
+
+// Parse the pod labels of CRICTL_INSPECT_TEMPLATE: pod name, namespace and UID separated by '|',
+// "<no value>" for labels the container does not have
+fn parse_pod_labels(output: &str) -> Option<Pod> {
+    let fields: Vec<&str> = output.trim().split('|').map(|f| f.trim()).collect();
+    if fields.len() != 3 || fields.iter().any(|f| f.is_empty() || *f == "<no value>") {
+        return None;
+    }
+    Some(Pod {
+        name: fields[0].to_string(),
+        namespace: fields[1].to_string(),
+        uid: fields[2].to_string(),
+    })
+}
+
+fn merge_java_home(
+    java_home: Option<&str>,
+    command: &mut Command,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(jh) = java_home {
+        command.env("JAVA_HOME", jh);
+        command.env(
+            "PATH",
+            format!("{}/bin:{}", jh, std::env::var("PATH").unwrap_or_default()),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::runner::CommandFuture;
+
+    // Returns canned stdout by command line, and fails commands it does not know
+    #[cfg(unix)]
+    struct FakeRunner(HashMap<&'static str, &'static str>);
+
+    fn dependency_gauge() -> GaugeVec {
+        GaugeVec::new(
+            prometheus::Opts::new("dependency_available", "Dependency available"),
+            &["dependency", "scope"],
+        )
+        .unwrap()
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for FakeRunner {
+        fn run(&self, command: Command) -> CommandFuture {
+            use std::os::unix::process::ExitStatusExt;
+
+            let std_command = command.as_std();
+            let command_line = std::iter::once(std_command.get_program())
+                .chain(std_command.get_args())
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let output = match self.0.get(command_line.as_str()) {
+                Some(stdout) => std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: stdout.as_bytes().to_vec(),
+                    stderr: Vec::new(),
+                },
+                None => std::process::Output {
+                    status: std::process::ExitStatus::from_raw(1 << 8),
+                    stdout: Vec::new(),
+                    stderr: format!("unexpected command: {}", command_line).into_bytes(),
+                },
+            };
+            Box::pin(async move { Ok(output) })
+        }
+    }
+
+    fn jps_process(line: &str) -> Option<(String, String)> {
+        parse_jps_line(line).and_then(|(pid, name)| {
+            java_process_name(name, true).map(|process| (pid.to_string(), process))
+        })
+    }
+
+    #[test]
+    fn parses_regular_jps_lines() {
+        assert_eq!(
+            jps_process("12345 com.example.Main"),
+            Some(("12345".to_string(), "com.example.Main".to_string()))
+        );
+        assert_eq!(
+            jps_process("  42\t/opt/app/service.jar  "),
+            Some(("42".to_string(), "/opt/app/service.jar".to_string()))
+        );
+    }
+
+    #[test]
+    fn maps_unavailable_process_information_to_unknown() {
+        assert_eq!(
+            jps_process("12345 -- process information unavailable"),
+            Some(("12345".to_string(), "unknown".to_string()))
+        );
+        assert_eq!(
+            jps_process("12345 --   process information\tunavailable"),
+            Some(("12345".to_string(), "unknown".to_string()))
+        );
+    }
+
+    #[test]
+    fn collapses_whitespace_and_strips_control_characters() {
+        assert_eq!(
+            jps_process("7 /opt/my   app/ser\u{1b}vice.jar\r"),
+            Some(("7".to_string(), "/opt/my app/service.jar".to_string()))
+        );
+    }
+
+    #[test]
+    fn skips_lines_without_pid_or_name() {
+        assert_eq!(jps_process("12345"), None);
+        assert_eq!(jps_process("12345   "), None);
+        assert_eq!(jps_process(""), None);
+        assert_eq!(jps_process("Error: could not attach"), None);
+    }
+
+    #[test]
+    fn finds_containers_of_host_processes() {
+        let containers = vec![
+            Container {
+                id: "abc123def456".to_string(),
+                runtime: "docker",
+                name: None,
+                image: None,
+                pod: None,
+            },
+            Container {
+                id: "fed789".to_string(),
+                runtime: "crictl",
+                name: None,
+                image: None,
+                pod: None,
+            },
+        ];
+        let proc_cgroup = "0::/system.slice/docker-abc123def4567890aaaa.scope\n";
+        assert_eq!(
+            cgroup_container(proc_cgroup, &containers),
+            Some("abc123def456".to_string())
+        );
+        assert_eq!(cgroup_container("0::/user.slice\n", &containers), None);
+    }
+
+    #[test]
+    fn strips_process_name_prefixes() {
+        let prefixes = vec![
+            "com.example.".to_string(),
+            "com.example.services.".to_string(),
+        ];
+        let mut processes = HashMap::from([
+            ("1".to_string(), "com.example.services.Billing".to_string()),
+            ("2".to_string(), "com.example.Gateway".to_string()),
+            ("3".to_string(), "com.example.".to_string()),
+            ("4".to_string(), "org.other.Main".to_string()),
+        ]);
+        strip_process_name_prefixes(&mut processes, &prefixes);
+        assert_eq!(processes["1"], "Billing");
+        assert_eq!(processes["2"], "Gateway");
+        assert_eq!(processes["3"], "com.example.");
+        assert_eq!(processes["4"], "org.other.Main");
+    }
+
+    #[test]
+    fn parses_context_switches() {
+        let status = "Name:\tjava\nThreads:\t42\nvoluntary_ctxt_switches:\t1500\nnonvoluntary_ctxt_switches:\t27\n";
+        assert_eq!(parse_context_switches(status), Some((1500, 27)));
+        assert_eq!(parse_context_switches("Name:\tjava\n"), None);
+    }
+
+    #[test]
+    fn parses_page_faults() {
+        let stat =
+            "4242 (java main) S 1 4242 4242 0 -1 4194560 81234 0 57 0 1200 300 0 0 20 0 42 0";
+        assert_eq!(parse_page_faults(stat), Some((57, 81234)));
+        assert_eq!(parse_page_faults("4242 (java) S 1"), None);
+    }
+
+    #[test]
+    fn parses_file_nr() {
+        assert_eq!(parse_file_nr("10432\t0\t65536\n"), Some((10432.0, 65536.0)));
+        assert_eq!(parse_file_nr("2048 48 812345\n"), Some((2000.0, 812345.0)));
+        assert_eq!(parse_file_nr("2048 0\n"), None);
+    }
+
+    #[test]
+    fn parses_open_files_limits() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max processes             63704                63704                processes \n\
+                      Max open files            1024                 524288               files     \n";
+        assert_eq!(parse_open_files_limits(limits), Some((1024.0, 524288.0)));
+        assert_eq!(
+            parse_open_files_limits("Max open files  unlimited  unlimited  files\n"),
+            Some((f64::INFINITY, f64::INFINITY))
+        );
+        assert_eq!(
+            parse_open_files_limits("Max processes  63704  63704  processes\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn detects_jdk_version_mismatches() {
+        assert!(is_version_mismatch_error(
+            "sun.jvmstat.monitor.MonitorException: Could not load PerfDataBuffer implementation for version 2"
+        ));
+        assert!(is_version_mismatch_error(
+            "java.lang.UnsupportedClassVersionError: sun/tools/jstat/Jstat"
+        ));
+        assert!(!is_version_mismatch_error("42 not found"));
+        assert!(!is_version_mismatch_error("Could not attach to 42"));
+    }
+
+    #[test]
+    fn combines_jstat_columns_of_aggregated_pids() {
+        let gcutil = GaugeVec::new(
+            prometheus::Opts::new("jstat_gcutil_metrics", "Metrics from jstat -gcutil"),
+            &["container", "process_name", "metric_name"],
+        )
+        .unwrap();
+        let mut updates = GaugeUpdates::default();
+        // Two PIDs of the same process name, aggregated under the same labels
+        for (old, young_gcs) in [(80.0, 3.0), (60.0, 4.0)] {
+            combine_jstat_column(
+                &mut updates,
+                &gcutil,
+                &["host", "app", "O"],
+                "-gcutil",
+                "O",
+                old,
+            );
+            combine_jstat_column(
+                &mut updates,
+                &gcutil,
+                &["host", "app", "YGC"],
+                "-gcutil",
+                "YGC",
+                young_gcs,
+            );
+        }
+        updates.apply();
+        assert_eq!(gcutil.with_label_values(&["host", "app", "O"]).get(), 80.0);
+        assert_eq!(gcutil.with_label_values(&["host", "app", "YGC"]).get(), 7.0);
+    }
+
+    #[test]
+    fn detects_non_hotspot_errors() {
+        assert!(is_non_hotspot_error(
+            "42 is not a HotSpot process, jstat cannot attach to it"
+        ));
+        assert!(!is_non_hotspot_error("42 not found"));
+    }
+
+    #[test]
+    fn retries_only_attach_errors() {
+        assert!(is_transient_jstat_error(
+            "jstat -gc failed for PID 42 in container host: Could not attach to 42",
+            "42"
+        ));
+        assert!(is_transient_jstat_error(
+            "com.sun.tools.attach.AttachNotSupportedException: Unable to open socket file",
+            "42"
+        ));
+        assert!(!is_transient_jstat_error("42 not found", "42"));
+        assert!(!is_transient_jstat_error(
+            "Could not attach to 42: No such process",
+            "42"
+        ));
+        assert!(!is_transient_jstat_error("Unexpected jstat output", "42"));
+    }
+
+    #[test]
+    fn lists_target_pids() {
+        let own_pid = std::process::id();
+        // PIDs are at most 2^22 on Linux
+        let processes = get_target_processes(&[own_pid, u32::MAX], false);
+        assert_eq!(processes.len(), 1);
+        assert!(processes.contains_key(&own_pid.to_string()));
+    }
+
+    #[test]
+    fn detects_restarted_pids() {
+        let previous = HashMap::from([
+            ("host#100".to_string(), 1000),
+            ("host#200".to_string(), 2000),
+        ]);
+        let current = HashMap::from([
+            ("host#100".to_string(), 1000),
+            ("host#200".to_string(), 2500),
+            ("host#300".to_string(), 3000),
+        ]);
+        assert_eq!(
+            restarted_pids(&previous, &current),
+            HashSet::from(["host#200".to_string()])
+        );
+
+        let own_pid = std::process::id().to_string();
+        let start_times = process_start_times(std::iter::once(own_pid.as_str()));
+        assert!(start_times.get(&own_pid).is_some_and(|&start| start > 0));
+    }
+
+    #[test]
+    fn splits_tcp_states_by_address_family() {
+        let labels = tcp_state_label_values(false);
+        assert_eq!(labels.len(), TCP_STATES.len());
+        assert_eq!(labels[0], vec!["CLOSED"]);
+
+        let labels = tcp_state_label_values(true);
+        assert_eq!(labels.len(), TCP_STATES.len() * 2);
+        assert_eq!(labels[0], vec!["CLOSED", "ipv4"]);
+        assert_eq!(labels[1], vec!["CLOSED", "ipv6"]);
+    }
+
+    #[test]
+    fn names_process_states() {
+        assert_eq!(process_state_name(sysinfo::ProcessStatus::Run), "running");
+        assert_eq!(process_state_name(sysinfo::ProcessStatus::Zombie), "zombie");
+        assert_eq!(
+            process_state_name(sysinfo::ProcessStatus::UninterruptibleDiskSleep),
+            "disk_sleep"
+        );
+        assert_eq!(
+            process_state_name(sysinfo::ProcessStatus::Unknown(42)),
+            "unknown"
+        );
+    }
+
+    #[test]
+    fn caps_series_at_max_series() {
+        let registry = Registry::new();
+        let config = crate::config::Config {
+            collect_system_metrics: Some(false),
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&registry, Arc::new(std::sync::RwLock::new(config)));
+        // Room for 2 PIDs next to the series reported without any JVM
+        let series: usize = metrics
+            .collectors()
+            .iter()
+            .flat_map(|collector| collector.collect())
+            .map(|family| family.get_metric().len())
+            .sum();
+        metrics.config.write().unwrap().max_series = Some(series + 2);
+        let cpu_usage = &metrics.process_metrics.cpu_usage;
+        cpu_usage.with_label_values(&["host", "1", "Main"]).set(1.0);
+        cpu_usage.with_label_values(&["host", "2", "Main"]).set(1.0);
+        metrics.check_series_limit();
+        assert!(!metrics.series_capped.load(Ordering::Relaxed));
+
+        cpu_usage.with_label_values(&["host", "3", "Main"]).set(1.0);
+        metrics.check_series_limit();
+        assert!(metrics.series_capped.load(Ordering::Relaxed));
+
+        let _ = cpu_usage.remove_label_values(&["host", "3", "Main"]);
+        metrics.check_series_limit();
+        assert!(!metrics.series_capped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        let etag = metrics_etag(b"process_cpu_usage 1\n", false);
+        assert_ne!(etag, metrics_etag(b"process_cpu_usage 2\n", false));
+        assert_ne!(etag, metrics_etag(b"process_cpu_usage 1\n", true));
+        assert!(etag_matches(Some(&etag), &etag));
+        assert!(etag_matches(Some(&format!("\"other\", W/{}", etag)), &etag));
+        assert!(etag_matches(Some("*"), &etag));
+        assert!(!etag_matches(Some("\"other\""), &etag));
+        assert!(!etag_matches(None, &etag));
+    }
+
+    #[test]
+    fn runs_tools_with_the_discovering_runtime() {
+        // The command line run for a container of the given runtime
+        let command_line = |runtime| {
+            let cmd = jdk_tool_command("abc123", runtime, "jstat", &["-gc", "1"], None, None);
+            let cmd = cmd.unwrap();
+            let cmd = cmd.as_std();
+            std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(
+            command_line(Some("crictl")),
+            "crictl exec abc123 jstat -gc 1"
+        );
+        assert_eq!(
+            command_line(Some("docker")),
+            "docker exec abc123 jstat -gc 1"
+        );
+        assert!(jdk_tool_command("abc123", None, "jstat", &[], None, None).is_err());
+
+        let cmd = jdk_tool_command("abc123", Some("docker"), "jps", &[], Some("/opt/jdk"), None);
+        let cmd = cmd.unwrap();
+        let args: Vec<_> = cmd.as_std().get_args().collect();
+        assert_eq!(args, ["exec", "abc123", "/opt/jdk/bin/jps"]);
+        assert!(cmd.as_std().get_envs().next().is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn detects_java_homes_in_containers_only_with_jstat() {
+        let runner = FakeRunner(HashMap::from([
+            (
+                "docker exec abc123 readlink /proc/7/exe",
+                "/opt/jdk/bin/java\n",
+            ),
+            ("docker exec abc123 test -x /opt/jdk/bin/jstat", ""),
+            (
+                "docker exec def456 readlink /proc/7/exe",
+                "/opt/jre/bin/java\n",
+            ),
+        ]));
+        let process = |container: &str| ProcessInfo {
+            container: container.to_string(),
+            runtime: Some("docker"),
+            image: None,
+            container_name: None,
+            pod: None,
+            pid: "7".to_string(),
+            process: "com.example.Main".to_string(),
+            user: None,
+            runner: None,
+            java_home: None,
+        };
+        assert_eq!(
+            detect_java_home(&runner, &process("abc123"))
+                .await
+                .as_deref(),
+            Some("/opt/jdk")
+        );
+        // A JRE has no jstat, the tools on the PATH of the container are used
+        assert_eq!(detect_java_home(&runner, &process("def456")).await, None);
+    }
+
+    #[test]
+    fn finds_java_home_of_executables() {
+        assert_eq!(
+            java_home_of("/usr/lib/jvm/java-17-openjdk/bin/java").as_deref(),
+            Some("/usr/lib/jvm/java-17-openjdk")
+        );
+        assert_eq!(
+            java_home_of("/opt/jdk1.8.0_392/jre/bin/java").as_deref(),
+            Some("/opt/jdk1.8.0_392")
+        );
+        assert_eq!(java_home_of("/opt/app/bin/native-image-app"), None);
+        assert_eq!(java_home_of("/usr/bin/java (deleted)"), None);
+    }
+
+    #[test]
+    fn resolves_container_java_homes() {
+        let config = crate::config::Config {
+            container_java_homes: Some(HashMap::from([
+                ("abc".to_string(), "/opt/jdk-short".to_string()),
+                ("abc123".to_string(), "/opt/jdk-id".to_string()),
+                ("registry:5000/app".to_string(), "/opt/java-17".to_string()),
+                ("billing".to_string(), "/opt/jdk-billing".to_string()),
+            ])),
+            ..Default::default()
+        };
+        assert_eq!(
+            config
+                .container_java_home("abc123def", None, None)
+                .as_deref(),
+            Some("/opt/jdk-id")
+        );
+        assert_eq!(
+            config
+                .container_java_home("fed789", Some("orders"), Some("registry:5000/app:1.2"))
+                .as_deref(),
+            Some("/opt/java-17")
+        );
+        assert_eq!(
+            config
+                .container_java_home("fed789", Some("billing"), Some("registry:5000/app:1.2"))
+                .as_deref(),
+            Some("/opt/jdk-billing")
+        );
+        assert!(config
+            .container_java_home("fed789", Some("orders"), Some("other:1.2"))
+            .is_none());
+    }
+
+    #[test]
+    fn keeps_the_global_java_home_out_of_containers() {
+        let registry = Registry::new();
+        let metrics = Metrics::new(
+            &registry,
+            Arc::new(std::sync::RwLock::new(crate::config::Config::default())),
+        );
+        let process = |container: &str, runtime, java_home: Option<&str>| ProcessInfo {
+            container: container.to_string(),
+            runtime,
+            image: None,
+            container_name: None,
+            pod: None,
+            pid: "7".to_string(),
+            process: "com.example.Main".to_string(),
+            user: None,
+            runner: None,
+            java_home: java_home.map(|jh| jh.to_string()),
+        };
+        assert_eq!(
+            process_java_home(&metrics, &process("host", None, None), Some("/opt/jdk")).as_deref(),
+            Some("/opt/jdk")
+        );
+        // Without a container_java_homes entry, the JDK detected in the container or its PATH
+        let detected = process("abc123", Some("docker"), Some("/usr/lib/jvm/java-17"));
+        assert_eq!(
+            process_java_home(&metrics, &detected, Some("/opt/jdk")).as_deref(),
+            Some("/usr/lib/jvm/java-17")
+        );
+        let undetected = process("abc123", Some("docker"), None);
+        assert_eq!(
+            process_java_home(&metrics, &undetected, Some("/opt/jdk")),
+            None
+        );
+    }
+
+    #[test]
+    fn writes_textfile() {
+        let registry = Registry::new();
+        let gauge =
+            GaugeVec::new(prometheus::Opts::new("jvm_up", "help"), &["process_name"]).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.with_label_values(&["myapp"]).set(1.0);
+
+        let dir =
+            std::env::temp_dir().join(format!("jvm-exporter-textfile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_textfile(&registry, &dir).unwrap();
+        let written = std::fs::read_to_string(dir.join(TEXTFILE_NAME)).unwrap();
+        assert!(written.contains("jvm_up{process_name=\"myapp\"} 1"));
+        assert!(!dir.join(format!(".{}.tmp", TEXTFILE_NAME)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn matches_system_processes() {
+        let patterns = ["myapp*".to_string(), "[".to_string()];
+        let globs = compile_system_processes(&patterns, ProcessMatchType::Glob);
+        assert_eq!(globs.len(), 1);
+        assert!(globs[0].matches("myapp-server"));
+        assert!(!globs[0].matches("other-myapp"));
+
+        let regexes = compile_system_processes(&patterns, ProcessMatchType::Regex);
+        assert_eq!(regexes.len(), 1);
+        assert!(regexes[0].matches("other-myap"));
+
+        let errors = system_process_pattern_errors(&patterns, ProcessMatchType::Regex);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("invalid pattern [: "));
+    }
+
+    #[test]
+    fn filters_metrics_by_container() {
+        let registry = Registry::new();
+        let usage = prometheus::GaugeVec::new(
+            prometheus::Opts::new("process_cpu_usage", "CPU usage"),
+            &["container", "pid"],
+        )
+        .unwrap();
+        let uptime = prometheus::Gauge::new("system_uptime", "Uptime").unwrap();
+        registry.register(Box::new(usage.clone())).unwrap();
+        registry.register(Box::new(uptime.clone())).unwrap();
+        usage.with_label_values(&["host", "1"]).set(1.0);
+        usage.with_label_values(&["abc123", "7"]).set(2.0);
+        uptime.set(10.0);
+
+        let families = filter_by_container(registry.gather(), &["abc123".to_string()]);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_metric().len(), 1);
+        assert_eq!(families[0].get_metric()[0].get_gauge().get_value(), 2.0);
+        assert!(filter_by_container(registry.gather(), &["missing".to_string()]).is_empty());
+
+        // A container name matches the ID of the container
+        let containers = vec![Container {
+            id: "abc123".to_string(),
+            runtime: "docker",
+            name: Some("web".to_string()),
+            image: None,
+            pod: None,
+        }];
+        let families = filter_by_container(registry.gather(), &container_ids(&containers, "web"));
+        assert_eq!(families[0].get_metric().len(), 1);
+        assert_eq!(families[0].get_metric()[0].get_gauge().get_value(), 2.0);
+        assert_eq!(container_ids(&containers, "host"), vec!["host".to_string()]);
+    }
+
+    #[test]
+    fn accepts_gzip_unless_its_q_value_is_zero() {
+        assert!(accepts_gzip(Some("gzip, deflate")));
+        assert!(accepts_gzip(Some("br;q=1.0, gzip;q=0.5")));
+        assert!(accepts_gzip(Some("*")));
+        assert!(!accepts_gzip(None));
+        assert!(!accepts_gzip(Some("deflate")));
+        assert!(!accepts_gzip(Some("gzip;q=0")));
+        assert!(!accepts_gzip(Some("gzip; q = 0.0")));
+        assert!(!accepts_gzip(Some("gzip;q=0.000")));
+        assert!(!accepts_gzip(Some("gzip;q=abc")));
+    }
+
+    #[test]
+    fn serializes_histograms_to_json() {
+        let registry = Registry::new();
+        let pauses = prometheus::HistogramVec::new(
+            prometheus::HistogramOpts::new("jvm_gc_pause_seconds", "GC pauses")
+                .buckets(vec![0.1, 1.0]),
+            &["process_name"],
+        )
+        .unwrap();
+        registry.register(Box::new(pauses.clone())).unwrap();
+        pauses.with_label_values(&["app"]).observe(0.0625);
+        pauses.with_label_values(&["app"]).observe(2.0);
+
+        let json = metric_family_to_json(&registry.gather()[0]);
+        assert_eq!(json["type"], "histogram");
+        let series = &json["metrics"][0];
+        assert_eq!(series["labels"]["process_name"], "app");
+        assert_eq!(series["count"], 2);
+        assert_eq!(series["sum"], 2.0625);
+        assert_eq!(
+            series["buckets"],
+            serde_json::json!([
+                { "le": "0.1", "count": 1 },
+                { "le": "1", "count": 1 },
+                { "le": "+Inf", "count": 2 },
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_java_runtimes() {
+        let temurin = "openjdk version \"17.0.8\" 2023-07-18\n\
+                       OpenJDK Runtime Environment Temurin-17.0.8+7 (build 17.0.8+7)\n\
+                       OpenJDK 64-Bit Server VM Temurin-17.0.8+7 (build 17.0.8+7, mixed mode)\n";
+        assert_eq!(
+            parse_java_runtime(temurin),
+            Some(("Temurin".to_string(), "17.0.8".to_string()))
+        );
+        let oracle = "java version \"1.8.0_381\"\n\
+                      Java(TM) SE Runtime Environment (build 1.8.0_381-b09)\n\
+                      Java HotSpot(TM) 64-Bit Server VM (build 25.381-b09, mixed mode)\n";
+        assert_eq!(
+            parse_java_runtime(oracle),
+            Some(("HotSpot".to_string(), "1.8.0_381".to_string()))
+        );
+        let openj9 = "openjdk version \"11.0.20\" 2023-07-18\n\
+                      IBM Semeru Runtime Open Edition 11.0.20.0 (build 11.0.20+8)\n\
+                      Eclipse OpenJ9 VM 11.0.20.0 (build openj9-0.40.0, JRE 11 Linux amd64-64-Bit)\n";
+        assert_eq!(
+            parse_java_runtime(openj9),
+            Some(("OpenJ9".to_string(), "11.0.20".to_string()))
+        );
+        let graalvm = "java version \"21.0.1\" 2023-10-17\n\
+                       Java(TM) SE Runtime Environment Oracle GraalVM 21.0.1+12.1 (build 21.0.1+12-jvmci-23.1-b19)\n\
+                       Java HotSpot(TM) 64-Bit Server VM Oracle GraalVM 21.0.1+12.1 (build 21.0.1+12-jvmci-23.1-b19, mixed mode)\n";
+        assert_eq!(
+            parse_java_runtime(graalvm),
+            Some(("GraalVM".to_string(), "21.0.1".to_string()))
+        );
+        let openjdk = "openjdk version \"21.0.2\" 2024-01-16\n\
+                       OpenJDK Runtime Environment (build 21.0.2+13-58)\n\
+                       OpenJDK 64-Bit Server VM (build 21.0.2+13-58, mixed mode, sharing)\n";
+        assert_eq!(
+            parse_java_runtime(openjdk),
+            Some(("OpenJDK".to_string(), "21.0.2".to_string()))
+        );
+        assert_eq!(
+            parse_java_runtime("Error: could not open `/proc/1/exe'"),
+            None
+        );
+    }
+
+    #[test]
+    fn computes_heap_used_percentage() {
+        let values: Vec<(String, f64)> = [
+            ("S0C", 0.0),
+            ("S1C", 1024.0),
+            ("S0U", 0.0),
+            ("S1U", 512.0),
+            ("EC", 2048.0),
+            ("EU", 1024.0),
+            ("OC", 4096.0),
+            ("OU", 1024.0),
+            ("MC", 512.0),
+            ("MU", 500.0),
+        ]
+        .iter()
+        .map(|(h, v)| (h.to_string(), *v))
+        .collect();
+        assert_eq!(
+            heap_used_percentage(&values),
+            Some(2560.0 / 7168.0 * 100.0)
+        );
+        assert_eq!(heap_used_percentage(&values[2..]), None);
+    }
 
-        metrics
-            .system_metrics
-            .total_disk
-            .with_label_values(&[&disk_name, &mount_point])
-            .set(total_space);
+    #[test]
+    fn computes_space_utilization() {
+        let values = vec![
+            ("OC".to_string(), 4096.0),
+            ("OU".to_string(), 1024.0),
+            ("MC".to_string(), 0.0),
+            ("MU".to_string(), 0.0),
+        ];
+        assert_eq!(space_utilization(&values, "O"), Some(25.0));
+        assert_eq!(space_utilization(&values, "M"), None);
+        assert_eq!(space_utilization(&values, "CCS"), None);
     }
 
-    // Update System uptime
-    let uptime = System::uptime() as f64; // uptime is in seconds
-    metrics
-        .system_metrics
-        .uptime
-        .with_label_values(&["system"])
-        .set(uptime);
+    #[test]
+    fn runs_optional_jstat_commands_only_when_listed() {
+        let registry = Registry::new();
+        let metrics = Metrics::new(
+            &registry,
+            Arc::new(std::sync::RwLock::new(crate::config::Config::default())),
+        );
+        assert!(runs_jstat_command(&metrics, &[], "-gc"));
+        assert!(runs_jstat_command(&metrics, &[], "-class"));
+        assert!(!runs_jstat_command(&metrics, &[], "-gcutil"));
+        assert!(!runs_jstat_command(&metrics, &[], "-gccapacity"));
+        assert!(runs_jstat_command(
+            &metrics,
+            &["-gccapacity".to_string()],
+            "-gccapacity"
+        ));
+    }
 
-    // Update Swap memory
-    metrics
-        .system_metrics
-        .total_swap
-        .with_label_values(&["total"])
-        .set(system.total_swap() as f64);
+    #[test]
+    fn computes_gc_time_percentage() {
+        let values = vec![("FGCT".to_string(), 1.0), ("GCT".to_string(), 3.0)];
+        assert_eq!(gc_time_percentage(&values, 60.0), Some(5.0));
+        assert_eq!(gc_time_percentage(&values, 2.0), Some(100.0));
+        assert_eq!(gc_time_percentage(&values, 0.0), None);
+        assert_eq!(gc_time_percentage(&values[..1], 60.0), None);
+    }
 
-    metrics
-        .system_metrics
-        .swap_usage
-        .with_label_values(&["used"])
-        .set(system.used_swap() as f64);
+    #[test]
+    fn parses_process_args() {
+        let output = "12345:\nVM Arguments:\njvm_args: -Xmx1g -Dapp.env=prod\n\
+                      java_command: com.example.Main --port 8080  --verbose\n\
+                      java_class_path (initial): /opt/app/lib/app.jar\n\
+                      Launcher Type: SUN_STANDARD\n";
+        assert_eq!(
+            parse_process_args(output),
+            Some("--port 8080 --verbose".to_string())
+        );
+        assert_eq!(
+            parse_process_args("java_command: /opt/app/service.jar\n"),
+            Some(String::new())
+        );
+        assert_eq!(parse_process_args("12345:\nVM Arguments:\n"), None);
+    }
 
-    let open_file = system
-        .processes()
-        .iter()
-        .map(|(_, process)| process.open_files().unwrap_or(0) as f64)
-        .sum::<f64>();
+    #[test]
+    fn redacts_secret_process_args() {
+        assert_eq!(
+            redact_process_args(
+                "--port 8080 --db-password=s3cr3t -Dapp.secret.key=abc --api-token xyz --verbose"
+            ),
+            "--port 8080 --db-password=*** -Dapp.secret.key=*** --api-token *** --verbose"
+        );
+        assert_eq!(
+            redact_process_args("--token --verbose"),
+            "--token --verbose"
+        );
+    }
 
-    let open_file_limit = system
-        .processes()
-        .iter()
-        .map(|(_, process)| process.open_files_limit().unwrap_or(0) as f64)
-        .sum::<f64>();
+    #[tokio::test]
+    async fn replaces_process_info_once_the_args_are_known() {
+        let registry = Registry::new();
+        let config = crate::config::Config {
+            collect_tcp_states: Some(false),
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::new(
+            &registry,
+            Arc::new(std::sync::RwLock::new(config)),
+        ));
+        metrics
+            .system
+            .lock()
+            .await
+            .refresh_processes(sysinfo::ProcessesToUpdate::All, true);
+        let pid = std::process::id().to_string();
+        let processes = [ProcessInfo {
+            container: "host".to_string(),
+            runtime: None,
+            image: None,
+            container_name: None,
+            pod: None,
+            pid: pid.clone(),
+            process: "com.example.Main".to_string(),
+            user: None,
+            runner: None,
+            java_home: None,
+        }];
+        // The args label of every jvm_process_info series
+        let args_labels = || {
+            registry
+                .gather()
+                .iter()
+                .filter(|family| family.get_name() == "jvm_process_info")
+                .flat_map(|family| family.get_metric().to_vec())
+                .flat_map(|metric| metric.get_label().to_vec())
+                .filter(|label| label.get_name() == "args")
+                .map(|label| label.get_value().to_string())
+                .collect::<Vec<_>>()
+        };
 
-    metrics
-        .system_metrics
-        .open_file
-        .with_label_values(&["system"])
-        .set(open_file);
+        update_cpu_memory_metrics(Arc::clone(&metrics), &processes)
+            .await
+            .unwrap();
+        assert_eq!(args_labels(), vec![String::new()]);
 
-    metrics
-        .system_metrics
-        .open_file_limit
-        .with_label_values(&["system"])
-        .set(open_file_limit);
+        metrics
+            .process_args
+            .lock()
+            .await
+            .insert(format!("host#{}", pid), "--port 8080".to_string());
+        update_cpu_memory_metrics(Arc::clone(&metrics), &processes)
+            .await
+            .unwrap();
+        assert_eq!(args_labels(), vec!["--port 8080".to_string()]);
+    }
 
-    let af_flags = AddressFamilyFlags::IPV4 | AddressFamilyFlags::IPV6;
-    let proto_flags = ProtocolFlags::TCP;
+    #[test]
+    fn parses_native_memory_summary() {
+        let output = "12345:\n\
+                      \n\
+                      Native Memory Tracking:\n\
+                      \n\
+                      Total: reserved=1457373KB, committed=148453KB\n\
+                      -                 Java Heap (reserved=262144KB, committed=16384KB)\n\
+                      (mmap: reserved=262144KB, committed=16384KB)\n\
+                      \n\
+                      -                     Class (reserved=1056882KB, committed=4978KB)\n\
+                      (classes #785)\n";
+        assert_eq!(
+            parse_native_memory(output),
+            vec![
+                ("total".to_string(), 1457373.0 * 1024.0, 148453.0 * 1024.0),
+                ("java_heap".to_string(), 262144.0 * 1024.0, 16384.0 * 1024.0),
+                ("class".to_string(), 1056882.0 * 1024.0, 4978.0 * 1024.0),
+            ]
+        );
+        assert!(parse_native_memory("Native memory tracking is not enabled").is_empty());
+    }
 
-    let sockets = get_sockets_info(af_flags, proto_flags)?;
-    let mut state_counts: HashMap<String, usize> = HashMap::new();
-    
-    for state in TCP_STATES {
-        state_counts.insert(state.to_string(), 0);
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn remembers_jvms_without_native_memory_tracking() {
+        let registry = Registry::new();
+        let mut metrics = Metrics::new(
+            &registry,
+            Arc::new(std::sync::RwLock::new(Default::default())),
+        );
+        metrics.runner = Arc::new(FakeRunner(HashMap::from([(
+            "jcmd 42 VM.native_memory summary scale=KB",
+            "42:\nNative memory tracking is not enabled\n",
+        )])));
+        let metrics = Arc::new(metrics);
+        let processes = [ProcessInfo {
+            container: "host".to_string(),
+            runtime: None,
+            image: None,
+            container_name: None,
+            pod: None,
+            pid: "42".to_string(),
+            process: "com.example.Main".to_string(),
+            user: None,
+            runner: None,
+            java_home: None,
+        }];
+        update_jcmd_metrics(Arc::clone(&metrics), &processes, None, &|_| None, None).await;
+        assert!(metrics.nmt_disabled_pids.lock().await.contains("host#42"));
     }
-    for socket in sockets.iter() {
-        if let ProtocolSocketInfo::Tcp(tcp_info) = &socket.protocol_socket_info {
-            *state_counts.entry(tcp_info.state.to_string()).or_insert(0) += 1;
-        }
+
+    #[test]
+    fn parses_java_major_versions() {
+        assert_eq!(java_major_version("17.0.8"), Some(17));
+        assert_eq!(java_major_version("21"), Some(21));
+        assert_eq!(java_major_version("1.8.0_381"), Some(8));
+        assert_eq!(java_major_version("unknown"), None);
     }
 
-    for (state, count) in state_counts.iter() {
-        metrics
-            .system_metrics
-            .tcp_connection_states
-            .with_label_values(&["system", state])
-            .set(*count as f64);
+    #[test]
+    fn parses_code_cache() {
+        let segmented = "12345:\n\
+                         CodeHeap 'non-profiled nmethods': size=120032Kb used=2048Kb max_used=2100Kb free=117984Kb\n\
+                         bounds [0x00007f, 0x00007f, 0x00007f]\n\
+                         CodeHeap 'profiled nmethods': size=120028Kb used=8192Kb max_used=8200Kb free=111836Kb\n\
+                         bounds [0x00007f, 0x00007f, 0x00007f]\n\
+                         CodeHeap 'non-nmethods': size=5700Kb used=1024Kb max_used=1100Kb free=4676Kb\n\
+                         total_blobs=3000 nmethods=2000 adapters=900\n\
+                         compilation: enabled\n";
+        assert_eq!(
+            parse_code_cache(segmented),
+            Some((11264.0 * 1024.0, 245760.0 * 1024.0))
+        );
+        let jdk8 = "12345:\n\
+                    CodeCache: size=245760Kb used=10240Kb max_used=10300Kb free=235520Kb\n";
+        assert_eq!(
+            parse_code_cache(jdk8),
+            Some((10240.0 * 1024.0, 245760.0 * 1024.0))
+        );
+        assert_eq!(parse_code_cache("Unknown diagnostic command"), None);
     }
 
-    Ok(())
-}
+    #[test]
+    fn counts_from_zero_after_reset() {
+        assert_eq!(counter_delta(0, 4096), 4096);
+        assert_eq!(counter_delta(4096, 6144), 2048);
+        assert_eq!(counter_delta(6144, 1024), 1024);
+    }
 
-// Get Java processes on the host or within containers
-async fn get_java_processes(
-    java_home: Option<&str>,
-    full_path: bool,
-    container: String,
-) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    let mut processes = HashMap::new();
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn lists_host_processes_from_jps() {
+        let runner = FakeRunner(HashMap::from([
+            (
+                "jps -l",
+                "12345 com.example.Main\n999 sun.tools.jps.Jps\n42 -- process information unavailable\n",
+            ),
+        ]));
+        let dependencies = dependency_gauge();
+        let jps_ok = AtomicBool::new(false);
+        let processes = get_java_processes(
+            &runner,
+            None,
+            true,
+            &[],
+            "host".to_string(),
+            None,
+            &dependencies,
+            &jps_ok,
+        )
+        .await
+        .unwrap();
+        assert!(jps_ok.load(Ordering::Relaxed));
+        assert_eq!(dependencies.with_label_values(&["jps", "host"]).get(), 1.0);
+        assert_eq!(
+            processes,
+            HashMap::from([
+                ("12345".to_string(), "com.example.Main".to_string()),
+                ("42".to_string(), UNKNOWN_PROCESS_NAME.to_string()),
+            ])
+        );
+    }
 
-    if container == "host" {
-        if !is_jps_available().await {
-            error!("jps command not found. Please ensure that JDK is installed and JAVA_HOME is set correctly.");
-            return Ok(processes); // Return empty if jps is not available
-        }
-        let mut command = Command::new("jps");
-        command.arg("-l");
-        merge_java_home(java_home, &mut command)?;
-        let output = command.output().await?;
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn lists_containers_with_their_image() {
+        let runner = FakeRunner(HashMap::from([
+            (
+                "docker ps --format {{.ID}} {{.Names}} {{.Image}}",
+                "abc123 web registry.example.com/app:1.2\ndef456\n",
+            ),
+            ("crictl ps -q", "fed789\n"),
+            (
+                "docker inspect --format {{.Config.Image}} def456",
+                "app:1.0\n",
+            ),
+        ]));
+        assert_eq!(
+            list_containers(&runner, "docker").await.unwrap(),
+            vec![
+                (
+                    "abc123".to_string(),
+                    Some("web".to_string()),
+                    Some("registry.example.com/app:1.2".to_string())
+                ),
+                ("def456".to_string(), None, None),
+            ]
+        );
+        assert_eq!(
+            list_containers(&runner, "crictl").await.unwrap(),
+            vec![("fed789".to_string(), None, None)]
+        );
+        assert_eq!(
+            container_image(&runner, "docker", "def456").await,
+            Some("app:1.0".to_string())
+        );
+        assert_eq!(container_image(&runner, "docker", "missing").await, None);
+    }
 
-        if !output.status.success() {
-            return Err(format!(
-                "jps failed for host: {}",
-                String::from_utf8_lossy(&output.stderr)
-            )
-            .into());
-        }
+    #[test]
+    fn parses_pod_labels() {
+        assert_eq!(
+            parse_pod_labels("orders-7d9f8b-x2k4p|shop|3f1c2a9e-1b2c-4d5e-8f90-123456789abc\n"),
+            Some(Pod {
+                name: "orders-7d9f8b-x2k4p".to_string(),
+                namespace: "shop".to_string(),
+                uid: "3f1c2a9e-1b2c-4d5e-8f90-123456789abc".to_string(),
+            })
+        );
+        // A container not started by the kubelet has none of the labels
+        assert_eq!(parse_pod_labels("<no value>|<no value>|<no value>\n"), None);
+        assert_eq!(parse_pod_labels(""), None);
+    }
 
-        let stdout = String::from_utf8(output.stdout)?;
-        info!("Host jps output:\n{}", stdout);
+    #[test]
+    fn parses_crictl_details() {
+        assert_eq!(
+            parse_crictl_details(
+                "docker.io/library/app:2.0|app|orders-7d9f8b-x2k4p|shop|3f1c2a9e\n"
+            ),
+            Some((
+                Some("docker.io/library/app:2.0".to_string()),
+                Some("app".to_string()),
+                Some(Pod {
+                    name: "orders-7d9f8b-x2k4p".to_string(),
+                    namespace: "shop".to_string(),
+                    uid: "3f1c2a9e".to_string(),
+                })
+            ))
+        );
+        // Inspected, but not started by the kubelet
+        assert_eq!(
+            parse_crictl_details("app:2.0|app|<no value>|<no value>|<no value>\n"),
+            Some((Some("app:2.0".to_string()), Some("app".to_string()), None))
+        );
+        assert_eq!(parse_crictl_details(""), None);
+    }
 
-        for line in stdout.lines() {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 2 {
-                let process_name_original = parts[1];
-                let class_name = process_name_original
-                    .split('.')
-                    .last()
-                    .unwrap_or(process_name_original);
-
-                if EXCLUDED_PROCESSES
-                    .iter()
-                    .any(|&excluded| excluded.eq_ignore_ascii_case(class_name))
-                {
-                    continue;
-                }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keeps_containers_while_their_runtime_is_unavailable() {
+        let config = crate::config::Config {
+            detect_docker_processes: Some(true),
+            container_runtime: Some(ContainerRuntime::Docker),
+            ..Default::default()
+        };
+        let registry = Registry::new();
+        let mut metrics = Metrics::new(&registry, Arc::new(std::sync::RwLock::new(config)));
+        // docker ps fails, as during a daemon restart
+        metrics.runner = Arc::new(FakeRunner(HashMap::new()));
+        let metrics = Arc::new(metrics);
+        *metrics.containers.lock().await = vec![Container {
+            id: "abc123".to_string(),
+            runtime: "docker",
+            name: None,
+            image: None,
+            pod: None,
+        }];
+        metrics
+            .active_pids
+            .lock()
+            .await
+            .insert("abc123#7".to_string(), "com.example.Main".to_string());
 
-                let process_name = if full_path {
-                    process_name_original.to_string()
-                } else {
-                    class_name.to_string()
-                };
+        refresh_container_list(&metrics).await;
+        assert_eq!(metrics.containers.lock().await.len(), 1);
+        assert!(metrics.runtime_outages.lock().await.contains_key("docker"));
 
-                processes.insert(parts[0].to_string(), process_name);
+        // The processes last seen are kept without running jps in the container
+        let processes = get_container_java_processes(Arc::clone(&metrics), true)
+            .await
+            .unwrap();
+        assert_eq!(processes.len(), 1);
+        assert_eq!(processes[0].pid, "7");
+        assert_eq!(processes[0].process, "com.example.Main");
+    }
+
+    #[tokio::test]
+    async fn times_out_hung_jstat_without_retrying() {
+        use std::sync::atomic::AtomicUsize;
+
+        struct HangingRunner(AtomicUsize);
+        impl CommandRunner for HangingRunner {
+            fn run(&self, _command: Command) -> CommandFuture {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Box::pin(std::future::pending())
             }
         }
-    } else {
-        if !is_jps_available_inside_container(&container).await {
-            error!("jps command not found inside container {}. Please ensure that JDK is installed in the container.", container);
-            return Ok(processes); // Return empty if jps is not available inside the container
-        }
-        let mut cmd;
-        if is_docker_available().await {
-            cmd = Command::new("docker");
-            cmd.args(&["exec", &container, "jps", "-l"]);
-            info!("Executing jps inside Docker container: {}", container);
-        } else if is_crictl_available().await {
-            cmd = Command::new("crictl");
-            cmd.args(&["exec", &container, "jps", "-l"]);
-            info!("Executing jps inside crictl container: {}", container);
-        } else {
-            return Err(
-                "Neither Docker nor crictl is available to execute commands in containers".into(),
-            );
-        }
+        let registry = Registry::new();
+        let metrics = Metrics::new(
+            &registry,
+            Arc::new(std::sync::RwLock::new(Default::default())),
+        );
+        let runner = HangingRunner(AtomicUsize::new(0));
+        let started = Instant::now();
+        let err = fetch_jstat_with_retries(
+            &metrics,
+            &runner,
+            &"host".to_string(),
+            None,
+            &"42".to_string(),
+            &"app".to_string(),
+            "-gc",
+            false,
+            None,
+            None,
+            2,
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("timed out after 50ms"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(runner.0.load(Ordering::Relaxed), 1);
+    }
 
-        if let Some(jh) = java_home {
-            cmd.env("JAVA_HOME", jh);
-            cmd.env(
-                "PATH",
-                format!("{}/bin:{}", jh, std::env::var("PATH").unwrap_or_default()),
-            );
+    #[tokio::test]
+    async fn times_out_container_commands() {
+        struct HangingRunner;
+        impl CommandRunner for HangingRunner {
+            fn run(&self, _command: Command) -> CommandFuture {
+                Box::pin(std::future::pending())
+            }
         }
+        let runner = TimeoutCommandRunner::new(Arc::new(HangingRunner), Duration::from_millis(10));
+        let err = runner.run(Command::new("docker")).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
 
-        let output = cmd.output().await?;
-
-        if !output.status.success() {
-            return Err(format!(
-                "jps failed for container {}: {}",
-                container,
-                String::from_utf8_lossy(&output.stderr)
-            )
-            .into());
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn detects_permission_denied_runtimes() {
+        struct DeniedRunner;
+        impl CommandRunner for DeniedRunner {
+            fn run(&self, _command: Command) -> CommandFuture {
+                use std::os::unix::process::ExitStatusExt;
+                let output = std::process::Output {
+                    status: std::process::ExitStatus::from_raw(1 << 8),
+                    stdout: Vec::new(),
+                    stderr: b"permission denied while trying to connect to the Docker daemon socket at unix:///var/run/docker.sock".to_vec(),
+                };
+                Box::pin(async move { Ok(output) })
+            }
         }
+        assert_eq!(
+            runtime_availability(&DeniedRunner, "docker").await,
+            RuntimeAvailability::PermissionDenied
+        );
 
-        let stdout = String::from_utf8(output.stdout)?;
-        info!("Container {} jps output:\n{}", container, stdout);
+        let runner = FakeRunner(HashMap::from([
+            ("docker ps", ""),
+            ("sudo -n -- docker ps", ""),
+        ]));
+        assert_eq!(
+            runtime_availability(&runner, "podman").await,
+            RuntimeAvailability::Unavailable
+        );
+        let sudo = SudoCommandRunner::new(Arc::new(runner));
+        assert_eq!(
+            runtime_availability(&sudo, "docker").await,
+            RuntimeAvailability::Available
+        );
+    }
 
-        for line in stdout.lines() {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 2 {
-                let process_name_original = parts[1];
-                let class_name = process_name_original
-                    .split('.')
-                    .last()
-                    .unwrap_or(process_name_original);
-
-                if EXCLUDED_PROCESSES
-                    .iter()
-                    .any(|&excluded| excluded.eq_ignore_ascii_case(class_name))
-                {
-                    continue;
-                }
+    #[test]
+    fn hints_at_the_denied_runtime() {
+        assert!(permission_denied_hint("docker").contains("docker_sudo"));
+        assert!(!permission_denied_hint("crictl").contains("docker"));
+        assert!(permission_denied_hint("podman").contains("podman containers"));
+    }
 
-                let process_name = if full_path {
-                    process_name_original.to_string()
-                } else {
-                    class_name.to_string()
-                };
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn lists_remote_processes_over_ssh() {
+        use std::sync::atomic::AtomicUsize;
 
-                processes.insert(parts[0].to_string(), process_name);
+        // Counts the jps runs of the remote host
+        struct CountingRunner(FakeRunner, AtomicUsize);
+        impl CommandRunner for CountingRunner {
+            fn run(&self, command: Command) -> CommandFuture {
+                let mut args = command.as_std().get_args();
+                if args.any(|arg| arg.to_string_lossy().ends_with("jps -l")) {
+                    self.1.fetch_add(1, Ordering::Relaxed);
+                }
+                self.0.run(command)
             }
         }
+        let local = Arc::new(CountingRunner(
+            FakeRunner(HashMap::from([(
+                "ssh -o BatchMode=yes -o ConnectTimeout=10 -i /etc/jvm-exporter/id_ed25519 -p 2222 -- app@db1 /opt/jdk/bin/jps -l",
+                "4242 com.example.Db\n",
+            )])),
+            AtomicUsize::new(0),
+        ));
+        let remote = RemoteHost {
+            host: "db1".to_string(),
+            user: Some("app".to_string()),
+            key: Some("/etc/jvm-exporter/id_ed25519".to_string()),
+            port: Some(2222),
+            java_home: Some("/opt/jdk".to_string()),
+        };
+        let runner = SshCommandRunner::new(remote, local.clone());
+        let dependencies = dependency_gauge();
+        let processes = get_java_processes(
+            &runner,
+            None,
+            true,
+            &[],
+            "db1".to_string(),
+            Some(SSH_RUNTIME),
+            &dependencies,
+            &AtomicBool::new(false),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            processes,
+            HashMap::from([("4242".to_string(), "com.example.Db".to_string())])
+        );
+        assert_eq!(local.1.load(Ordering::Relaxed), 1);
+
+        // An unreachable host is an error, not a host without JVMs
+        let unreachable = SshCommandRunner::new(
+            RemoteHost {
+                host: "db2".to_string(),
+                ..Default::default()
+            },
+            local,
+        );
+        let jps_ok = AtomicBool::new(false);
+        let result = get_java_processes(
+            &unreachable,
+            None,
+            true,
+            &[],
+            "db2".to_string(),
+            Some(SSH_RUNTIME),
+            &dependencies,
+            &jps_ok,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(!jps_ok.load(Ordering::Relaxed));
+        assert_eq!(dependencies.with_label_values(&["jps", "db2"]).get(), 0.0);
     }
 
-    Ok(processes)
-}
+    #[test]
+    fn rejects_ssh_destinations_read_as_options() {
+        let remote = |host: &str, user: Option<&str>| RemoteHost {
+            host: host.to_string(),
+            user: user.map(str::to_string),
+            ..Default::default()
+        };
+        assert_eq!(ssh_destination_error(&remote("db1", Some("app"))), None);
+        assert!(ssh_destination_error(&remote("-oProxyCommand=touch /tmp/x", None)).is_some());
+        assert!(ssh_destination_error(&remote("db1", Some("-oProxyCommand=id"))).is_some());
+        assert!(ssh_destination_error(&remote("db1 -v", None)).is_some());
+        assert!(ssh_destination_error(&remote("db1\n", None)).is_some());
+        assert!(ssh_destination_error(&remote("", None)).is_some());
+    }
 
-// Detect if Docker is available
-async fn is_docker_available() -> bool {
-    let output = Command::new("docker")
-        .arg("ps")
-        .output()
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn parses_jstat_output() {
+        let runner = FakeRunner(HashMap::from([(
+            "jstat -gc 12345 1000 1",
+            "    S0C    S1C    S0U    S1U      EC       EU  YGC     YGCT\n\
+             0.0 1024.0    0.0  512.0  2048.0   1024.0   12    0.345\n",
+        )]));
+        let (values, gc_cause) = fetch_jstat(
+            &runner,
+            &"host".to_string(),
+            None,
+            &"12345".to_string(),
+            &"com.example.Main".to_string(),
+            "-gc",
+            false,
+            None,
+            None,
+        )
         .await
-        .map(|o| o.status.success())
-        .unwrap_or(false);
-    output
-}
+        .unwrap();
+        assert_eq!(gc_cause, None);
+        assert_eq!(values.len(), 8);
+        assert_eq!(values[1], ("S1C".to_string(), 1024.0));
+        assert_eq!(values[7], ("YGCT".to_string(), 0.345));
 
-// Detect if crictl is available
-async fn is_crictl_available() -> bool {
-    let output = Command::new("crictl")
-        .arg("ps")
-        .output()
+        let err = fetch_jstat(
+            &runner,
+            &"host".to_string(),
+            None,
+            &"42".to_string(),
+            &"com.example.Other".to_string(),
+            "-gc",
+            false,
+            None,
+            None,
+        )
         .await
-        .map(|o| o.status.success())
-        .unwrap_or(false);
-    output
-}
-
-// Get Java processes from all containers
-async fn get_container_java_processes(
-    metrics: Arc<Metrics>,
-    java_home: Option<&str>,
-    full_path: bool,
-) -> Result<Vec<ProcessInfo>, Box<dyn std::error::Error>> {
-    let mut container_processes = Vec::new();
-    if !metrics
-        .config
-        .read()
-        .unwrap()
-        .detect_docker_processes
-        .unwrap_or_default()
-    {
-        return Ok(container_processes);
+        .unwrap_err();
+        assert!(err.to_string().contains("jstat -gc failed for PID 42"));
     }
-    if is_docker_available().await {
-        let containers = list_docker_containers().await?;
-        for container in containers {
-            match get_java_processes(java_home, full_path, container.clone()).await {
-                Ok(procs) => {
-                    for (pid, pname) in procs {
-                        container_processes.push(ProcessInfo {
-                            container: container.clone(),
-                            pid,
-                            process: pname,
-                        });
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        "Failed to get Java processes for Docker container {}: {}",
-                        container, e
-                    );
-                }
-            }
+
+    #[test]
+    fn splits_gc_causes() {
+        let output = "  S0     S1     E      O      M     CCS    YGC     YGCT    FGC    FGCT     GCT    LGCC                 GCC                 \n\
+                      0.00 100.00  54.55  11.94  97.12  91.37     10    0.082     0    0.000    0.082 G1 Evacuation Pause  No GC               \n";
+        let (columns, cause) = split_gc_cause(output).unwrap();
+        assert_eq!(cause, "G1 Evacuation Pause");
+        let values = parse_jstat_output(&columns, "test").unwrap();
+        assert_eq!(values.len(), 11);
+        assert_eq!(values[10], ("GCT".to_string(), 0.082));
+
+        // Causes as wide as or wider than their 20 character columns
+        let header = format!("Timestamp     S0     GCT    {:<20} {:<20}\n", "LGCC", "GCC");
+        for (last, current) in [
+            ("Metadata GC Threshold", "G1 Humongous Allocation"),
+            ("G1 Humongous Allocation", "Allocation Failure"),
+            ("Allocation Failure", "GCLocker Initiated GC"),
+            ("System.gc()", "No GC"),
+        ] {
+            let output = format!(
+                "{}     12.5   0.00   0.082 {:<20} {:<20}\n",
+                header, last, current
+            );
+            let (columns, cause) = split_gc_cause(&output).unwrap();
+            assert_eq!(cause, last);
+            assert_eq!(parse_jstat_output(&columns, "test").unwrap().len(), 3);
         }
+        assert!(split_gc_cause("S0 S1\n0.00 1.00\n").is_none());
     }
 
-    if is_crictl_available().await {
-        let containers = list_crictl_containers().await?;
-        for container in containers {
-            match get_java_processes(java_home, full_path, container.clone()).await {
-                Ok(procs) => {
-                    for (pid, pname) in procs {
-                        container_processes.push(ProcessInfo {
-                            container: container.clone(),
-                            pid,
-                            process: pname,
-                        });
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        "Failed to get Java processes for crictl container {}: {}",
-                        container, e
-                    );
-                }
-            }
-        }
+    #[test]
+    fn parses_aligned_jstat_output() {
+        let output = "Loaded  Bytes  Unloaded  Bytes     Time\n  1000  2048.0        0     -    0.50\n";
+        assert_eq!(
+            parse_jstat_output(output, "test"),
+            Some(vec![
+                ("Loaded".to_string(), 1000.0),
+                ("Bytes".to_string(), 2048.0),
+                ("Unloaded".to_string(), 0.0),
+                ("Bytes".to_string(), 0.0),
+                ("Time".to_string(), 0.5),
+            ])
+        );
+        assert_eq!(parse_jstat_output("Loaded  Bytes\n", "test"), None);
     }
 
-    Ok(container_processes)
-}
+    #[test]
+    fn aligns_misaligned_jstat_output_from_the_right() {
+        // Uptime printed by -t without its header
+        let output = "   S0C    S1C     YGC\n  123.4    0.0 1024.0   12\n";
+        assert_eq!(
+            parse_jstat_output(output, "test"),
+            Some(vec![
+                ("S0C".to_string(), 0.0),
+                ("S1C".to_string(), 1024.0),
+                ("YGC".to_string(), 12.0),
+            ])
+        );
+        // A missing leading value
+        assert_eq!(
+            parse_jstat_output("   S0C    S1C    S0U\n  1024.0  512.0\n", "test"),
+            Some(vec![("S1C".to_string(), 1024.0), ("S0U".to_string(), 512.0)])
+        );
+    }
 
-async fn is_jps_available() -> bool {
-    Command::new("jps")
-        .arg("-l")
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .status()
-        .await
-        .map(|status| status.success())
-        .unwrap_or(false)
-}
+    #[test]
+    fn skips_the_failed_method_of_jstat_compiler() {
+        let expected = |failed_type: f64| {
+            Some(vec![
+                ("Compiled".to_string(), 20512.0),
+                ("Failed".to_string(), 3.0),
+                ("Invalid".to_string(), 0.0),
+                ("Time".to_string(), 41.25),
+                ("FailedType".to_string(), failed_type),
+            ])
+        };
+        let failed = "Compiled Failed Invalid   Time   FailedType FailedMethod
+   20512      3       0    41.25          1 java/lang/String indexOf
+";
+        assert_eq!(parse_jstat_output(failed, "test"), expected(1.0));
+        let none_failed = "Compiled Failed Invalid   Time   FailedType FailedMethod
+   20512      3       0    41.25          0
+";
+        assert_eq!(parse_jstat_output(none_failed, "test"), expected(0.0));
+    }
 
-async fn is_jps_available_inside_container(container: &str) -> bool {
-    if is_docker_available().await {
-        Command::new("docker")
-            .args(&["exec", container, "jps", "-l"])
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .status()
-            .await
-            .map(|status| status.success())
-            .unwrap_or(false)
-    } else if is_crictl_available().await {
-        Command::new("crictl")
-            .args(&["exec", container, "jps", "-l"])
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .status()
-            .await
-            .map(|status| status.success())
-            .unwrap_or(false)
-    } else {
-        false
+    #[test]
+    fn parses_the_timestamp_column_of_jstat_t() {
+        let output = "Timestamp        S0C    S1C     YGC\n         5230.1    0.0 1024.0    12\n";
+        assert_eq!(
+            parse_jstat_output(output, "test"),
+            Some(vec![
+                ("Timestamp".to_string(), 5230.1),
+                ("S0C".to_string(), 0.0),
+                ("S1C".to_string(), 1024.0),
+                ("YGC".to_string(), 12.0),
+            ])
+        );
     }
-}
 
-// List Docker containers
-async fn list_docker_containers() -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let output = Command::new("docker")
-        .args(&["ps", "--format", "{{.ID}}"])
-        .output()
-        .await?;
+    #[tokio::test]
+    async fn keeps_tasks_finished_before_the_deadline() {
+        let tasks = vec![
+            tokio::spawn(async { 1 }),
+            tokio::spawn(async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                2
+            }),
+        ];
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(100);
+        let (results, timed_out) = join_until(tasks, Some(deadline)).await;
+        assert!(timed_out);
+        assert_eq!(
+            results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            vec![1]
+        );
 
-    if !output.status.success() {
-        return Err(format!(
-            "Failed to list Docker containers: {}",
-            String::from_utf8_lossy(&output.stderr)
-        )
-        .into());
+        let (results, timed_out) = join_until(vec![tokio::spawn(async { 3 })], None).await;
+        assert!(!timed_out);
+        assert_eq!(results.len(), 1);
     }
 
-    let stdout = String::from_utf8(output.stdout)?;
-    let containers: Vec<String> = stdout.lines().map(|s| s.to_string()).collect();
-    Ok(containers)
-}
-
-// List crictl containers
-async fn list_crictl_containers() -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let output = Command::new("crictl").args(&["ps", "-q"]).output().await?;
+    #[tokio::test]
+    async fn keeps_lookups_finished_before_the_deadline() {
+        let lookups = [1, 2].map(|n| async move {
+            if n == 2 {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            n
+        });
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(100);
+        assert_eq!(join_all_until(lookups, Some(deadline)).await, vec![1]);
+        assert_eq!(join_all_until([async { 3 }], None).await, vec![3]);
+    }
 
-    if !output.status.success() {
-        return Err(format!(
-            "Failed to list crictl containers: {}",
-            String::from_utf8_lossy(&output.stderr)
-        )
-        .into());
+    #[tokio::test]
+    async fn aborts_tasks_of_a_dropped_collection() {
+        let pending = tokio::spawn(std::future::pending::<()>());
+        drop(AbortOnDrop::new(std::slice::from_ref(&pending)));
+        assert!(pending.await.unwrap_err().is_cancelled());
     }
 
-    let stdout = String::from_utf8(output.stdout)?;
-    let containers: Vec<String> = stdout.lines().map(|s| s.to_string()).collect();
-    Ok(containers)
-}
+    #[test]
+    fn computes_gc_rates_from_the_previous_sample() {
+        let mut gc_counts = HashMap::new();
+        let sample =
+            |young: f64, full: f64| vec![("YGC".to_string(), young), ("FGC".to_string(), full)];
+        assert_eq!(
+            gc_rates_since_last_sample(&mut gc_counts, "host#1".to_string(), &sample(10.0, 1.0)),
+            None
+        );
 
-fn merge_java_home(
-    java_home: Option<&str>,
-    command: &mut Command,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if let Some(jh) = java_home {
-        command.env("JAVA_HOME", jh);
-        command.env(
-            "PATH",
-            format!("{}/bin:{}", jh, std::env::var("PATH").unwrap_or_default()),
+        let last_time = Instant::now() - Duration::from_secs(10);
+        gc_counts.insert("host#1".to_string(), (10.0, 1.0, last_time));
+        let (young_per_sec, full_per_sec) =
+            gc_rates_since_last_sample(&mut gc_counts, "host#1".to_string(), &sample(30.0, 2.0))
+                .unwrap();
+        assert!((young_per_sec - 2.0).abs() < 0.01);
+        assert!((full_per_sec - 0.1).abs() < 0.001);
+
+        // Counts going down start a new baseline
+        assert_eq!(
+            gc_rates_since_last_sample(&mut gc_counts, "host#1".to_string(), &sample(1.0, 0.0)),
+            None
         );
     }
-    Ok(())
+
+    #[test]
+    fn skips_excluded_processes() {
+        assert_eq!(jps_process("999 sun.tools.jps.Jps"), None);
+        assert_eq!(jps_process("999 jdk.jcmd/sun.tools.jps.Jps"), None);
+    }
 }
@@ -0,0 +1,324 @@
+use crate::metrics::metrics::Metrics;
+use log::{info, warn};
+use prometheus::core::Collector;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Parse the GC pauses appended to the configured GC log files since the previous call.
+// Files found on the first call are read from their end so that old pauses are not replayed,
+// files showing up later, e.g. after rotation, are read from their start.
+// Returns whether any GC log path is configured.
+pub(crate) async fn update_gc_log_metrics(metrics: &Metrics, from_end: bool) -> bool {
+    let gc_log_paths = match metrics.config.read().unwrap().gc_log_paths.clone() {
+        Some(gc_log_paths) if !gc_log_paths.is_empty() => gc_log_paths,
+        _ => return false,
+    };
+
+    let mut offsets = metrics.gc_log_offsets.lock().await;
+    let mut seen: HashMap<u64, u64> = HashMap::new();
+    for (process_name, patterns) in &gc_log_paths {
+        for path in expand_globs(patterns) {
+            let lines = match read_new_lines(&path, &offsets, &mut seen, from_end) {
+                Ok(lines) => lines,
+                Err(e) => {
+                    warn!("Failed to read GC log {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            for line in lines {
+                if let Some((pause, heap_after_bytes, seconds)) = parse_gc_pause(&line) {
+                    metrics
+                        .process_metrics
+                        .gc_pause_seconds
+                        .with_label_values(&[process_name, pause_generation(&pause), &pause])
+                        .observe(seconds);
+                    if let Some(heap_after_bytes) = heap_after_bytes {
+                        metrics
+                            .process_metrics
+                            .heap_after_gc
+                            .with_label_values(&[process_name])
+                            .set(heap_after_bytes);
+                    }
+                }
+            }
+        }
+    }
+    // Forget files that were deleted
+    *offsets = seen;
+    true
+}
+
+// Remove the GC log series of a process name once none of its PIDs is left, like the series of
+// the PIDs themselves. A restarted JVM keeps adding to the same series.
+pub(crate) fn remove_gc_log_metrics(metrics: &Metrics, process_name: &str) {
+    let _ = metrics
+        .process_metrics
+        .heap_after_gc
+        .remove_label_values(&[process_name]);
+    let gc_pause_seconds = &metrics.process_metrics.gc_pause_seconds;
+    for family in gc_pause_seconds.collect() {
+        for metric in family.get_metric() {
+            let labels: HashMap<&str, &str> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect();
+            if labels.get("process_name") == Some(&process_name) {
+                let _ = gc_pause_seconds.remove(&labels);
+            }
+        }
+    }
+}
+
+fn expand_globs(patterns: &[String]) -> Vec<PathBuf> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match glob::glob(pattern) {
+            Ok(paths) => Some(paths),
+            Err(e) => {
+                warn!("Invalid GC log path {}: {}", pattern, e);
+                None
+            }
+        })
+        .flatten()
+        .flatten()
+        .collect()
+}
+
+// Read the complete lines appended to a file since the offset recorded for it. Offsets are
+// kept by file id rather than path, so a log renamed by rotation is not read again.
+fn read_new_lines(
+    path: &Path,
+    offsets: &HashMap<u64, u64>,
+    seen: &mut HashMap<u64, u64>,
+    from_end: bool,
+) -> std::io::Result<Vec<String>> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let id = file_id(path, &metadata);
+    if seen.contains_key(&id) {
+        // Matched by more than one pattern
+        return Ok(Vec::new());
+    }
+
+    let len = metadata.len();
+    let offset = match offsets.get(&id) {
+        Some(&offset) if offset <= len => offset,
+        Some(_) => {
+            info!("GC log {} was truncated, reading it from the start", path.display());
+            0
+        }
+        None if from_end => len,
+        None => 0,
+    };
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    file.take(len - offset).read_to_end(&mut buf)?;
+    // Leave an incomplete last line for the next read
+    let complete = buf.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    seen.insert(id, offset + complete as u64);
+
+    Ok(String::from_utf8_lossy(&buf[..complete])
+        .lines()
+        .map(|line| line.to_string())
+        .collect())
+}
+
+#[cfg(unix)]
+fn file_id(_path: &Path, metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino() ^ metadata.dev().rotate_left(32)
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path, _metadata: &Metadata) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Parse a Unified GC Logging pause line, e.g.
+// [2.345s][info][gc] GC(12) Pause Young (Normal) (G1 Evacuation Pause) 120M->30M(256M) 5.123ms
+// into the pause type, the heap used after it in bytes and its duration in seconds. The
+// "(Mixed)" of G1 mixed collections is kept in the type. ZGC and Shenandoah log their pauses
+// without the heap, e.g. "GC(3) Pause Init Mark 0.345ms".
+fn parse_gc_pause(line: &str) -> Option<(String, Option<f64>, f64)> {
+    static PAUSE: OnceLock<Regex> = OnceLock::new();
+    let re = PAUSE.get_or_init(|| {
+        Regex::new(
+            r"GC\(\d+\) (Pause(?: [A-Z][a-z]+)+(?: \(Mixed\))?)(?: \(.*?\))?(?: \d+[BKMG]->(\d+)([BKMG])\(\d+[BKMG]\))? (\d+(?:\.\d+)?)ms",
+        )
+        .expect("Invalid GC pause regex")
+    });
+
+    let captures = re.captures(line)?;
+    let heap_after_bytes = match (captures.get(2), captures.get(3)) {
+        (Some(value), Some(unit)) => {
+            let unit = match unit.as_str() {
+                "K" => 1024.0,
+                "M" => 1024.0 * 1024.0,
+                "G" => 1024.0 * 1024.0 * 1024.0,
+                _ => 1.0,
+            };
+            Some(value.as_str().parse::<f64>().ok()? * unit)
+        }
+        _ => None,
+    };
+    let seconds = captures[4].parse::<f64>().ok()? / 1000.0;
+    Some((captures[1].to_string(), heap_after_bytes, seconds))
+}
+
+// Why gc_pause_buckets cannot be used as histogram buckets, if it cannot
+pub(crate) fn gc_pause_buckets_error(buckets: &[f64]) -> Option<String> {
+    if buckets.is_empty() {
+        return Some("no bucket given".to_string());
+    }
+    if let Some(bucket) = buckets.iter().find(|b| !b.is_finite() || **b <= 0.0) {
+        return Some(format!("{} is not a positive number of seconds", bucket));
+    }
+    buckets
+        .windows(2)
+        .find(|pair| pair[0] >= pair[1])
+        .map(|pair| {
+            format!(
+                "{} is not above {}, buckets must be ascending",
+                pair[1], pair[0]
+            )
+        })
+}
+
+// Generation a pause type collects: young, mixed and full collections, the pauses of collectors
+// working on the whole heap at once, ZGC and Shenandoah, and the pauses of the concurrent old
+// generation cycle, e.g. "Pause Remark" of G1
+fn pause_generation(pause: &str) -> &'static str {
+    match pause {
+        "Pause Young" => "young",
+        "Pause Young (Mixed)" => "mixed",
+        "Pause Full" => "full",
+        "Pause Mark Start"
+        | "Pause Mark End"
+        | "Pause Relocate Start"
+        | "Pause Init Mark"
+        | "Pause Final Mark"
+        | "Pause Init Update Refs"
+        | "Pause Final Update Refs" => "all",
+        _ => "old",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_unified_logging_pauses() {
+        assert_eq!(
+            parse_gc_pause("[2024-03-01T10:00:00.123+0000][info][gc] GC(12) Pause Young (Normal) (G1 Evacuation Pause) 120M->30M(256M) 5.000ms"),
+            Some(("Pause Young".to_string(), Some(30.0 * 1024.0 * 1024.0), 0.005))
+        );
+        assert_eq!(
+            parse_gc_pause(
+                "[0.123s][info][gc] GC(0) Pause Full (System.gc()) 10M->2M(64M) 15.500ms"
+            ),
+            Some((
+                "Pause Full".to_string(),
+                Some(2.0 * 1024.0 * 1024.0),
+                0.0155
+            ))
+        );
+        assert_eq!(
+            parse_gc_pause("[0.200s][info][gc] GC(1) Pause Remark 20M->20M(64M) 1ms"),
+            Some((
+                "Pause Remark".to_string(),
+                Some(20.0 * 1024.0 * 1024.0),
+                0.001
+            ))
+        );
+        assert_eq!(
+            parse_gc_pause("[3.100s][info][gc] GC(7) Pause Young (Mixed) (G1 Evacuation Pause) 90M->40M(256M) 4ms"),
+            Some(("Pause Young (Mixed)".to_string(), Some(40.0 * 1024.0 * 1024.0), 0.004))
+        );
+    }
+
+    #[test]
+    fn parses_pauses_logged_without_the_heap() {
+        assert_eq!(
+            parse_gc_pause("[1.000s][info][gc] GC(3) Pause Init Mark (unload classes) 0.250ms"),
+            Some(("Pause Init Mark".to_string(), None, 0.00025))
+        );
+        assert_eq!(
+            parse_gc_pause("[1.000s][info][gc,phases] GC(4) Pause Mark Start 0.500ms"),
+            Some(("Pause Mark Start".to_string(), None, 0.0005))
+        );
+    }
+
+    #[test]
+    fn validates_gc_pause_buckets() {
+        assert_eq!(gc_pause_buckets_error(&[0.001, 0.01, 0.1, 1.0]), None);
+        assert!(gc_pause_buckets_error(&[]).is_some());
+        assert!(gc_pause_buckets_error(&[0.1, 0.01]).is_some());
+        assert!(gc_pause_buckets_error(&[0.1, 0.1]).is_some());
+        assert!(gc_pause_buckets_error(&[-1.0, 0.1]).is_some());
+        assert!(gc_pause_buckets_error(&[0.1, f64::INFINITY]).is_some());
+    }
+
+    #[test]
+    fn classifies_pause_generations() {
+        assert_eq!(pause_generation("Pause Young"), "young");
+        assert_eq!(pause_generation("Pause Young (Mixed)"), "mixed");
+        assert_eq!(pause_generation("Pause Full"), "full");
+        assert_eq!(pause_generation("Pause Remark"), "old");
+        assert_eq!(pause_generation("Pause Mark Start"), "all");
+        assert_eq!(pause_generation("Pause Final Update Refs"), "all");
+    }
+
+    #[test]
+    fn removes_the_series_of_a_process_name() {
+        let registry = prometheus::Registry::new();
+        let metrics = Metrics::new(
+            &registry,
+            std::sync::Arc::new(std::sync::RwLock::new(Default::default())),
+        );
+        let pauses = &metrics.process_metrics.gc_pause_seconds;
+        pauses
+            .with_label_values(&["app", "young", "Pause Young"])
+            .observe(0.005);
+        pauses
+            .with_label_values(&["app", "full", "Pause Full"])
+            .observe(0.5);
+        pauses
+            .with_label_values(&["other", "young", "Pause Young"])
+            .observe(0.005);
+        metrics
+            .process_metrics
+            .heap_after_gc
+            .with_label_values(&["app"])
+            .set(1024.0);
+
+        remove_gc_log_metrics(&metrics, "app");
+        let series = &pauses.collect()[0];
+        assert_eq!(series.get_metric().len(), 1);
+        assert!(series.get_metric()[0]
+            .get_label()
+            .iter()
+            .any(|label| label.get_value() == "other"));
+        assert!(metrics.process_metrics.heap_after_gc.collect()[0]
+            .get_metric()
+            .is_empty());
+    }
+
+    #[test]
+    fn skips_other_lines() {
+        assert_eq!(parse_gc_pause("[0.010s][info][gc] Using G1"), None);
+        assert_eq!(
+            parse_gc_pause("[0.300s][info][gc] GC(2) Concurrent Mark Cycle 12.345ms"),
+            None
+        );
+    }
+}
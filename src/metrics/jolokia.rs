@@ -0,0 +1,332 @@
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Bounds each request to a Jolokia agent, ureq blocks a thread of the blocking pool meanwhile
+const JOLOKIA_TIMEOUT: Duration = Duration::from_secs(10);
+// jstat -gc columns reported from the MBeans, in the order jstat prints them
+const GC_COLUMNS: &[&str] = &[
+    "S0C", "S1C", "S0U", "S1U", "EC", "EU", "OC", "OU", "MC", "MU", "CCSC", "CCSU", "YGC", "YGCT",
+    "FGC", "FGCT", "CGC", "CGCT", "GCT",
+];
+const HEAP_COLUMNS: &[&str] = &["S0C", "S1C", "S0U", "S1U", "EC", "EU", "OC", "OU"];
+// Threading MBean attributes and the state label of jvm_threads
+pub(crate) const THREAD_ATTRIBUTES: &[(&str, &str)] = &[
+    ("ThreadCount", "live"),
+    ("DaemonThreadCount", "daemon"),
+    ("PeakThreadCount", "peak"),
+];
+
+// Values read from the Jolokia agent of a JVM
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct JolokiaSample {
+    pub(crate) gc_values: Vec<(String, f64)>, // jstat -gc columns, in KB and seconds like jstat
+    pub(crate) threads: Vec<(&'static str, f64)>, // (state, threads)
+    pub(crate) mbeans: Vec<(String, String, f64)>, // (mbean, attribute, value) of jolokia_mbeans
+}
+
+// The Jolokia agent URL of a process from jolokia_urls, by container#pid first, then by PID for
+// host processes, since a container or remote host can reuse a host PID, then by process name
+pub(crate) fn jolokia_url<'a>(
+    urls: &'a HashMap<String, String>,
+    container: &str,
+    pid: &str,
+    process: &str,
+) -> Option<&'a str> {
+    urls.get(&format!("{}#{}", container, pid))
+        .or_else(|| urls.get(pid).filter(|_| container == "host"))
+        .or_else(|| urls.get(process))
+        .map(|url| url.as_str())
+}
+
+// Read the memory pool, garbage collector and Threading MBeans and the jolokia_mbeans of a JVM
+// in a single bulk request. ureq blocks, so the request runs on the blocking pool.
+pub(crate) async fn fetch_jolokia(
+    url: String,
+    mbeans: Vec<String>,
+) -> Result<JolokiaSample, Box<dyn std::error::Error + Send + Sync>> {
+    let body = jolokia_request(&mbeans).to_string();
+    let response = tokio::task::spawn_blocking(
+        move || -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            let response = ureq::post(&url)
+                .timeout(JOLOKIA_TIMEOUT)
+                .set("Content-Type", "application/json")
+                .send_string(&body)?;
+            Ok(response.into_string()?)
+        },
+    )
+    .await??;
+    parse_jolokia_response(&response, &mbeans)
+}
+
+fn jolokia_request(mbeans: &[String]) -> Value {
+    let thread_attributes: Vec<&str> = THREAD_ATTRIBUTES.iter().map(|(a, _)| *a).collect();
+    let mut requests = vec![
+        json!({"type": "read", "mbean": "java.lang:type=MemoryPool,name=*", "attribute": "Usage"}),
+        json!({
+            "type": "read",
+            "mbean": "java.lang:type=GarbageCollector,name=*",
+            "attribute": ["CollectionCount", "CollectionTime"],
+        }),
+        json!({"type": "read", "mbean": "java.lang:type=Threading", "attribute": thread_attributes}),
+    ];
+    requests.extend(
+        mbeans
+            .iter()
+            .map(|mbean| json!({"type": "read", "mbean": mbean})),
+    );
+    Value::Array(requests)
+}
+
+// Parse the responses of the bulk request, in the order of jolokia_request. Only the memory pools
+// are required, the other MBeans are left out when they cannot be read.
+fn parse_jolokia_response(
+    body: &str,
+    mbeans: &[String],
+) -> Result<JolokiaSample, Box<dyn std::error::Error + Send + Sync>> {
+    let responses: Vec<Value> = serde_json::from_str(body)?;
+    let value = |index: usize| {
+        let response = responses.get(index)?;
+        (response["status"] == 200).then_some(&response["value"])
+    };
+
+    let mut columns: HashMap<&'static str, f64> = HashMap::new();
+    let pools = value(0)
+        .and_then(Value::as_object)
+        .ok_or("Jolokia did not return the memory pools")?;
+    for (mbean, attributes) in pools {
+        let Some((capacity, used)) = mbean_key(mbean, "name").and_then(pool_columns) else {
+            continue;
+        };
+        let usage = &attributes["Usage"];
+        let (Some(committed), Some(used_bytes)) =
+            (usage["committed"].as_f64(), usage["used"].as_f64())
+        else {
+            continue;
+        };
+        // jstat reports capacities and usage in KB
+        *columns.entry(capacity).or_default() += committed / 1024.0;
+        *columns.entry(used).or_default() += used_bytes / 1024.0;
+    }
+    if HEAP_COLUMNS.iter().any(|c| columns.contains_key(c)) {
+        for column in HEAP_COLUMNS {
+            columns.entry(*column).or_default();
+        }
+    }
+
+    if let Some(collectors) = value(1).and_then(Value::as_object) {
+        for (mbean, attributes) in collectors {
+            let (Some(count), Some(time_ms)) = (
+                attributes["CollectionCount"].as_f64(),
+                attributes["CollectionTime"].as_f64(),
+            ) else {
+                continue;
+            };
+            let (count_column, time_column) = gc_columns(mbean_key(mbean, "name").unwrap_or(""));
+            *columns.entry(count_column).or_default() += count;
+            *columns.entry(time_column).or_default() += time_ms / 1000.0;
+            *columns.entry("GCT").or_default() += time_ms / 1000.0;
+        }
+    }
+
+    let gc_values = GC_COLUMNS
+        .iter()
+        .filter_map(|column| Some((column.to_string(), *columns.get(column)?)))
+        .collect();
+    let threads = value(2)
+        .map(|threads| {
+            THREAD_ATTRIBUTES
+                .iter()
+                .filter_map(|(attribute, state)| Some((*state, threads[*attribute].as_f64()?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut mbean_values = Vec::new();
+    for (index, mbean) in mbeans.iter().enumerate() {
+        let Some(Value::Object(attributes)) = value(3 + index) else {
+            continue;
+        };
+        // A pattern matches several MBeans and returns their attributes by MBean name
+        if mbean.contains('*') || mbean.contains('?') {
+            for (name, attributes) in attributes {
+                if let Value::Object(attributes) = attributes {
+                    mbean_values.extend(numeric_attributes(name, attributes));
+                }
+            }
+        } else {
+            mbean_values.extend(numeric_attributes(mbean, attributes));
+        }
+    }
+
+    Ok(JolokiaSample {
+        gc_values,
+        threads,
+        mbeans: mbean_values,
+    })
+}
+
+// A key property of an MBean name, e.g. the name of "java.lang:name=G1 Eden Space,type=MemoryPool"
+fn mbean_key<'a>(mbean: &'a str, key: &str) -> Option<&'a str> {
+    let (_, properties) = mbean.split_once(':')?;
+    properties.split(',').find_map(|property| {
+        let (name, value) = property.split_once('=')?;
+        (name == key).then_some(value)
+    })
+}
+
+// jstat -gc capacity and usage columns of a memory pool, by the pool names of the HotSpot collectors
+fn pool_columns(pool: &str) -> Option<(&'static str, &'static str)> {
+    if pool.contains("Eden") {
+        Some(("EC", "EU"))
+    } else if pool.contains("Survivor") {
+        Some(("S0C", "S0U"))
+    } else if pool.contains("Old Gen") || pool.contains("Tenured") {
+        Some(("OC", "OU"))
+    } else if pool == "Metaspace" {
+        Some(("MC", "MU"))
+    } else if pool == "Compressed Class Space" {
+        Some(("CCSC", "CCSU"))
+    } else {
+        None
+    }
+}
+
+// jstat -gc count and time columns of a garbage collector, e.g. "G1 Young Generation" or "PS MarkSweep"
+fn gc_columns(collector: &str) -> (&'static str, &'static str) {
+    let young = ["Young", "Scavenge", "ParNew", "Copy", "Minor"];
+    if young.iter().any(|name| collector.contains(name)) {
+        ("YGC", "YGCT")
+    } else if collector.ends_with("Concurrent GC") {
+        ("CGC", "CGCT")
+    } else {
+        ("FGC", "FGCT")
+    }
+}
+
+// Numeric attributes of an MBean, composite values with the key appended, e.g. "HeapMemoryUsage.used"
+fn numeric_attributes(mbean: &str, attributes: &Map<String, Value>) -> Vec<(String, String, f64)> {
+    let mut values = Vec::new();
+    for (attribute, value) in attributes {
+        match value {
+            Value::Number(number) => {
+                if let Some(number) = number.as_f64() {
+                    values.push((mbean.to_string(), attribute.clone(), number));
+                }
+            }
+            Value::Object(fields) => {
+                for (key, field) in fields {
+                    if let Some(number) = field.as_f64() {
+                        values.push((mbean.to_string(), format!("{}.{}", attribute, key), number));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_jolokia_response() {
+        let body = r#"[
+            {"status": 200, "value": {
+                "java.lang:name=G1 Eden Space,type=MemoryPool": {"Usage": {"committed": 4194304, "used": 2097152, "max": -1}},
+                "java.lang:name=G1 Survivor Space,type=MemoryPool": {"Usage": {"committed": 1048576, "used": 1048576, "max": -1}},
+                "java.lang:name=G1 Old Gen,type=MemoryPool": {"Usage": {"committed": 8388608, "used": 3145728, "max": 67108864}},
+                "java.lang:name=Metaspace,type=MemoryPool": {"Usage": {"committed": 2048, "used": 1024, "max": -1}},
+                "java.lang:name=CodeHeap 'non-nmethods',type=MemoryPool": {"Usage": {"committed": 4096, "used": 1024, "max": 8192}}
+            }},
+            {"status": 200, "value": {
+                "java.lang:name=G1 Young Generation,type=GarbageCollector": {"CollectionCount": 12, "CollectionTime": 250},
+                "java.lang:name=G1 Old Generation,type=GarbageCollector": {"CollectionCount": 1, "CollectionTime": 125},
+                "java.lang:name=G1 Concurrent GC,type=GarbageCollector": {"CollectionCount": 4, "CollectionTime": 125}
+            }},
+            {"status": 200, "value": {"ThreadCount": 42, "DaemonThreadCount": 20, "PeakThreadCount": 50}},
+            {"status": 200, "value": {"Size": 7, "Name": "orders", "Stats": {"processed": 120, "state": "ok"}}},
+            {"status": 404, "error": "javax.management.InstanceNotFoundException"}
+        ]"#;
+        let mbeans = vec![
+            "com.example:type=OrderQueue".to_string(),
+            "com.example:type=Missing".to_string(),
+        ];
+        let sample = parse_jolokia_response(body, &mbeans).unwrap();
+        let column = |name: &str| {
+            sample
+                .gc_values
+                .iter()
+                .find(|(h, _)| h == name)
+                .map(|(_, v)| *v)
+        };
+        assert_eq!(column("EC"), Some(4096.0));
+        assert_eq!(column("EU"), Some(2048.0));
+        assert_eq!(column("S0U"), Some(1024.0));
+        assert_eq!(column("S1C"), Some(0.0));
+        assert_eq!(column("OC"), Some(8192.0));
+        assert_eq!(column("MU"), Some(1.0));
+        assert_eq!(column("CCSC"), None);
+        assert_eq!(column("YGC"), Some(12.0));
+        assert_eq!(column("YGCT"), Some(0.25));
+        assert_eq!(column("FGC"), Some(1.0));
+        assert_eq!(column("CGC"), Some(4.0));
+        assert_eq!(column("GCT"), Some(0.5));
+        assert_eq!(
+            sample.threads,
+            vec![("live", 42.0), ("daemon", 20.0), ("peak", 50.0)]
+        );
+        let mut mbean_values = sample.mbeans;
+        mbean_values.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            mbean_values,
+            vec![
+                (
+                    "com.example:type=OrderQueue".to_string(),
+                    "Size".to_string(),
+                    7.0
+                ),
+                (
+                    "com.example:type=OrderQueue".to_string(),
+                    "Stats.processed".to_string(),
+                    120.0
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn fails_without_memory_pools() {
+        let body = r#"[{"status": 403, "error": "Access denied"}]"#;
+        assert!(parse_jolokia_response(body, &[]).is_err());
+        assert!(parse_jolokia_response("<html>", &[]).is_err());
+    }
+
+    #[test]
+    fn finds_jolokia_url_by_pid_or_process_name() {
+        let urls: HashMap<String, String> = [
+            ("4242", "http://localhost:8778/jolokia"),
+            ("abc123#4242", "http://172.17.0.2:8778/jolokia"),
+            ("OrderService", "http://localhost:8779/jolokia"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            jolokia_url(&urls, "host", "4242", "OrderService"),
+            Some("http://localhost:8778/jolokia")
+        );
+        assert_eq!(
+            jolokia_url(&urls, "abc123", "4242", "Other"),
+            Some("http://172.17.0.2:8778/jolokia")
+        );
+        // A bare PID is a host PID
+        assert_eq!(jolokia_url(&urls, "def456", "4242", "Other"), None);
+        assert_eq!(
+            jolokia_url(&urls, "host", "1", "OrderService"),
+            Some("http://localhost:8779/jolokia")
+        );
+        assert_eq!(jolokia_url(&urls, "host", "1", "Other"), None);
+    }
+}
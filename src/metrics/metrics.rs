@@ -1,11 +1,53 @@
-use crate::config::Config;
-use prometheus::{GaugeVec, Registry};
+use crate::config::{Config, ProcessMatchType, DEFAULT_GC_PAUSE_BUCKETS, DEFAULT_MAX_SERIES};
+use crate::metrics::collect::ProcessPattern;
+use crate::metrics::gclog::gc_pause_buckets_error;
+use crate::metrics::runner::{CommandRunner, TokioCommandRunner};
+use log::{info, warn};
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, HistogramVec, Registry};
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use sysinfo::System;
 use tokio::sync::Mutex;
 
-pub const JSTAT_COMMANDS: &[&str] = &["-gc", "-class"];
+pub const JSTAT_COMMANDS: &[&str] = &["-gc", "-gcutil", "-gccapacity", "-class", "-compiler"];
+// jstat commands only run once listed in jstat_extra_commands, so that each JVM costs as few
+// jstat runs per collection as possible by default
+pub const JSTAT_OPTIONAL_COMMANDS: &[&str] = &["-gcutil", "-gccapacity", "-gccause"];
+// jstat -gc columns in KB that are also exposed as named metrics in bytes: (column, name, help)
+pub const JSTAT_GC_BYTES_METRICS: &[(&str, &str, &str)] = &[
+    ("MC", "jvm_metaspace_capacity_bytes", "Metaspace capacity in bytes"),
+    ("MU", "jvm_metaspace_used_bytes", "Metaspace used in bytes"),
+    (
+        "CCSC",
+        "jvm_compressed_class_space_capacity_bytes",
+        "Compressed class space capacity in bytes",
+    ),
+    (
+        "CCSU",
+        "jvm_compressed_class_space_used_bytes",
+        "Compressed class space used in bytes",
+    ),
+];
+// jstat -gcutil utilization columns also exposed as named metrics, computed from the used and
+// capacity columns of jstat -gc the way -gcutil does: (column, name, help)
+pub const JSTAT_GCUTIL_PERCENT_METRICS: &[(&str, &str, &str)] = &[
+    (
+        "O",
+        "jvm_old_gen_utilization_percent",
+        "Old generation utilization percentage, the O column of jstat -gcutil",
+    ),
+    (
+        "M",
+        "jvm_metaspace_utilization_percent",
+        "Metaspace utilization percentage, the M column of jstat -gcutil",
+    ),
+];
 pub const EXCLUDED_PROCESSES: &[&str] = &["jps"];
+pub const ADDRESS_FAMILIES: &[&str] = &["ipv4", "ipv6"];
 pub const TCP_STATES: &[&str] = &[
     "CLOSED",
     "LISTEN",
@@ -22,62 +64,209 @@ pub const TCP_STATES: &[&str] = &[
 ];
 pub struct Metrics {
     pub(crate) config: Arc<RwLock<Config>>,
+    pub(crate) runner: Arc<dyn CommandRunner>, // Runs jps, jstat, jcmd and container runtime commands
+    pub(crate) aggregate_by_process: bool, // Drop the pid label and combine PIDs of the same process
+    pub(crate) tcp_address_family: bool,   // Split TCP connection states by address_family
+    pub(crate) collect_system_metrics: bool, // Collect and export the system_* metrics
+    pub(crate) disabled_metrics: HashSet<String>, // Names of metrics that are not registered
     pub(crate) process_metrics: ProcessMetrics,
     pub(crate) system_metrics: SystemMetrics,
+    pub(crate) container_metrics: ContainerMetrics,
     pub(crate) active_pids: Mutex<HashMap<String, String>>, // Key: container#pid
+    pub(crate) start_times: Mutex<HashMap<String, u64>>, // Key: container#pid, start time of host processes
+    pub(crate) up_times: Mutex<HashMap<String, f64>>, // Key: container#pid, seconds since start at the last collection
     pub(crate) jstat_labels:
         Mutex<HashMap<(&'static str, String, String, String), HashSet<String>>>, // (command, container, pid, process_name)
+    pub(crate) non_hotspot_pids: Mutex<HashSet<String>>, // Key: container#pid, processes jstat cannot attach to
+    pub(crate) jstat_version_mismatches: Mutex<HashSet<String>>, // Key: container#pid, processes jstat last failed for with a JDK mismatch
+    pub(crate) jstat_failures: Mutex<HashMap<String, JstatFailures>>, // Key: container#pid, processes jstat keeps failing for
+    pub(crate) java_versions: Mutex<HashMap<String, String>>, // Key: container#pid
+    pub(crate) process_args: Mutex<HashMap<String, String>>, // Key: container#pid, program arguments from jcmd VM.command_line
+    pub(crate) process_args_retries: Mutex<HashMap<String, Instant>>, // Key: container#pid, when a failed VM.command_line is run again
+    pub(crate) java_runtimes: Mutex<HashMap<String, (String, String)>>, // Key: container#pid, (vendor, version) from java -version
+    pub(crate) detected_java_homes: Mutex<HashMap<String, Option<String>>>, // Key: container#pid, JDK found from /proc/<pid>/exe
+    pub(crate) jcmd_labels: Mutex<HashMap<String, HashSet<String>>>, // Key: container#pid, native memory categories
+    pub(crate) nmt_disabled_pids: Mutex<HashSet<String>>, // Key: container#pid, JVMs started without -XX:NativeMemoryTracking
+    pub(crate) jolokia_labels: Mutex<HashMap<String, HashSet<(String, String)>>>, // Key: container#pid, (mbean, attribute) of jvm_jolokia_mbean_value
+    pub(crate) listening_ports: Mutex<HashMap<String, HashSet<String>>>, // Key: container#pid, port labels of process_listening_ports
+    pub(crate) disk_io_totals: Mutex<HashMap<String, (u64, u64)>>, // Key: container#pid, last seen (read, written) bytes
+    pub(crate) ctxt_switch_totals: Mutex<HashMap<String, (u64, u64)>>, // Key: container#pid, last seen (voluntary, nonvoluntary)
+    pub(crate) page_fault_totals: Mutex<HashMap<String, (u64, u64)>>, // Key: container#pid, last seen (major, minor)
+    pub(crate) gc_counts: Mutex<HashMap<String, (f64, f64, Instant)>>, // Key: container#pid, last (YGC, FGC, time) from jstat -gc
+    pub(crate) gc_log_offsets: Mutex<HashMap<u64, u64>>, // Key: GC log file id, bytes already read
+    pub(crate) process_info_labels: Mutex<HashMap<String, Vec<String>>>, // Key: container#pid, labels of jvm_process_info
+    pub(crate) process_states: Mutex<HashMap<String, &'static str>>, // Key: container#pid, state label of process_state_info
+    pub(crate) gc_causes: Mutex<HashMap<String, String>>, // Key: container#pid, cause label of jvm_last_gc_cause_info
+    pub(crate) containers: Mutex<Vec<Container>>, // Refreshed in the background every container_list_ttl_secs
+    pub(crate) denied_runtimes: Mutex<HashSet<&'static str>>, // Container runtimes already warned about for permission denied
+    pub(crate) system_process_patterns:
+        Mutex<Option<(Vec<String>, ProcessMatchType, Vec<ProcessPattern>)>>, // system_processes as compiled for the current config
+    pub(crate) runtime_outages: Mutex<HashMap<&'static str, Instant>>, // Container runtimes that stopped answering, and since when
+    pub(crate) jps_ok: AtomicBool, // Whether jps succeeded at least once, for /readyz
+    pub(crate) series_capped: AtomicBool, // Over max_series at the last collection, new series are not added
+    pub(crate) series_fingerprint: AtomicU64, // Label cache sizes and max_series when the series were last counted
+    pub(crate) collection: Mutex<()>, // Held by update_metrics, so that two collections never overlap
+    pub(crate) system: Mutex<System>, // Process list refreshed once per collection, see update_metrics
 }
 
 pub(crate) struct ProcessMetrics {
     pub(crate) cpu_usage: GaugeVec,
+    pub(crate) cpu_usage_normalized: GaugeVec,
     pub(crate) memory_usage: GaugeVec,
     pub(crate) memory_usage_percentage: GaugeVec,
+    pub(crate) virtual_memory: GaugeVec,
+    pub(crate) disk_read_bytes: CounterVec,
+    pub(crate) disk_write_bytes: CounterVec,
+    pub(crate) voluntary_ctxt_switches: CounterVec,
+    pub(crate) nonvoluntary_ctxt_switches: CounterVec,
+    pub(crate) major_page_faults: CounterVec,
+    pub(crate) minor_page_faults: CounterVec,
     pub(crate) start_time: GaugeVec,
     pub(crate) up_time: GaugeVec,
     pub(crate) open_file: GaugeVec,
     pub(crate) open_file_limit: GaugeVec,
+    pub(crate) open_file_soft_limit: GaugeVec,
+    pub(crate) open_file_hard_limit: GaugeVec,
     pub(crate) tcp_connection_states: GaugeVec,
+    pub(crate) listening_ports: GaugeVec,
+    pub(crate) info: GaugeVec,
+    pub(crate) version_info: GaugeVec,
+    pub(crate) state_info: GaugeVec,
+    pub(crate) last_gc_cause_info: GaugeVec,
+    pub(crate) up: GaugeVec,
+    pub(crate) jstat_version_mismatch: GaugeVec,
     pub(crate) jstat_metrics_map: HashMap<&'static str, GaugeVec>,
+    pub(crate) jstat_gc_bytes_metrics: HashMap<&'static str, GaugeVec>, // Key: jstat -gc column
+    pub(crate) jstat_gcutil_percent_metrics: HashMap<&'static str, GaugeVec>, // Key: jstat -gcutil column
+    pub(crate) heap_used_percentage: GaugeVec,
+    pub(crate) gc_time_percentage: GaugeVec,
+    pub(crate) jstat_sample_timestamp: GaugeVec,
+    pub(crate) gc_young_per_sec: GaugeVec,
+    pub(crate) gc_full_per_sec: GaugeVec,
+    pub(crate) native_memory_reserved: GaugeVec,
+    pub(crate) native_memory_committed: GaugeVec,
+    pub(crate) code_cache_used: GaugeVec,
+    pub(crate) code_cache_max: GaugeVec,
+    pub(crate) threads: GaugeVec,
+    pub(crate) jolokia_mbean_value: GaugeVec,
+    pub(crate) gc_pause_seconds: HistogramVec,
+    pub(crate) heap_after_gc: GaugeVec,
 }
 
 pub(crate) struct SystemMetrics {
     pub(crate) cpu_usage: GaugeVec,
+    pub(crate) cpu_count: GaugeVec,
     pub(crate) memory_usage: GaugeVec,
     pub(crate) total_memory: GaugeVec,
+    pub(crate) available_memory: GaugeVec,
+    pub(crate) memory_usage_percentage: GaugeVec,
+    pub(crate) cgroup_memory_limit: GaugeVec,
     pub(crate) disk_usage: GaugeVec,
     pub(crate) total_disk: GaugeVec,
+    pub(crate) disk_inodes_total: GaugeVec,
+    pub(crate) disk_inodes_used: GaugeVec,
     pub(crate) network_receive_bytes_per_sec: GaugeVec,
     pub(crate) network_transmit_bytes_per_sec: GaugeVec,
+    pub(crate) network_errors: CounterVec,
+    pub(crate) network_drops: CounterVec,
     pub(crate) uptime: GaugeVec,
     pub(crate) total_swap: GaugeVec,
     pub(crate) swap_usage: GaugeVec,
     pub(crate) open_file: GaugeVec,
     pub(crate) open_file_limit: GaugeVec,
     pub(crate) tcp_connection_states: GaugeVec,
+    pub(crate) scrape_timeouts: Counter,
+    pub(crate) java_processes: Gauge,
+    pub(crate) containers_scanned: Gauge,
+    pub(crate) dependency_available: GaugeVec,
+    pub(crate) java_home_info: GaugeVec,
+    pub(crate) jstat_duration_seconds: HistogramVec,
+}
+
+pub(crate) struct ContainerMetrics {
+    pub(crate) cpu_quota: GaugeVec,
+    pub(crate) memory_limit: GaugeVec,
 }
 
 impl Metrics {
     pub(crate) fn new(registry: &Registry, config: Arc<RwLock<Config>>) -> Self {
+        // Label sets are fixed at registration, so this only takes effect on restart
+        let aggregate_by_process = config
+            .read()
+            .unwrap()
+            .aggregate_by_process
+            .unwrap_or_default();
+        let tcp_address_family = config
+            .read()
+            .unwrap()
+            .tcp_address_family
+            .unwrap_or_default();
+        let tcp_state_labels: &[&str] = if tcp_address_family {
+            &["state", "address_family"]
+        } else {
+            &["state"]
+        };
+        let collect_system_metrics = config
+            .read()
+            .unwrap()
+            .collect_system_metrics
+            .unwrap_or(true);
+        let disabled_metrics: HashSet<String> = config
+            .read()
+            .unwrap()
+            .disabled_metrics
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        // Histogram buckets cannot change once registered, invalid ones fall back to the default
+        let gc_pause_buckets = match config.read().unwrap().gc_pause_buckets.clone() {
+            Some(buckets) => match gc_pause_buckets_error(&buckets) {
+                Some(e) => {
+                    warn!("Invalid gc_pause_buckets, using the default buckets: {}", e);
+                    DEFAULT_GC_PAUSE_BUCKETS.to_vec()
+                }
+                None => buckets,
+            },
+            None => DEFAULT_GC_PAUSE_BUCKETS.to_vec(),
+        };
+        let registry = MetricsRegistry {
+            registry,
+            disabled: &disabled_metrics,
+        };
+
         // Initialize Process Metrics
         let process_metrics = {
             // CPU Usage
             let cpu_usage = GaugeVec::new(
                 prometheus::Opts::new("process_cpu_usage", "CPU usage percentage of the process"),
-                &["container", "pid", "process_name"],
+                &process_label_names(aggregate_by_process, &[]),
             )
             .expect("Failed to create process_cpu_usage GaugeVec");
             registry
                 .register(Box::new(cpu_usage.clone()))
                 .expect("Failed to register process_cpu_usage metric");
 
+            // CPU Usage relative to all cores, 100 when every core is busy
+            let cpu_usage_normalized = GaugeVec::new(
+                prometheus::Opts::new(
+                    "process_cpu_usage_normalized",
+                    "CPU usage percentage of the process divided by the number of CPUs, 0 to 100",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_cpu_usage_normalized GaugeVec");
+            registry
+                .register(Box::new(cpu_usage_normalized.clone()))
+                .expect("Failed to register process_cpu_usage_normalized metric");
+
             // Memory Usage
             let memory_usage = GaugeVec::new(
                 prometheus::Opts::new(
                     "process_memory_usage_bytes",
                     "Memory usage in bytes of the process",
                 ),
-                &["container", "pid", "process_name"],
+                &process_label_names(aggregate_by_process, &[]),
             )
             .expect("Failed to create process_memory_usage_bytes GaugeVec");
             registry
@@ -90,20 +279,108 @@ impl Metrics {
                     "process_memory_usage_percentage",
                     "Memory usage percentage of the process",
                 ),
-                &["container", "pid", "process_name"],
+                &process_label_names(aggregate_by_process, &[]),
             )
             .expect("Failed to create process_memory_usage_percentage GaugeVec");
             registry
                 .register(Box::new(memory_usage_percentage.clone()))
                 .expect("Failed to register process_memory_usage_percentage metric");
 
+            // Virtual Memory
+            let virtual_memory = GaugeVec::new(
+                prometheus::Opts::new(
+                    "process_virtual_memory_bytes",
+                    "Virtual memory size in bytes of the process",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_virtual_memory_bytes GaugeVec");
+            registry
+                .register(Box::new(virtual_memory.clone()))
+                .expect("Failed to register process_virtual_memory_bytes metric");
+
+            // Disk I/O, counters since sysinfo reports cumulative bytes
+            let disk_read_bytes = CounterVec::new(
+                prometheus::Opts::new(
+                    "process_disk_read_bytes_total",
+                    "Bytes read from disk by the process",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_disk_read_bytes_total CounterVec");
+            registry
+                .register(Box::new(disk_read_bytes.clone()))
+                .expect("Failed to register process_disk_read_bytes_total metric");
+
+            let disk_write_bytes = CounterVec::new(
+                prometheus::Opts::new(
+                    "process_disk_write_bytes_total",
+                    "Bytes written to disk by the process",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_disk_write_bytes_total CounterVec");
+            registry
+                .register(Box::new(disk_write_bytes.clone()))
+                .expect("Failed to register process_disk_write_bytes_total metric");
+
+            // Context switches from /proc/<pid>/status, Linux only
+            let voluntary_ctxt_switches = CounterVec::new(
+                prometheus::Opts::new(
+                    "process_voluntary_ctxt_switches_total",
+                    "Voluntary context switches of the process, e.g. waiting for I/O or a lock",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_voluntary_ctxt_switches_total CounterVec");
+            registry
+                .register(Box::new(voluntary_ctxt_switches.clone()))
+                .expect("Failed to register process_voluntary_ctxt_switches_total metric");
+
+            let nonvoluntary_ctxt_switches = CounterVec::new(
+                prometheus::Opts::new(
+                    "process_nonvoluntary_ctxt_switches_total",
+                    "Involuntary context switches of the process, preempted by the scheduler",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_nonvoluntary_ctxt_switches_total CounterVec");
+            registry
+                .register(Box::new(nonvoluntary_ctxt_switches.clone()))
+                .expect("Failed to register process_nonvoluntary_ctxt_switches_total metric");
+
+            // Page faults from /proc/<pid>/stat, Linux only
+            let major_page_faults = CounterVec::new(
+                prometheus::Opts::new(
+                    "process_major_page_faults_total",
+                    "Major page faults of the process, which had to read the page from disk or swap",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_major_page_faults_total CounterVec");
+            registry
+                .register(Box::new(major_page_faults.clone()))
+                .expect("Failed to register process_major_page_faults_total metric");
+
+            let minor_page_faults = CounterVec::new(
+                prometheus::Opts::new(
+                    "process_minor_page_faults_total",
+                    "Minor page faults of the process, served without reading from disk",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_minor_page_faults_total CounterVec");
+            registry
+                .register(Box::new(minor_page_faults.clone()))
+                .expect("Failed to register process_minor_page_faults_total metric");
+
             // Start Time
             let start_time = GaugeVec::new(
                 prometheus::Opts::new(
                     "process_start_time_seconds",
                     "Start time of the process in seconds since the epoch",
                 ),
-                &["container", "pid", "process_name"],
+                &process_label_names(aggregate_by_process, &[]),
             )
             .expect("Failed to create process_start_time_seconds GaugeVec");
             registry
@@ -116,7 +393,7 @@ impl Metrics {
                     "process_up_time_seconds",
                     "Up time of the process in seconds",
                 ),
-                &["container", "pid", "process_name"],
+                &process_label_names(aggregate_by_process, &[]),
             )
             .expect("Failed to create process_up_time_seconds GaugeVec");
             registry
@@ -131,7 +408,7 @@ impl Metrics {
                         format!("jstat_{}_metrics", &cmd[1..]),
                         format!("Metrics from jstat {}", cmd),
                     ),
-                    &["container", "pid", "process_name", "metric_name"],
+                    &process_label_names(aggregate_by_process, &["metric_name"]),
                 )
                 .expect(&format!("Failed to create GaugeVec for command {}", cmd));
                 registry
@@ -140,50 +417,409 @@ impl Metrics {
                 jstat_metrics_map.insert(cmd, metric);
             }
 
+            // Named metrics derived from jstat -gc columns
+            let mut jstat_gc_bytes_metrics = HashMap::new();
+            for &(column, name, help) in JSTAT_GC_BYTES_METRICS.iter() {
+                let metric = GaugeVec::new(
+                    prometheus::Opts::new(name, help),
+                    &process_label_names(aggregate_by_process, &[]),
+                )
+                .expect(&format!("Failed to create {} GaugeVec", name));
+                registry
+                    .register(Box::new(metric.clone()))
+                    .expect(&format!("Failed to register {} metric", name));
+                jstat_gc_bytes_metrics.insert(column, metric);
+            }
+
+            // Named metrics derived from jstat -gcutil columns
+            let mut jstat_gcutil_percent_metrics = HashMap::new();
+            for &(column, name, help) in JSTAT_GCUTIL_PERCENT_METRICS.iter() {
+                let metric = GaugeVec::new(
+                    prometheus::Opts::new(name, help),
+                    &process_label_names(aggregate_by_process, &[]),
+                )
+                .expect(&format!("Failed to create {} GaugeVec", name));
+                registry
+                    .register(Box::new(metric.clone()))
+                    .expect(&format!("Failed to register {} metric", name));
+                jstat_gcutil_percent_metrics.insert(column, metric);
+            }
+
+            let heap_used_percentage = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_heap_used_percentage",
+                    "Heap usage percentage, survivor, eden and old generation used over their capacity",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_heap_used_percentage GaugeVec");
+            registry
+                .register(Box::new(heap_used_percentage.clone()))
+                .expect("Failed to register jvm_heap_used_percentage metric");
+
+            let gc_time_percentage = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_gc_time_percentage",
+                    "Percentage of the time since the process started spent in GC, GCT of jstat -gc over the uptime",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_gc_time_percentage GaugeVec");
+            registry
+                .register(Box::new(gc_time_percentage.clone()))
+                .expect("Failed to register jvm_gc_time_percentage metric");
+
+            // JVM uptime of the last jstat sample, printed by jstat -t
+            let jstat_sample_timestamp = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_jstat_sample_timestamp_seconds",
+                    "JVM uptime in seconds when jstat took the sample, from the Timestamp column of jstat -t",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_jstat_sample_timestamp_seconds GaugeVec");
+            registry
+                .register(Box::new(jstat_sample_timestamp.clone()))
+                .expect("Failed to register jvm_jstat_sample_timestamp_seconds metric");
+
+            // GC frequency between two jstat -gc samples
+            let gc_young_per_sec = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_gc_young_per_sec",
+                    "Young generation collections per second since the previous sample",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_gc_young_per_sec GaugeVec");
+            registry
+                .register(Box::new(gc_young_per_sec.clone()))
+                .expect("Failed to register jvm_gc_young_per_sec metric");
+
+            let gc_full_per_sec = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_gc_full_per_sec",
+                    "Full collections per second since the previous sample",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_gc_full_per_sec GaugeVec");
+            registry
+                .register(Box::new(gc_full_per_sec.clone()))
+                .expect("Failed to register jvm_gc_full_per_sec metric");
+
+            // jcmd Native Memory Tracking
+            let native_memory_reserved = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_native_memory_reserved_bytes",
+                    "Reserved memory in bytes by native memory tracking category, from jcmd VM.native_memory",
+                ),
+                &process_label_names(aggregate_by_process, &["category"]),
+            )
+            .expect("Failed to create jvm_native_memory_reserved_bytes GaugeVec");
+            registry
+                .register(Box::new(native_memory_reserved.clone()))
+                .expect("Failed to register jvm_native_memory_reserved_bytes metric");
+
+            let native_memory_committed = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_native_memory_committed_bytes",
+                    "Committed memory in bytes by native memory tracking category, from jcmd VM.native_memory",
+                ),
+                &process_label_names(aggregate_by_process, &["category"]),
+            )
+            .expect("Failed to create jvm_native_memory_committed_bytes GaugeVec");
+            registry
+                .register(Box::new(native_memory_committed.clone()))
+                .expect("Failed to register jvm_native_memory_committed_bytes metric");
+
+            // jcmd Compiler.codecache, a full code cache stops JIT compilation
+            let code_cache_used = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_code_cache_used_bytes",
+                    "Used JIT code cache in bytes, summed over code heaps, from jcmd Compiler.codecache",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_code_cache_used_bytes GaugeVec");
+            registry
+                .register(Box::new(code_cache_used.clone()))
+                .expect("Failed to register jvm_code_cache_used_bytes metric");
+
+            let code_cache_max = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_code_cache_max_bytes",
+                    "Size of the JIT code cache in bytes, summed over code heaps, from jcmd Compiler.codecache",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_code_cache_max_bytes GaugeVec");
+            registry
+                .register(Box::new(code_cache_max.clone()))
+                .expect("Failed to register jvm_code_cache_max_bytes metric");
+
+            // MBeans read through the Jolokia agent of JVMs listed in jolokia_urls
+            let threads = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_threads",
+                    "Live, daemon and peak threads of the JVM from the Threading MBean, read through Jolokia",
+                ),
+                &process_label_names(aggregate_by_process, &["state"]),
+            )
+            .expect("Failed to create jvm_threads GaugeVec");
+            registry
+                .register(Box::new(threads.clone()))
+                .expect("Failed to register jvm_threads metric");
+
+            let jolokia_mbean_value = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_jolokia_mbean_value",
+                    "Numeric attributes of the MBeans listed in jolokia_mbeans, read through Jolokia",
+                ),
+                &process_label_names(aggregate_by_process, &["mbean", "attribute"]),
+            )
+            .expect("Failed to create jvm_jolokia_mbean_value GaugeVec");
+            registry
+                .register(Box::new(jolokia_mbean_value.clone()))
+                .expect("Failed to register jvm_jolokia_mbean_value metric");
+
+            // GC log, keyed by process name since log files are configured per process
+            let gc_pause_seconds = HistogramVec::new(
+                prometheus::HistogramOpts::new(
+                    "jvm_gc_pause_seconds",
+                    "GC pause durations in seconds read from GC log files",
+                )
+                .buckets(gc_pause_buckets),
+                &["process_name", "generation", "pause"],
+            )
+            .expect("Failed to create jvm_gc_pause_seconds HistogramVec");
+            registry
+                .register(Box::new(gc_pause_seconds.clone()))
+                .expect("Failed to register jvm_gc_pause_seconds metric");
+
+            let heap_after_gc = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_gc_heap_after_gc_bytes",
+                    "Heap used in bytes after the latest GC pause read from GC log files",
+                ),
+                &["process_name"],
+            )
+            .expect("Failed to create jvm_gc_heap_after_gc_bytes GaugeVec");
+            registry
+                .register(Box::new(heap_after_gc.clone()))
+                .expect("Failed to register jvm_gc_heap_after_gc_bytes metric");
+
             let open_file = GaugeVec::new(
                 prometheus::Opts::new("process_open_file", "Used open file descriptors"),
-                &["container", "pid", "process_name"],
+                &process_label_names(aggregate_by_process, &[]),
             )
             .expect("Failed to create process_open_file GaugeVec");
             registry
                 .register(Box::new(open_file.clone()))
                 .expect("Failed to register process_open_file metric");
 
+            // Kept for compatibility, prefer the soft and hard limits below
             let open_file_limit = GaugeVec::new(
-                prometheus::Opts::new("process_open_file_limit", "Max open file descriptors"),
-                &["container", "pid", "process_name"],
+                prometheus::Opts::new(
+                    "process_open_file_limit",
+                    "Max open file descriptors (deprecated, use process_open_file_soft_limit)",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
             )
             .expect("Failed to create process_open_file_limit GaugeVec");
             registry
                 .register(Box::new(open_file_limit.clone()))
                 .expect("Failed to register process_open_file_limit metric");
 
+            let open_file_soft_limit = GaugeVec::new(
+                prometheus::Opts::new(
+                    "process_open_file_soft_limit",
+                    "Soft limit of open file descriptors",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_open_file_soft_limit GaugeVec");
+            registry
+                .register(Box::new(open_file_soft_limit.clone()))
+                .expect("Failed to register process_open_file_soft_limit metric");
+
+            let open_file_hard_limit = GaugeVec::new(
+                prometheus::Opts::new(
+                    "process_open_file_hard_limit",
+                    "Hard limit of open file descriptors",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create process_open_file_hard_limit GaugeVec");
+            registry
+                .register(Box::new(open_file_hard_limit.clone()))
+                .expect("Failed to register process_open_file_hard_limit metric");
+
             let tcp_connection_states = GaugeVec::new(
                 prometheus::Opts::new(
                     "process_tcp_connection_states",
                     "Number of TCP connections in different states for the process",
                 ),
-                &["container", "pid", "process_name", "state"], // 添加 state 标签
+                &process_label_names(aggregate_by_process, tcp_state_labels), // 添加 state 标签
             )
             .expect("Failed to create process_tcp_connection_states GaugeVec");
             registry
                 .register(Box::new(tcp_connection_states.clone()))
                 .expect("Failed to register process_tcp_connection_states metric");
 
+            let listening_ports = GaugeVec::new(
+                prometheus::Opts::new(
+                    "process_listening_ports",
+                    "TCP ports the process listens on, always 1",
+                ),
+                &process_label_names(aggregate_by_process, &["port"]),
+            )
+            .expect("Failed to create process_listening_ports GaugeVec");
+            registry
+                .register(Box::new(listening_ports.clone()))
+                .expect("Failed to register process_listening_ports metric");
+
+            let info = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_process_info",
+                    "Descriptive information about the JVM process, always 1",
+                ),
+                &process_label_names(
+                    aggregate_by_process,
+                    &[
+                        "java_version",
+                        "start_time",
+                        "user",
+                        "image",
+                        "args",
+                        "pod",
+                        "namespace",
+                        "pod_uid",
+                    ],
+                ),
+            )
+            .expect("Failed to create jvm_process_info GaugeVec");
+            registry
+                .register(Box::new(info.clone()))
+                .expect("Failed to register jvm_process_info metric");
+
+            let version_info = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_version_info",
+                    "Vendor and version of the JVM from java -version, always 1",
+                ),
+                &process_label_names(aggregate_by_process, &["vendor", "version"]),
+            )
+            .expect("Failed to create jvm_version_info GaugeVec");
+            registry
+                .register(Box::new(version_info.clone()))
+                .expect("Failed to register jvm_version_info metric");
+
+            let state_info = GaugeVec::new(
+                prometheus::Opts::new(
+                    "process_state_info",
+                    "Scheduler state of the process, e.g. running, sleeping or zombie, always 1",
+                ),
+                &process_label_names(aggregate_by_process, &["state"]),
+            )
+            .expect("Failed to create process_state_info GaugeVec");
+            registry
+                .register(Box::new(state_info.clone()))
+                .expect("Failed to register process_state_info metric");
+
+            // From jstat -gccause, which is run in place of -gcutil when both are listed
+            let last_gc_cause_info = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_last_gc_cause_info",
+                    "Cause of the last GC of the JVM, e.g. Allocation Failure, always 1",
+                ),
+                &process_label_names(aggregate_by_process, &["cause"]),
+            )
+            .expect("Failed to create jvm_last_gc_cause_info GaugeVec");
+            registry
+                .register(Box::new(last_gc_cause_info.clone()))
+                .expect("Failed to register jvm_last_gc_cause_info metric");
+
+            let up = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_process_up",
+                    "Whether jstat metrics were collected for the JVM process, 0 when jstat failed or is skipped after repeated failures",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_process_up GaugeVec");
+            registry
+                .register(Box::new(up.clone()))
+                .expect("Failed to register jvm_process_up metric");
+
+            let jstat_version_mismatch = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_jstat_version_mismatch",
+                    "Whether jstat last failed for the JVM process because its JDK does not match the JVM's",
+                ),
+                &process_label_names(aggregate_by_process, &[]),
+            )
+            .expect("Failed to create jvm_jstat_version_mismatch GaugeVec");
+            registry
+                .register(Box::new(jstat_version_mismatch.clone()))
+                .expect("Failed to register jvm_jstat_version_mismatch metric");
+
             ProcessMetrics {
                 cpu_usage,
+                cpu_usage_normalized,
                 memory_usage,
                 memory_usage_percentage,
+                virtual_memory,
+                disk_read_bytes,
+                disk_write_bytes,
+                voluntary_ctxt_switches,
+                nonvoluntary_ctxt_switches,
+                major_page_faults,
+                minor_page_faults,
                 start_time,
                 up_time,
                 jstat_metrics_map,
+                jstat_gc_bytes_metrics,
+                jstat_gcutil_percent_metrics,
+                heap_used_percentage,
+                gc_time_percentage,
+                jstat_sample_timestamp,
+                gc_young_per_sec,
+                gc_full_per_sec,
+                native_memory_reserved,
+                native_memory_committed,
+                code_cache_used,
+                code_cache_max,
+                threads,
+                jolokia_mbean_value,
+                gc_pause_seconds,
+                heap_after_gc,
                 open_file,
                 open_file_limit,
+                open_file_soft_limit,
+                open_file_hard_limit,
                 tcp_connection_states,
+                listening_ports,
+                info,
+                version_info,
+                state_info,
+                last_gc_cause_info,
+                up,
+                jstat_version_mismatch,
             }
         };
 
         // Initialize System Metrics
+        // Without collect_system_metrics the system gauges are kept out of the registry, so
+        // that they are not exported. The jvm_exporter_* metrics are always registered.
+        let unregistered = Registry::new();
+        let system_registry = if collect_system_metrics {
+            registry
+        } else {
+            MetricsRegistry {
+                registry: &unregistered,
+                disabled: &disabled_metrics,
+            }
+        };
         let system_metrics = {
             // System CPU Usage
             let cpu_usage = GaugeVec::new(
@@ -194,10 +830,20 @@ impl Metrics {
                 &["cpu"],
             )
             .expect("Failed to create system_cpu_usage_percentage GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(cpu_usage.clone()))
                 .expect("Failed to register system_cpu_usage_percentage metric");
 
+            // System CPU Count
+            let cpu_count = GaugeVec::new(
+                prometheus::Opts::new("system_cpu_count", "Number of CPUs of the system"),
+                &["type"],
+            )
+            .expect("Failed to create system_cpu_count GaugeVec");
+            system_registry
+                .register(Box::new(cpu_count.clone()))
+                .expect("Failed to register system_cpu_count metric");
+
             // System Memory Usage
             let memory_usage = GaugeVec::new(
                 prometheus::Opts::new(
@@ -207,7 +853,7 @@ impl Metrics {
                 &["memory_type"],
             )
             .expect("Failed to create system_memory_usage_bytes GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(memory_usage.clone()))
                 .expect("Failed to register system_memory_usage_bytes metric");
 
@@ -217,17 +863,56 @@ impl Metrics {
                 &["memory_type"],
             )
             .expect("Failed to create system_total_memory_bytes GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(total_memory.clone()))
                 .expect("Failed to register system_total_memory_bytes metric");
 
+            // Memory available to new processes without swapping, including reclaimable caches
+            let available_memory = GaugeVec::new(
+                prometheus::Opts::new(
+                    "system_available_memory_bytes",
+                    "Available system memory in bytes",
+                ),
+                &["memory_type"],
+            )
+            .expect("Failed to create system_available_memory_bytes GaugeVec");
+            system_registry
+                .register(Box::new(available_memory.clone()))
+                .expect("Failed to register system_available_memory_bytes metric");
+
+            // System Memory Usage Percentage
+            let memory_usage_percentage = GaugeVec::new(
+                prometheus::Opts::new(
+                    "system_memory_usage_percentage",
+                    "System memory usage percentage",
+                ),
+                &["memory_type"],
+            )
+            .expect("Failed to create system_memory_usage_percentage GaugeVec");
+            system_registry
+                .register(Box::new(memory_usage_percentage.clone()))
+                .expect("Failed to register system_memory_usage_percentage metric");
+
+            // Memory limit of the exporter's cgroup
+            let cgroup_memory_limit = GaugeVec::new(
+                prometheus::Opts::new(
+                    "system_cgroup_memory_limit_bytes",
+                    "Memory limit of the cgroup the exporter runs in, in bytes",
+                ),
+                &["memory_type"],
+            )
+            .expect("Failed to create system_cgroup_memory_limit_bytes GaugeVec");
+            system_registry
+                .register(Box::new(cgroup_memory_limit.clone()))
+                .expect("Failed to register system_cgroup_memory_limit_bytes metric");
+
             // System Disk Usage
             let disk_usage = GaugeVec::new(
                 prometheus::Opts::new("system_disk_usage_bytes", "Disk usage in bytes"),
                 &["disk", "mount_point"],
             )
             .expect("Failed to create system_disk_usage_bytes GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(disk_usage.clone()))
                 .expect("Failed to register system_disk_usage_bytes metric");
 
@@ -237,10 +922,29 @@ impl Metrics {
                 &["disk", "mount_point"],
             )
             .expect("Failed to create system_total_disk_bytes GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(total_disk.clone()))
                 .expect("Failed to register system_total_disk_bytes metric");
 
+            // System Disk Inodes
+            let disk_inodes_total = GaugeVec::new(
+                prometheus::Opts::new("system_disk_inodes_total", "Total number of inodes"),
+                &["disk", "mount_point"],
+            )
+            .expect("Failed to create system_disk_inodes_total GaugeVec");
+            system_registry
+                .register(Box::new(disk_inodes_total.clone()))
+                .expect("Failed to register system_disk_inodes_total metric");
+
+            let disk_inodes_used = GaugeVec::new(
+                prometheus::Opts::new("system_disk_inodes_used", "Number of used inodes"),
+                &["disk", "mount_point"],
+            )
+            .expect("Failed to create system_disk_inodes_used GaugeVec");
+            system_registry
+                .register(Box::new(disk_inodes_used.clone()))
+                .expect("Failed to register system_disk_inodes_used metric");
+
             // Network Receive Bytes Per Sec
             let network_receive_bytes_per_sec = GaugeVec::new(
                 prometheus::Opts::new(
@@ -250,7 +954,7 @@ impl Metrics {
                 &["interface"],
             )
             .expect("Failed to create system_network_receive_bytes_per_sec GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(network_receive_bytes_per_sec.clone()))
                 .expect("Failed to register system_network_receive_bytes_per_sec metric");
 
@@ -263,17 +967,42 @@ impl Metrics {
                 &["interface"],
             )
             .expect("Failed to create system_network_transmit_bytes_per_sec GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(network_transmit_bytes_per_sec.clone()))
                 .expect("Failed to register system_network_transmit_bytes_per_sec metric");
 
+            // Network errors and dropped packets, cumulative since boot
+            let network_errors = CounterVec::new(
+                prometheus::Opts::new(
+                    "system_network_errors_total",
+                    "Network errors by interface and direction",
+                ),
+                &["interface", "direction"],
+            )
+            .expect("Failed to create system_network_errors_total CounterVec");
+            system_registry
+                .register(Box::new(network_errors.clone()))
+                .expect("Failed to register system_network_errors_total metric");
+
+            let network_drops = CounterVec::new(
+                prometheus::Opts::new(
+                    "system_network_drops_total",
+                    "Dropped network packets by interface and direction",
+                ),
+                &["interface", "direction"],
+            )
+            .expect("Failed to create system_network_drops_total CounterVec");
+            system_registry
+                .register(Box::new(network_drops.clone()))
+                .expect("Failed to register system_network_drops_total metric");
+
             // System Uptime
             let uptime = GaugeVec::new(
                 prometheus::Opts::new("system_uptime_seconds", "Total system uptime in seconds"),
                 &["type"],
             )
             .expect("Failed to create system_uptime_seconds GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(uptime.clone()))
                 .expect("Failed to register system_uptime_seconds metric");
 
@@ -283,7 +1012,7 @@ impl Metrics {
                 &["swap_type"],
             )
             .expect("Failed to create system_total_swap GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(total_swap.clone()))
                 .expect("Failed to register system_total_swap metric");
 
@@ -293,25 +1022,31 @@ impl Metrics {
                 &["swap_type"],
             )
             .expect("Failed to create system_swap_usage GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(swap_usage.clone()))
                 .expect("Failed to register system_swap_usage metric");
 
             let open_file = GaugeVec::new(
-                prometheus::Opts::new("system_open_file", "Used open file descriptors"),
+                prometheus::Opts::new(
+                    "system_open_file",
+                    "File handles allocated by the kernel for all processes, from /proc/sys/fs/file-nr",
+                ),
                 &["type"],
             )
             .expect("Failed to create system_open_file GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(open_file.clone()))
                 .expect("Failed to register system_open_file metric");
 
             let open_file_limit = GaugeVec::new(
-                prometheus::Opts::new("system_open_file_limit", "Max open file descriptors"),
+                prometheus::Opts::new(
+                    "system_open_file_limit",
+                    "Max file handles the kernel allocates (fs.file-max), process limits are in process_open_file_soft_limit",
+                ),
                 &["type"],
             )
             .expect("Failed to create system_open_file_limit GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(open_file_limit.clone()))
                 .expect("Failed to register system_open_file_limit metric");
 
@@ -320,41 +1055,547 @@ impl Metrics {
                     "system_tcp_connection_states",
                     "Number of TCP connections in different states for the system",
                 ),
-                &["type", "state"], // 添加 state 标签
+                &[&["type"], tcp_state_labels].concat(), // 添加 state 标签
             )
             .expect("Failed to create system_tcp_connection_states GaugeVec");
-            registry
+            system_registry
                 .register(Box::new(tcp_connection_states.clone()))
                 .expect("Failed to register system_tcp_connection_states metric");
 
+            // Collections that hit scrape_deadline_ms
+            let scrape_timeouts = Counter::new(
+                "jvm_exporter_scrape_timeouts_total",
+                "Number of collections that hit scrape_deadline_ms and kept partial results",
+            )
+            .expect("Failed to create jvm_exporter_scrape_timeouts_total Counter");
+            registry
+                .register(Box::new(scrape_timeouts.clone()))
+                .expect("Failed to register jvm_exporter_scrape_timeouts_total metric");
+
+            // Discovered processes and containers
+            let java_processes = Gauge::new(
+                "jvm_exporter_java_processes",
+                "Number of Java processes discovered on the host, in containers and from system_processes",
+            )
+            .expect("Failed to create jvm_exporter_java_processes Gauge");
+            registry
+                .register(Box::new(java_processes.clone()))
+                .expect("Failed to register jvm_exporter_java_processes metric");
+
+            let containers_scanned = Gauge::new(
+                "jvm_exporter_containers_scanned",
+                "Number of containers scanned for Java processes",
+            )
+            .expect("Failed to create jvm_exporter_containers_scanned Gauge");
+            registry
+                .register(Box::new(containers_scanned.clone()))
+                .expect("Failed to register jvm_exporter_containers_scanned metric");
+
+            // Whether jps, jstat and the container runtimes can be used, to tell why metrics are missing
+            let dependency_available = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_exporter_dependency_available",
+                    "Whether a tool the collection depends on is available, 1 or 0, on the host, in a container or on a remote host",
+                ),
+                &["dependency", "scope"],
+            )
+            .expect("Failed to create jvm_exporter_dependency_available GaugeVec");
+            registry
+                .register(Box::new(dependency_available.clone()))
+                .expect("Failed to register jvm_exporter_dependency_available metric");
+
+            // Where the JAVA_HOME of the JDK tools comes from, to tell which JDK is picked
+            let java_home_info = GaugeVec::new(
+                prometheus::Opts::new(
+                    "jvm_exporter_java_home_info",
+                    "JAVA_HOME the JDK tools are run from and the source it comes from, always 1",
+                ),
+                &["source", "path"],
+            )
+            .expect("Failed to create jvm_exporter_java_home_info GaugeVec");
+            registry
+                .register(Box::new(java_home_info.clone()))
+                .expect("Failed to register jvm_exporter_java_home_info metric");
+
+            // Time taken by each jstat run, to tell which commands make collections slow
+            let jstat_duration_seconds = HistogramVec::new(
+                prometheus::HistogramOpts::new(
+                    "jvm_exporter_jstat_duration_seconds",
+                    "Duration of jstat runs in seconds, including failed ones",
+                )
+                .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 1.5, 2.0, 3.0, 5.0, 10.0]),
+                &["command"],
+            )
+            .expect("Failed to create jvm_exporter_jstat_duration_seconds HistogramVec");
+            registry
+                .register(Box::new(jstat_duration_seconds.clone()))
+                .expect("Failed to register jvm_exporter_jstat_duration_seconds metric");
+
             SystemMetrics {
                 cpu_usage,
+                cpu_count,
                 memory_usage,
                 total_memory,
+                available_memory,
+                memory_usage_percentage,
+                cgroup_memory_limit,
                 disk_usage,
                 total_disk,
+                disk_inodes_total,
+                disk_inodes_used,
                 network_receive_bytes_per_sec,
                 network_transmit_bytes_per_sec,
+                network_errors,
+                network_drops,
                 uptime,
                 total_swap,
                 swap_usage,
                 open_file,
                 open_file_limit,
                 tcp_connection_states,
+                scrape_timeouts,
+                java_processes,
+                containers_scanned,
+                dependency_available,
+                java_home_info,
+                jstat_duration_seconds,
+            }
+        };
+
+        let container_metrics = {
+            // Container CPU quota
+            let cpu_quota = GaugeVec::new(
+                prometheus::Opts::new(
+                    "container_cpu_quota_cores",
+                    "CPU quota of the container's cgroup in cores",
+                ),
+                &["container"],
+            )
+            .expect("Failed to create container_cpu_quota_cores GaugeVec");
+            registry
+                .register(Box::new(cpu_quota.clone()))
+                .expect("Failed to register container_cpu_quota_cores metric");
+
+            // Container Memory Limit
+            let memory_limit = GaugeVec::new(
+                prometheus::Opts::new(
+                    "container_memory_limit_bytes",
+                    "Memory limit of the container's cgroup in bytes",
+                ),
+                &["container"],
+            )
+            .expect("Failed to create container_memory_limit_bytes GaugeVec");
+            registry
+                .register(Box::new(memory_limit.clone()))
+                .expect("Failed to register container_memory_limit_bytes metric");
+
+            ContainerMetrics {
+                cpu_quota,
+                memory_limit,
             }
         };
 
-        Metrics {
+        let metrics = Metrics {
+            aggregate_by_process,
+            tcp_address_family,
+            collect_system_metrics,
+            disabled_metrics,
             process_metrics,
             system_metrics,
+            container_metrics,
             active_pids: Mutex::new(HashMap::new()),
+            start_times: Mutex::new(HashMap::new()),
+            up_times: Mutex::new(HashMap::new()),
             jstat_labels: Mutex::new(HashMap::new()),
+            non_hotspot_pids: Mutex::new(HashSet::new()),
+            jstat_version_mismatches: Mutex::new(HashSet::new()),
+            jstat_failures: Mutex::new(HashMap::new()),
+            java_versions: Mutex::new(HashMap::new()),
+            process_args: Mutex::new(HashMap::new()),
+            process_args_retries: Mutex::new(HashMap::new()),
+            java_runtimes: Mutex::new(HashMap::new()),
+            jcmd_labels: Mutex::new(HashMap::new()),
+            nmt_disabled_pids: Mutex::new(HashSet::new()),
+            jolokia_labels: Mutex::new(HashMap::new()),
+            listening_ports: Mutex::new(HashMap::new()),
+            disk_io_totals: Mutex::new(HashMap::new()),
+            ctxt_switch_totals: Mutex::new(HashMap::new()),
+            page_fault_totals: Mutex::new(HashMap::new()),
+            detected_java_homes: Mutex::new(HashMap::new()),
+            gc_counts: Mutex::new(HashMap::new()),
+            gc_log_offsets: Mutex::new(HashMap::new()),
+            process_info_labels: Mutex::new(HashMap::new()),
+            process_states: Mutex::new(HashMap::new()),
+            gc_causes: Mutex::new(HashMap::new()),
+            containers: Mutex::new(Vec::new()),
+            denied_runtimes: Mutex::new(HashSet::new()),
+            system_process_patterns: Mutex::new(None),
+            runtime_outages: Mutex::new(HashMap::new()),
+            jps_ok: AtomicBool::new(false),
+            series_capped: AtomicBool::new(false),
+            series_fingerprint: AtomicU64::new(0),
+            collection: Mutex::new(()),
+            system: Mutex::new(System::new()),
             config,
+            runner: Arc::new(TokioCommandRunner),
+        };
+        let known_metrics: HashSet<String> = metrics
+            .all_collectors()
+            .iter()
+            .flat_map(|collector| collector.desc())
+            .map(|desc| desc.fq_name.clone())
+            .collect();
+        for name in metrics.disabled_metrics.difference(&known_metrics) {
+            warn!("Ignoring unknown metric {} in disabled_metrics", name);
+        }
+        metrics
+    }
+}
+impl Metrics {
+    // Every registered metric, used to describe them as a single collector
+    pub(crate) fn collectors(&self) -> Vec<&dyn Collector> {
+        self.all_collectors()
+            .into_iter()
+            .filter(|collector| {
+                collector
+                    .desc()
+                    .iter()
+                    .all(|desc| self.exports(&desc.fq_name))
+            })
+            .collect()
+    }
+
+    // Whether a metric is registered, which is not the case for disabled_metrics and, without
+    // collect_system_metrics, for the system_* metrics. Collection can skip work for the others.
+    pub(crate) fn exports(&self, name: &str) -> bool {
+        !self.disabled_metrics.contains(name)
+            && (self.collect_system_metrics || !name.starts_with("system_"))
+    }
+
+    // Whether JVMs can be discovered: jps succeeded at least once, or target_pids replaces it
+    pub(crate) fn is_ready(&self) -> bool {
+        self.jps_ok.load(Ordering::Relaxed) || self.config.read().unwrap().target_pids.is_some()
+    }
+
+    // Count the exported series against max_series after a collection. Over it, new PIDs and
+    // jstat columns are not added until enough series are removed again.
+    pub(crate) fn check_series_limit(&self) {
+        let max_series = self
+            .config
+            .read()
+            .unwrap()
+            .max_series
+            .unwrap_or(DEFAULT_MAX_SERIES);
+        let families: Vec<MetricFamily> = self
+            .collectors()
+            .iter()
+            .flat_map(|collector| collector.collect())
+            .collect();
+        let series: usize = families.iter().map(|f| f.get_metric().len()).sum();
+        let capped = series > max_series;
+        let was_capped = self.series_capped.swap(capped, Ordering::Relaxed);
+        if capped && !was_capped {
+            let (metrics, labels) = top_series(&families);
+            warn!(
+                "{} series exceed max_series {}, new PIDs and jstat columns are not added. Most series: {}; {}",
+                series, max_series, metrics, labels
+            );
+        } else if !capped && was_capped {
+            info!(
+                "{} series are within max_series {} again, new series are added",
+                series, max_series
+            );
+        }
+    }
+
+    fn all_collectors(&self) -> Vec<&dyn Collector> {
+        let p = &self.process_metrics;
+        let s = &self.system_metrics;
+        let c = &self.container_metrics;
+        let mut collectors: Vec<&dyn Collector> = vec![
+            &p.cpu_usage,
+            &p.cpu_usage_normalized,
+            &p.memory_usage,
+            &p.memory_usage_percentage,
+            &p.virtual_memory,
+            &p.disk_read_bytes,
+            &p.disk_write_bytes,
+            &p.voluntary_ctxt_switches,
+            &p.nonvoluntary_ctxt_switches,
+            &p.major_page_faults,
+            &p.minor_page_faults,
+            &p.start_time,
+            &p.up_time,
+            &p.open_file,
+            &p.open_file_limit,
+            &p.open_file_soft_limit,
+            &p.open_file_hard_limit,
+            &p.tcp_connection_states,
+            &p.listening_ports,
+            &p.info,
+            &p.version_info,
+            &p.state_info,
+            &p.last_gc_cause_info,
+            &p.up,
+            &p.jstat_version_mismatch,
+            &p.heap_used_percentage,
+            &p.gc_time_percentage,
+            &p.jstat_sample_timestamp,
+            &p.gc_young_per_sec,
+            &p.gc_full_per_sec,
+            &p.native_memory_reserved,
+            &p.native_memory_committed,
+            &p.code_cache_used,
+            &p.code_cache_max,
+            &p.threads,
+            &p.jolokia_mbean_value,
+            &p.gc_pause_seconds,
+            &p.heap_after_gc,
+            &s.cpu_usage,
+            &s.cpu_count,
+            &s.memory_usage,
+            &s.total_memory,
+            &s.available_memory,
+            &s.memory_usage_percentage,
+            &s.cgroup_memory_limit,
+            &s.disk_usage,
+            &s.total_disk,
+            &s.disk_inodes_total,
+            &s.disk_inodes_used,
+            &s.network_receive_bytes_per_sec,
+            &s.network_transmit_bytes_per_sec,
+            &s.network_errors,
+            &s.network_drops,
+            &s.uptime,
+            &s.total_swap,
+            &s.swap_usage,
+            &s.open_file,
+            &s.open_file_limit,
+            &s.tcp_connection_states,
+            &s.scrape_timeouts,
+            &s.java_processes,
+            &s.containers_scanned,
+            &s.dependency_available,
+            &s.java_home_info,
+            &s.jstat_duration_seconds,
+            &c.cpu_quota,
+            &c.memory_limit,
+        ];
+        collectors.extend(p.jstat_metrics_map.values().map(|m| m as &dyn Collector));
+        collectors.extend(p.jstat_gc_bytes_metrics.values().map(|m| m as &dyn Collector));
+        collectors.extend(
+            p.jstat_gcutil_percent_metrics
+                .values()
+                .map(|m| m as &dyn Collector),
+        );
+        collectors
+    }
+
+    // Label values of a per-process metric, matching process_label_names
+    pub(crate) fn process_label_values<'a>(
+        &self,
+        container: &'a str,
+        pid: &'a str,
+        process: &'a str,
+        extra: &[&'a str],
+    ) -> Vec<&'a str> {
+        let mut values = if self.aggregate_by_process {
+            vec![container, process]
+        } else {
+            vec![container, pid, process]
+        };
+        values.extend_from_slice(extra);
+        values
+    }
+}
+
+// Registers metrics except the ones listed in disabled_metrics, which are still created so
+// that collection does not have to check for them, but never exported
+#[derive(Clone, Copy)]
+struct MetricsRegistry<'a> {
+    registry: &'a Registry,
+    disabled: &'a HashSet<String>,
+}
+
+impl MetricsRegistry<'_> {
+    fn register(&self, collector: Box<dyn Collector>) -> prometheus::Result<()> {
+        if collector
+            .desc()
+            .iter()
+            .any(|desc| self.disabled.contains(&desc.fq_name))
+        {
+            return Ok(());
+        }
+        self.registry.register(collector)
+    }
+}
+
+// Label names of a per-process metric, pid is dropped when aggregating by process
+fn process_label_names(aggregate_by_process: bool, extra: &[&'static str]) -> Vec<&'static str> {
+    let mut names = if aggregate_by_process {
+        vec!["container", "process_name"]
+    } else {
+        vec!["container", "pid", "process_name"]
+    };
+    names.extend_from_slice(extra);
+    names
+}
+
+// The metrics, and the container and process_name labels, with the most series
+fn top_series(families: &[MetricFamily]) -> (String, String) {
+    let mut by_metric: HashMap<String, usize> = HashMap::new();
+    let mut by_label: HashMap<String, usize> = HashMap::new();
+    for family in families {
+        *by_metric.entry(family.get_name().to_string()).or_default() += family.get_metric().len();
+        for metric in family.get_metric() {
+            for label in metric.get_label() {
+                if label.get_name() == "container" || label.get_name() == "process_name" {
+                    let key = format!("{}=\"{}\"", label.get_name(), label.get_value());
+                    *by_label.entry(key).or_default() += 1;
+                }
+            }
+        }
+    }
+    (top_counts(by_metric), top_counts(by_label))
+}
+
+// The 5 highest counts as "key (count)"
+fn top_counts(counts: HashMap<String, usize>) -> String {
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+        .iter()
+        .take(5)
+        .map(|(key, count)| format!("{} ({})", key, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Values of per-process gauges collected during one update. Series that end up with the
+// same labels, which only happens when the pid label is dropped, are combined instead of
+// overwriting each other.
+#[derive(Default)]
+pub(crate) struct GaugeUpdates<'a> {
+    values: HashMap<(usize, Vec<String>), (&'a GaugeVec, f64)>,
+}
+
+impl<'a> GaugeUpdates<'a> {
+    pub(crate) fn sum(&mut self, gauge: &'a GaugeVec, labels: &[&str], value: f64) {
+        self.combine(gauge, labels, value, |a, b| a + b);
+    }
+
+    pub(crate) fn max(&mut self, gauge: &'a GaugeVec, labels: &[&str], value: f64) {
+        self.combine(gauge, labels, value, f64::max);
+    }
+
+    pub(crate) fn min(&mut self, gauge: &'a GaugeVec, labels: &[&str], value: f64) {
+        self.combine(gauge, labels, value, f64::min);
+    }
+
+    fn combine(
+        &mut self,
+        gauge: &'a GaugeVec,
+        labels: &[&str],
+        value: f64,
+        combine: fn(f64, f64) -> f64,
+    ) {
+        let key = (
+            gauge as *const GaugeVec as usize,
+            labels.iter().map(|l| l.to_string()).collect(),
+        );
+        self.values
+            .entry(key)
+            .and_modify(|(_, current)| *current = combine(*current, value))
+            .or_insert((gauge, value));
+    }
+
+    pub(crate) fn apply(self) {
+        for ((_, labels), (gauge, value)) in self.values {
+            let labels: Vec<&str> = labels.iter().map(|l| l.as_str()).collect();
+            gauge.with_label_values(&labels).set(value);
         }
     }
 }
+
+// Consecutive jstat failures of a process, jstat is skipped until skip_until once they reach the threshold
+#[derive(Default)]
+pub(crate) struct JstatFailures {
+    pub(crate) consecutive: u32,
+    pub(crate) skip_until: Option<Instant>,
+}
+
 pub struct ProcessInfo {
-    pub(crate) container: String, // "host" or container ID
+    pub(crate) container: String, // "host", container ID or remote host
+    pub(crate) runtime: Option<&'static str>, // Container runtime command, None on the host
+    pub(crate) image: Option<String>, // Image of the container, None on the host
+    pub(crate) container_name: Option<String>, // Name of the container, None on the host
     pub(crate) pid: String,
     pub(crate) process: String,
+    pub(crate) user: Option<String>, // Owner of host processes
+    pub(crate) runner: Option<Arc<dyn CommandRunner>>, // Runs JDK tools for remote and container processes, Metrics::runner otherwise
+    pub(crate) java_home: Option<String>, // JDK the JVM runs from, detected when no JAVA_HOME is configured for it
+    // Kubernetes pod of crictl containers, None elsewhere
+    pub(crate) pod: Option<Pod>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Container {
+    pub(crate) id: String,
+    pub(crate) runtime: &'static str, // "docker", "crictl" or "podman"
+    pub(crate) name: Option<String>,  // Name given to the container, e.g. by docker run --name
+    pub(crate) image: Option<String>, // Image reference such as "repo:tag"
+    // Kubernetes pod, for crictl containers
+    pub(crate) pod: Option<Pod>,
+}
+
+// Kubernetes pod of a container, from the io.kubernetes.pod.* labels the kubelet sets on it
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Pod {
+    pub(crate) name: String,
+    pub(crate) namespace: String,
+    pub(crate) uid: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_disabled_metrics_unregistered() {
+        let registry = Registry::new();
+        let config = Config {
+            disabled_metrics: Some(vec![
+                "process_open_file".to_string(),
+                "jstat_class_metrics".to_string(),
+                "no_such_metric".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let metrics = Metrics::new(&registry, Arc::new(RwLock::new(config)));
+        let labels = ["host", "1", "Main"];
+        metrics
+            .process_metrics
+            .open_file
+            .with_label_values(&labels)
+            .set(10.0);
+        metrics
+            .process_metrics
+            .cpu_usage
+            .with_label_values(&labels)
+            .set(1.0);
+
+        let names: Vec<String> = registry
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        assert!(names.contains(&"process_cpu_usage".to_string()));
+        assert!(!names.contains(&"process_open_file".to_string()));
+        assert!(!metrics.exports("jstat_class_metrics"));
+        assert!(metrics.exports("jstat_gc_metrics"));
+        assert!(metrics
+            .collectors()
+            .iter()
+            .all(|collector| collector.desc()[0].fq_name != "process_open_file"));
+    }
 }
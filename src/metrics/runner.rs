@@ -0,0 +1,186 @@
+use crate::config::RemoteHost;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Output;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+
+// Runtime name of processes on remote hosts, reached through SshCommandRunner
+pub(crate) const SSH_RUNTIME: &str = "ssh";
+
+pub type CommandFuture = Pin<Box<dyn Future<Output = std::io::Result<Output>> + Send>>;
+
+/// Runs the external commands the collection relies on: jps, jstat, jcmd and the container
+/// runtimes. Tests can supply an implementation returning canned output, the program and
+/// arguments are available through `Command::as_std`.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, command: Command) -> CommandFuture;
+}
+
+/// Runs commands as subprocesses, the default.
+#[derive(Default)]
+pub struct TokioCommandRunner;
+
+impl CommandRunner for TokioCommandRunner {
+    fn run(&self, mut command: Command) -> CommandFuture {
+        // Commands that are given up on, e.g. at scrape_deadline_ms, do not outlive their future
+        command.kill_on_drop(true);
+        Box::pin(async move { command.output().await })
+    }
+}
+
+// Runs commands on a remote host over ssh, like docker exec does for containers.
+// BatchMode makes ssh fail instead of prompting when the key is not accepted.
+pub(crate) struct SshCommandRunner {
+    remote: RemoteHost,
+    local: Arc<dyn CommandRunner>,
+}
+
+impl SshCommandRunner {
+    pub(crate) fn new(remote: RemoteHost, local: Arc<dyn CommandRunner>) -> Self {
+        SshCommandRunner { remote, local }
+    }
+
+    // The ssh command running `command` on the remote host. Environment variables are not
+    // forwarded, JDK tools are found through the remote java_home or the remote PATH.
+    fn ssh_command(&self, command: &Command) -> Command {
+        let command = command.as_std();
+        let mut program = command.get_program().to_string_lossy().into_owned();
+        if let Some(java_home) = &self.remote.java_home {
+            if !program.contains('/') {
+                program = format!("{}/bin/{}", java_home, program);
+            }
+        }
+        // ssh joins its arguments into a command line for the remote shell
+        let remote_command = std::iter::once(program)
+            .chain(
+                command
+                    .get_args()
+                    .map(|arg| arg.to_string_lossy().into_owned()),
+            )
+            .map(|arg| shell_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut ssh = Command::new("ssh");
+        ssh.args(["-o", "BatchMode=yes", "-o", "ConnectTimeout=10"]);
+        if let Some(key) = &self.remote.key {
+            ssh.arg("-i").arg(key);
+        }
+        if let Some(port) = self.remote.port {
+            ssh.arg("-p").arg(port.to_string());
+        }
+        let destination = match &self.remote.user {
+            Some(user) => format!("{}@{}", user, self.remote.host),
+            None => self.remote.host.clone(),
+        };
+        // -- ends the options, so neither the destination nor the command is read as one
+        ssh.arg("--").arg(destination).arg(remote_command);
+        ssh
+    }
+}
+
+impl CommandRunner for SshCommandRunner {
+    fn run(&self, command: Command) -> CommandFuture {
+        if let Some(e) = ssh_destination_error(&self.remote) {
+            return Box::pin(async move {
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+            });
+        }
+        self.local.run(self.ssh_command(&command))
+    }
+}
+
+// What is wrong with the host or user of a remote host. ssh would read a value starting with
+// '-' as an option, e.g. -oProxyCommand=... running a local command, and whitespace or control
+// characters are never part of a host or user name.
+pub(crate) fn ssh_destination_error(remote: &RemoteHost) -> Option<String> {
+    let invalid = |value: &str| {
+        value.is_empty()
+            || value.starts_with('-')
+            || value.chars().any(|c| c.is_whitespace() || c.is_control())
+    };
+    if invalid(&remote.host) {
+        return Some(format!("invalid host '{}'", remote.host.escape_debug()));
+    }
+    match &remote.user {
+        Some(user) if invalid(user) => Some(format!(
+            "invalid user '{}' for host {}",
+            user.escape_debug(),
+            remote.host
+        )),
+        _ => None,
+    }
+}
+
+// Quote an argument for a POSIX shell, unless it only has characters the shell leaves alone
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// Runs commands through `sudo -n`, for a docker daemon the exporter user cannot access.
+// -n makes sudo fail instead of prompting when a password would be required.
+pub(crate) struct SudoCommandRunner {
+    local: Arc<dyn CommandRunner>,
+}
+
+impl SudoCommandRunner {
+    pub(crate) fn new(local: Arc<dyn CommandRunner>) -> Self {
+        SudoCommandRunner { local }
+    }
+}
+
+impl CommandRunner for SudoCommandRunner {
+    fn run(&self, command: Command) -> CommandFuture {
+        let command = command.as_std();
+        let mut sudo = Command::new("sudo");
+        sudo.args(["-n", "--"])
+            .arg(command.get_program())
+            .args(command.get_args());
+        self.local.run(sudo)
+    }
+}
+
+// Fails commands that run longer than the timeout with a TimedOut error, used for the commands
+// run in containers with container_exec_timeout_ms
+pub(crate) struct TimeoutCommandRunner {
+    local: Arc<dyn CommandRunner>,
+    timeout: Duration,
+}
+
+impl TimeoutCommandRunner {
+    pub(crate) fn new(local: Arc<dyn CommandRunner>, timeout: Duration) -> Self {
+        TimeoutCommandRunner { local, timeout }
+    }
+}
+
+impl CommandRunner for TimeoutCommandRunner {
+    fn run(&self, command: Command) -> CommandFuture {
+        let program = command
+            .as_std()
+            .get_program()
+            .to_string_lossy()
+            .into_owned();
+        let timeout = self.timeout;
+        let output = self.local.run(command);
+        Box::pin(async move {
+            tokio::time::timeout(timeout, output)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("{} timed out after {}ms", program, timeout.as_millis()),
+                    ))
+                })
+        })
+    }
+}
@@ -1,11 +1,83 @@
-use crate::metrics::collect::Metrics;
-use std::sync::Arc;
-use std::time::Duration;
-use sysinfo::{Networks, System};
+use crate::config::{Config, DEFAULT_COLLECTION_INTERVAL_SECS, DEFAULT_CONTAINER_LIST_TTL_SECS};
+use crate::metrics::collect::{
+    counter_delta, refresh_container_list, update_metrics, write_textfile, Metrics,
+};
+use crate::metrics::gclog::update_gc_log_metrics;
+use log::error;
+use prometheus::{CounterVec, Registry};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use sysinfo::{CpuRefreshKind, Networks, RefreshKind, System};
 use tokio::time::interval;
 
-pub fn run(metrics: Arc<Metrics>) {
+const GC_LOG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn run(metrics: Arc<Metrics>, java_home: Arc<Option<String>>, full_path: bool) {
     let metrics = metrics.clone();
+    // Collect process and system metrics in the background, /metrics serves the latest snapshot
+    tokio::spawn({
+        let metrics = Arc::clone(&metrics);
+        async move {
+            loop {
+                let started = Instant::now();
+                if let Err(err) =
+                    update_metrics(Arc::clone(&metrics), java_home.as_deref(), full_path).await
+                {
+                    error!("Failed to update metrics: {}", err);
+                    // Discovery failed, e.g. jps is broken, so no process is known
+                    metrics.system_metrics.java_processes.set(0.0);
+                }
+
+                let interval_secs = metrics
+                    .config
+                    .read()
+                    .unwrap()
+                    .collection_interval_secs
+                    .unwrap_or(DEFAULT_COLLECTION_INTERVAL_SECS);
+                let interval = Duration::from_secs(interval_secs.max(1));
+                tokio::time::sleep(interval.saturating_sub(started.elapsed())).await;
+            }
+        }
+    });
+    tokio::spawn({
+        let metrics = Arc::clone(&metrics);
+        async move {
+            loop {
+                let (detect_containers, ttl_secs) = {
+                    let config = metrics.config.read().unwrap();
+                    (
+                        config.detects_containers(),
+                        config
+                            .container_list_ttl_secs
+                            .unwrap_or(DEFAULT_CONTAINER_LIST_TTL_SECS),
+                    )
+                };
+                if detect_containers {
+                    refresh_container_list(&metrics).await;
+                }
+                tokio::time::sleep(Duration::from_secs(ttl_secs.max(1))).await;
+            }
+        }
+    });
+    // Tail GC log files configured with gc_log_paths
+    tokio::spawn({
+        let metrics = Arc::clone(&metrics);
+        async move {
+            let mut first_run = true;
+            loop {
+                if update_gc_log_metrics(&metrics, first_run).await {
+                    first_run = false;
+                }
+                tokio::time::sleep(GC_LOG_POLL_INTERVAL).await;
+            }
+        }
+    });
+    // System CPU and network usage, left to node_exporter without collect_system_metrics
+    if !metrics.collect_system_metrics {
+        return;
+    }
     tokio::spawn({
         let metrics = Arc::clone(&metrics);
         move || {
@@ -13,6 +85,9 @@ pub fn run(metrics: Arc<Metrics>) {
             async move {
                 let mut network_task_interval = interval(Duration::from_millis(3000));
                 let mut cpu_task_interval = interval(Duration::from_millis(10000));
+                // Last cumulative totals by (interface, direction)
+                let mut network_error_totals = HashMap::new();
+                let mut network_drop_totals = HashMap::new();
 
                 loop {
                     tokio::select! {
@@ -34,21 +109,82 @@ pub fn run(metrics: Arc<Metrics>) {
                                     .network_transmit_bytes_per_sec
                                     .with_label_values(&[interface_name])
                                     .set(transmitted);
+
+                                let errors = [
+                                    ("receive", data.total_errors_on_received()),
+                                    ("transmit", data.total_errors_on_transmitted()),
+                                ];
+                                for (direction, total) in errors {
+                                    inc_network_counter(
+                                        &metrics.system_metrics.network_errors,
+                                        &mut network_error_totals,
+                                        interface_name,
+                                        direction,
+                                        total,
+                                    );
+                                }
+                                for (direction, total) in network_drops(interface_name) {
+                                    inc_network_counter(
+                                        &metrics.system_metrics.network_drops,
+                                        &mut network_drop_totals,
+                                        interface_name,
+                                        direction,
+                                        total,
+                                    );
+                                }
                             }
                         },
                         _ = cpu_task_interval.tick() => {
-                            let mut system = System::new_all();
-                            
+                            // CPUs only, processes are enumerated by the collection
+                            let mut system = System::new_with_specifics(
+                                RefreshKind::nothing().with_cpu(CpuRefreshKind::everything()),
+                            );
                             tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
                             system.refresh_cpu_all();
-                            // Update CPU usage
-                            for (i, processor) in system.cpus().iter().enumerate() {
+                            let per_core_cpu = metrics
+                                .config
+                                .read()
+                                .unwrap()
+                                .per_core_cpu
+                                .unwrap_or(true);
+                            // Update CPU usage, per core or as the range across cores
+                            let usages: Vec<f64> =
+                                system.cpus().iter().map(|c| c.cpu_usage() as f64).collect();
+                            for (i, usage) in usages.iter().enumerate() {
                                 let cpu_label = format!("cpu_{}", i);
+                                let cpu_usage = &metrics.system_metrics.cpu_usage;
+                                if per_core_cpu {
+                                    cpu_usage.with_label_values(&[&cpu_label]).set(*usage);
+                                } else {
+                                    let _ = cpu_usage.remove_label_values(&[&cpu_label]);
+                                }
+                            }
+                            for (label, usage) in cpu_usage_range(&usages) {
+                                let cpu_usage = &metrics.system_metrics.cpu_usage;
+                                if per_core_cpu {
+                                    let _ = cpu_usage.remove_label_values(&[label]);
+                                } else {
+                                    cpu_usage.with_label_values(&[label]).set(usage);
+                                }
+                            }
+                            metrics
+                                .system_metrics
+                                .cpu_usage
+                                .with_label_values(&["total"])
+                                .set(system.global_cpu_usage() as f64);
+
+                            // Update CPU count
+                            metrics
+                                .system_metrics
+                                .cpu_count
+                                .with_label_values(&["logical"])
+                                .set(system.cpus().len() as f64);
+                            if let Some(physical) = System::physical_core_count() {
                                 metrics
                                     .system_metrics
-                                    .cpu_usage
-                                    .with_label_values(&[&cpu_label])
-                                    .set(processor.cpu_usage() as f64);
+                                    .cpu_count
+                                    .with_label_values(&["physical"])
+                                    .set(physical as f64);
                             }
                         }
                     }
@@ -57,3 +193,64 @@ pub fn run(metrics: Arc<Metrics>) {
         }
     }());
 }
+
+// Write the registry to textfile_output_dir every collection interval
+pub fn run_textfile(registry: Arc<Registry>, dir: PathBuf, config: Arc<RwLock<Config>>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = write_textfile(&registry, &dir) {
+                error!("Failed to write metrics to {}: {}", dir.display(), e);
+            }
+            let interval_secs = config
+                .read()
+                .unwrap()
+                .collection_interval_secs
+                .unwrap_or(DEFAULT_COLLECTION_INTERVAL_SECS);
+            tokio::time::sleep(Duration::from_secs(interval_secs.max(1))).await;
+        }
+    });
+}
+
+// Lowest and highest usage across cores, reported instead of each core without per_core_cpu
+fn cpu_usage_range(usages: &[f64]) -> Vec<(&'static str, f64)> {
+    if usages.is_empty() {
+        return Vec::new();
+    }
+    let min = usages.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = usages.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    vec![("min", min), ("max", max)]
+}
+
+// Increase the counter of an interface by the change of a cumulative total since the last sample
+fn inc_network_counter(
+    counter: &CounterVec,
+    totals: &mut HashMap<(String, &'static str), u64>,
+    interface: &str,
+    direction: &'static str,
+    total: u64,
+) {
+    let last = totals
+        .insert((interface.to_string(), direction), total)
+        .unwrap_or(0);
+    counter
+        .with_label_values(&[interface, direction])
+        .inc_by(counter_delta(last, total) as f64);
+}
+
+// Dropped packets of an interface by direction, which sysinfo does not report
+#[cfg(target_os = "linux")]
+fn network_drops(interface: &str) -> Vec<(&'static str, u64)> {
+    [("receive", "rx_dropped"), ("transmit", "tx_dropped")]
+        .into_iter()
+        .filter_map(|(direction, file)| {
+            let path = format!("/sys/class/net/{}/statistics/{}", interface, file);
+            let total = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+            Some((direction, total))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn network_drops(_interface: &str) -> Vec<(&'static str, u64)> {
+    Vec::new()
+}
@@ -1,40 +1,155 @@
-use crate::config::{fetch_and_merge_config, Config};
-use crate::routes::setup_routes;
-use clap::{App, Arg};
+use crate::config::{fetch_and_merge_config, Config, LogFormat, DEFAULT_WORKER_THREADS};
+use crate::metrics::collect::system_process_pattern_errors;
+use crate::metrics::gclog::gc_pause_buckets_error;
+use crate::metrics::metrics::JSTAT_OPTIONAL_COMMANDS;
+use crate::metrics::runner::ssh_destination_error;
+use crate::metrics::timer::run_textfile;
+use crate::routes::{metrics_registry, setup_routes};
+use clap::{App, Arg, ArgMatches};
 use env_logger::Env;
+#[cfg(unix)]
+use log::{error, info};
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
-#[tokio::main]
-pub(crate) async fn main() {
-    let mut config =
-        Config::new("/usr/local/jvm-exporter/config.yaml").unwrap_or_else(|_| Config {
-            log_level: None,
-            java_home: None,
-            configuration_service_url: None,
-            system_processes: None,
-            detect_docker_processes: None,
-        });
+const CONFIG_PATH: &str = "/usr/local/jvm-exporter/config.yaml";
+const DEFAULT_PORT: u16 = 29090;
+const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:29090";
+// Mode of the unix socket, read and write for its owner and group
+#[cfg(unix)]
+const UNIX_SOCKET_MODE: u32 = 0o660;
 
-    let configuration_service_url = config.configuration_service_url.clone();
-    if let Some(configuration_service_url) = configuration_service_url {
-        if let Err(e) = fetch_and_merge_config(&configuration_service_url, &mut config).await {
-            eprintln!(
-                "Failed to fetch configuration from configuration service: {}",
-                e
-            );
+pub(crate) fn main() {
+    let matches = cli().get_matches();
+    if matches.is_present("check_config") {
+        let path = matches.value_of("check_config").unwrap_or(CONFIG_PATH);
+        let errors = check_config(path, matches.value_of("listen_address"));
+        if errors.is_empty() {
+            println!("OK");
+            std::process::exit(0);
+        }
+        for error in &errors {
+            eprintln!("{}", error);
         }
+        std::process::exit(1);
     }
 
-    let log_level = config
-        .log_level
-        .clone()
-        .unwrap_or_else(|| "info,warp=info".to_string());
-    env_logger::Builder::from_env(Env::default().default_filter_or(&log_level)).init();
+    let config = Config::new(CONFIG_PATH).unwrap_or_else(|_| Config::default());
 
-    let matches = App::new("jvm-exporter")
+    // A small runtime is enough for an exporter and leaves the cores to the monitored JVMs.
+    // The runtime exists before the configuration service is queried, so only the local file sets it.
+    let worker_threads = config
+        .worker_threads
+        .unwrap_or(DEFAULT_WORKER_THREADS)
+        .max(1);
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start the tokio runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(serve(config, matches));
+}
+
+// Load a configuration file like the server does at startup and return what is wrong with it
+fn check_config(path: &str, listen_address: Option<&str>) -> Vec<String> {
+    let config = match Config::new(path) {
+        Ok(config) => config,
+        Err(e) => return vec![format!("{}: {}", path, e)],
+    };
+    let mut errors: Vec<String> = system_process_pattern_errors(
+        config.system_processes.as_deref().unwrap_or_default(),
+        config.system_process_match_type.unwrap_or_default(),
+    )
+    .into_iter()
+    .map(|e| format!("system_processes: {}", e))
+    .collect();
+    // serde ignores unknown keys, a misspelled one would silently leave its setting unset
+    let content = fs::read_to_string(path).unwrap_or_default();
+    for key in unknown_config_keys(&content) {
+        errors.push(format!("unknown configuration key '{}'", key));
+    }
+    if let Some(e) = config
+        .gc_pause_buckets
+        .as_deref()
+        .and_then(gc_pause_buckets_error)
+    {
+        errors.push(format!("gc_pause_buckets: {}", e));
+    }
+    for command in config.jstat_extra_commands.iter().flatten() {
+        if !JSTAT_OPTIONAL_COMMANDS.contains(&command.as_str()) {
+            errors.push(format!(
+                "jstat_extra_commands: {} is not one of {}",
+                command,
+                JSTAT_OPTIONAL_COMMANDS.join(", ")
+            ));
+        }
+    }
+    for remote in config.remote_hosts.iter().flatten() {
+        if let Some(e) = ssh_destination_error(remote) {
+            errors.push(format!("remote_hosts: {}", e));
+        }
+    }
+    if let Some(listen_address) = listen_address.or(config.listen_address.as_deref()) {
+        if let Err(e) = parse_listen_address(listen_address) {
+            errors.push(format!(
+                "invalid listen address '{}': {}",
+                listen_address, e
+            ));
+        }
+    }
+    errors
+}
+
+// Top-level keys of a configuration file that are not configuration options
+fn unknown_config_keys(content: &str) -> Vec<String> {
+    let schema = schemars::schema_for!(Config);
+    let Some(object) = schema.schema.object.as_ref() else {
+        return Vec::new();
+    };
+    let Ok(serde_yaml::Value::Mapping(mapping)) = serde_yaml::from_str(content) else {
+        return Vec::new();
+    };
+    mapping
+        .keys()
+        .filter_map(|key| key.as_str())
+        .filter(|key| !object.properties.contains_key(*key))
+        .map(|key| key.to_string())
+        .collect()
+}
+
+// JAVA_HOME of the JDK tools and where it comes from: --java-home, then java_home of the
+// configuration service, then java_home of the configuration file. Without any, each JVM's own
+// JDK is used, the JAVA_HOME environment variable is not. It is a host path, containers only use
+// container_java_homes, see process_java_home.
+fn resolve_java_home(
+    cli: Option<&str>,
+    file: Option<&str>,
+    config: &Config,
+) -> (&'static str, Option<String>) {
+    if let Some(java_home) = cli {
+        return ("cli", Some(java_home.to_string()));
+    }
+    match config.java_home.as_deref() {
+        Some(java_home) if Some(java_home) != file => {
+            ("remote_config", Some(java_home.to_string()))
+        }
+        Some(java_home) => ("config", Some(java_home.to_string())),
+        None => ("detected", None),
+    }
+}
+
+// Command-line arguments of the exporter
+fn cli() -> App<'static> {
+    App::new("jvm-exporter")
         .version("0.3.6")
         .author("tf1997")
         .about("Monitor the JVM, cpu and memory metrics of process and the system cpu, disk, network and memory metrics.")
@@ -51,107 +166,518 @@ pub(crate) async fn main() {
                 .help("Only use class name instead of full package path in the process name")
                 .takes_value(false),
         )
+        .arg(
+            Arg::new("listen_address")
+                .long("listen-address")
+                .value_name("ADDRESS")
+                .help("Address to listen on, e.g. 0.0.0.0, :: or [::]:29090 (default 0.0.0.0:29090)")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("auto_start")
                 .long("auto-start")
                 .help("Configure the program to auto-start with the system"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("check_config")
+                .long("check-config")
+                .value_name("FILE")
+                .help("Validate a configuration file, the installed one by default, print OK or its errors and exit")
+                .takes_value(true)
+                .min_values(0),
+        )
+        .arg(
+            Arg::new("service_user")
+                .long("service-user")
+                .value_name("USER")
+                .help("User running the service installed by --auto-start (default root)")
+                .takes_value(true),
+        )
+}
+
+// One JSON object per line with the timestamp, level, target and message of the record
+fn write_json_log(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    let line = serde_json::json!({
+        "timestamp": buf.timestamp_millis().to_string(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    writeln!(buf, "{}", line)
+}
+
+async fn serve(mut config: Config, matches: ArgMatches) {
+    let file_java_home = config.java_home.clone();
+    let configuration_service_url = config.configuration_service_url.clone();
+    if let Some(configuration_service_url) = configuration_service_url {
+        if let Err(e) = fetch_and_merge_config(&configuration_service_url, &mut config).await {
+            eprintln!(
+                "Failed to fetch configuration from configuration service: {}",
+                e
+            );
+        }
+    }
 
-    let java_home = matches.value_of("java_home").map(|s| s.to_string());
+    let log_level = config
+        .log_level
+        .clone()
+        .unwrap_or_else(|| "info,warp=info".to_string());
+    let mut logger = env_logger::Builder::from_env(Env::default().default_filter_or(&log_level));
+    if config.log_format.unwrap_or_default() == LogFormat::Json {
+        logger.format(write_json_log);
+    }
+    logger.init();
+
+    let cli_java_home = matches.value_of("java_home").map(|s| s.to_string());
+    let (java_home_source, java_home) =
+        resolve_java_home(cli_java_home.as_deref(), file_java_home.as_deref(), &config);
+    match &java_home {
+        Some(java_home) => println!("Using JAVA_HOME {} from {}", java_home, java_home_source),
+        None => println!("No JAVA_HOME configured, running the JDK tools of each JVM's own JDK"),
+    }
+    let listen_address = matches
+        .value_of("listen_address")
+        .map(|s| s.to_string())
+        .or_else(|| config.listen_address.clone());
+    let unix_socket_path = unix_socket_path(&config);
+    // With a unix socket or a textfile, the TCP port is only opened when a listen address is
+    // given explicitly
+    let listen_address = match listen_address {
+        Some(listen_address) => Some(listen_address),
+        None if unix_socket_path.is_some() || config.textfile_output_dir.is_some() => None,
+        None => Some(DEFAULT_LISTEN_ADDRESS.to_string()),
+    };
+    let addr = listen_address.map(
+        |listen_address| match parse_listen_address(&listen_address) {
+            Ok(addr) => addr,
+            Err(e) => {
+                eprintln!("Invalid listen address '{}': {}", listen_address, e);
+                std::process::exit(1);
+            }
+        },
+    );
     let full_path = matches.is_present("full_path");
     let auto_start = matches.is_present("auto_start");
     if auto_start {
-        match configure_auto_start() {
+        // The service runs the exporter with the same options as this command line
+        let service = ServiceSettings {
+            user: matches
+                .value_of("service_user")
+                .unwrap_or("root")
+                .to_string(),
+            java_home: cli_java_home.clone(),
+            listen_address: matches.value_of("listen_address").map(|s| s.to_string()),
+            full_path,
+        };
+        match configure_auto_start(&service) {
             Ok(_) => println!("Auto-start configuration successful."),
             Err(e) => eprintln!("Failed to configure auto-start: {}", e),
         }
     }
 
+    let metrics_path = config.metrics_path();
+    let run_as_user = config.run_as_user.clone();
+    let run_as_group = config.run_as_group.clone();
+    let textfile_output_dir = config.textfile_output_dir.clone();
     let config = Arc::new(RwLock::new(config));
+    #[cfg(unix)]
+    reload_config_on_sighup(config.clone());
 
     // Encapsulate shared data into Arc
     let java_home = Arc::new(java_home);
 
-    let addr = ([0, 0, 0, 0], 29090);
-    let ip_addr = std::net::Ipv4Addr::from(addr.0);
-    let routes = setup_routes(java_home, full_path, config.clone());
-    let server = warp::serve(routes).bind((ip_addr, addr.1));
-    let server_handle = tokio::spawn(server);
+    let (registry, metrics) =
+        metrics_registry(java_home, java_home_source, full_path, config.clone());
+    if let Some(dir) = &textfile_output_dir {
+        println!("Writing metrics to {}", dir);
+        run_textfile(registry.clone(), PathBuf::from(dir), config.clone());
+    }
+    let routes = setup_routes(registry, metrics, config.clone());
+    // Bind before reporting success, so that a port already in use makes the service manager see a failure
+    let tcp_server_handle = addr.map(|addr| {
+        let (addr, server) = match warp::serve(routes.clone()).try_bind_ephemeral(addr) {
+            Ok(bound) => bound,
+            Err(e) => {
+                eprintln!("Failed to bind to {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        };
+        println!("Listening on http://{}/{}", addr, metrics_path);
+        tokio::spawn(server)
+    });
 
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    #[cfg(unix)]
+    let unix_server_handle = unix_socket_path.as_deref().map(|path| {
+        remove_stale_socket(path);
+        let listener = match tokio::net::UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Failed to bind to unix socket {}: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        // Set after binding, the mode of the new socket file depends on the umask
+        if let Err(e) = set_socket_mode(path) {
+            eprintln!("Failed to set the mode of unix socket {}: {}", path, e);
+            let _ = fs::remove_file(path);
+            std::process::exit(1);
+        }
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let connection = listener.accept().await.map(|(stream, _)| stream);
+            Some((connection, listener))
+        });
+        println!("Listening on unix:{} at /{}", path, metrics_path);
+        tokio::spawn(warp::serve(routes.clone()).serve_incoming(incoming))
+    });
+    #[cfg(not(unix))]
+    let unix_server_handle: Option<tokio::task::JoinHandle<()>> = None;
+
+    // The bound sockets stay usable after giving up root
+    if let Err(e) = drop_privileges(run_as_user.as_deref(), run_as_group.as_deref()) {
+        eprintln!("Failed to drop privileges: {}", e);
+        if let Some(path) = &unix_socket_path {
+            let _ = fs::remove_file(path);
+        }
+        std::process::exit(1);
+    }
 
     println!("Server started successfully");
-    println!("Listening on http://{}:{}/metrics", "127.0.0.1", addr.1);
 
+    let mut exit_code = 0;
     tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            println!("Received Ctrl+C, shutting down.");
+        _ = shutdown_signal() => {
+            println!("Received shutdown signal, shutting down.");
+        },
+        res = wait_for_server(tcp_server_handle) => {
+            if let Err(e) = res {
+                eprintln!("Server error: {}", e);
+                exit_code = 1;
+            }
         },
-        res = server_handle => {
+        res = wait_for_server(unix_server_handle) => {
             if let Err(e) = res {
                 eprintln!("Server error: {}", e);
+                exit_code = 1;
             }
         },
     }
+
+    // Without root the socket may not be removable, the next start removes it before binding
+    if let Some(path) = &unix_socket_path {
+        if let Err(e) = fs::remove_file(path) {
+            eprintln!("Failed to remove unix socket {}: {}", path, e);
+        }
+    }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
 }
 
-fn configure_auto_start() -> Result<(), Box<dyn std::error::Error>> {
-    let service_path = "/etc/systemd/system/jvm-exporter.service";
-    let binary_target_dir = "/usr/local/bin";
-    let binary_target_path = format!("{}/jvm-exporter", binary_target_dir);
+// Path of the unix socket to serve on, unix sockets are not supported on other platforms
+#[cfg(unix)]
+fn unix_socket_path(config: &Config) -> Option<String> {
+    config.unix_socket_path.clone()
+}
 
-    let current_executable_path = std::env::current_exe()?;
-    println!(
-        "Current executable path: {}",
-        current_executable_path.display()
-    );
+#[cfg(not(unix))]
+fn unix_socket_path(config: &Config) -> Option<String> {
+    if config.unix_socket_path.is_some() {
+        eprintln!("unix_socket_path is only supported on Unix, ignoring it");
+    }
+    None
+}
 
-    if !Path::new(binary_target_dir).exists() {
-        fs::create_dir_all(binary_target_dir)?;
-        println!("Target directory created: {}", binary_target_dir);
+// Switch to run_as_group and run_as_user, so that the exporter does not keep root for its lifetime.
+// The group is changed first, root is needed for it. Numeric IDs work without a passwd or group entry.
+#[cfg(unix)]
+fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use nix::unistd::{setgid, setuid, Gid, Group, Uid, User};
+
+    let user = match user {
+        Some(user) => Some(match user.parse::<u32>() {
+            Ok(uid) => (Uid::from_raw(uid), User::from_uid(Uid::from_raw(uid))?),
+            Err(_) => {
+                let entry =
+                    User::from_name(user)?.ok_or_else(|| format!("unknown user {}", user))?;
+                (entry.uid, Some(entry))
+            }
+        }),
+        None => None,
+    };
+    let entry = user.as_ref().and_then(|(_, entry)| entry.as_ref());
+    let gid = match group {
+        Some(group) => Some(match group.parse::<u32>() {
+            Ok(gid) => Gid::from_raw(gid),
+            Err(_) => {
+                Group::from_name(group)?
+                    .ok_or_else(|| format!("unknown group {}", group))?
+                    .gid
+            }
+        }),
+        None => entry.map(|entry| entry.gid),
+    };
+    if user.is_some() && gid.is_none() {
+        return Err("run_as_group is required for a user without an entry in /etc/passwd".into());
     }
 
-    fs::copy(&current_executable_path, &binary_target_path)?;
-    println!("Executable copied to: {}", binary_target_path);
+    if let Some(gid) = gid {
+        set_supplementary_groups(entry, gid)?;
+        setgid(gid)?;
+    }
+    if let Some((uid, _)) = user {
+        setuid(uid)?;
+    }
+    if gid.is_some() {
+        info!(
+            "Running as uid {} and gid {}",
+            Uid::current(),
+            Gid::current()
+        );
+    }
+    Ok(())
+}
 
-    let java_home = std::env::var("JAVA_HOME").ok();
+// Replace the supplementary groups of root, keeping those of the user, e.g. docker
+#[cfg(target_os = "linux")]
+fn set_supplementary_groups(
+    entry: Option<&nix::unistd::User>,
+    gid: nix::unistd::Gid,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match entry {
+        Some(entry) => nix::unistd::initgroups(&std::ffi::CString::new(entry.name.as_str())?, gid)?,
+        None => nix::unistd::setgroups(&[gid])?,
+    }
+    Ok(())
+}
 
-    let service_content = if let Some(jh) = java_home {
-        format!(
-            "[Unit]
-Description=JVM Exporter Service
-After=network.target
+// setgroups is Linux only in nix, elsewhere the supplementary groups of root are kept
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_supplementary_groups(
+    _entry: Option<&nix::unistd::User>,
+    _gid: nix::unistd::Gid,
+) -> Result<(), Box<dyn std::error::Error>> {
+    log::warn!("Keeping the supplementary groups of root, they can only be dropped on Linux");
+    Ok(())
+}
 
-[Service]
-Type=simple
-ExecStart={}
-User=root
-Environment=\"JAVA_HOME={}\"
-Environment=\"PATH={}/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin\"
-Restart=on-failure
+#[cfg(not(unix))]
+fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if user.is_some() || group.is_some() {
+        eprintln!("run_as_user and run_as_group are only supported on Unix, ignoring them");
+    }
+    Ok(())
+}
 
-[Install]
-WantedBy=multi-user.target",
-            binary_target_path, jh, jh
+// Remove a socket file left behind by a previous run, which would make bind fail.
+// Anything else at that path is left alone and reported by bind.
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_socket_mode(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(UNIX_SOCKET_MODE))
+}
+
+// Resolve when a server task stops, never when there is no such server
+async fn wait_for_server(
+    handle: Option<tokio::task::JoinHandle<()>>,
+) -> Result<(), tokio::task::JoinError> {
+    match handle {
+        Some(handle) => handle.await,
+        None => std::future::pending().await,
+    }
+}
+
+// Ctrl+C, or SIGTERM sent by service managers on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// Re-read the configuration file, and the configuration service if set, when receiving SIGHUP.
+// Settings used at startup only, such as listen_address and aggregate_by_process, still need a restart.
+#[cfg(unix)]
+fn reload_config_on_sighup(config: Arc<RwLock<Config>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to register SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!(
+                "Received SIGHUP, reloading configuration from {}",
+                CONFIG_PATH
+            );
+            let mut new_config = match Config::new(CONFIG_PATH) {
+                Ok(new_config) => new_config,
+                Err(e) => {
+                    error!(
+                        "Failed to reload configuration, keeping the current one: {}",
+                        e
+                    );
+                    continue;
+                }
+            };
+            if let Some(url) = new_config.configuration_service_url.clone() {
+                if let Err(e) = fetch_and_merge_config(&url, &mut new_config).await {
+                    error!(
+                        "Failed to fetch configuration from configuration service: {}",
+                        e
+                    );
+                }
+            }
+
+            info!(
+                "Reloaded configuration: {}",
+                serde_json::to_string(&new_config).unwrap_or_default()
+            );
+            *config.write().unwrap() = new_config;
+        }
+    });
+}
+
+// Parse the listen address, either a full socket address or only an IP using the default port.
+// Binding to :: accepts IPv4 connections too on systems where IPv6 sockets are dual-stack (the Linux default).
+fn parse_listen_address(address: &str) -> Result<SocketAddr, std::net::AddrParseError> {
+    let address = address.trim();
+    address.parse::<SocketAddr>().or_else(|_| {
+        address
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+    })
+}
+
+// Settings of the systemd service installed by --auto-start
+struct ServiceSettings {
+    user: String,
+    java_home: Option<String>,
+    listen_address: Option<String>,
+    full_path: bool,
+}
+
+// Quote a command line argument for systemd when it has characters splitting or escaping it
+fn systemd_quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "\"'\\;$%".contains(c)) {
+        format!(
+            "\"{}\"",
+            arg.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('$', "$$")
+                .replace('%', "%%")
         )
     } else {
-        format!(
-            "[Unit]
+        arg.to_string()
+    }
+}
+
+// Unit file running the exporter at `binary_path` with the given settings. PATH includes the
+// JDK of JAVA_HOME when it is set, so that jps and jstat are found.
+fn service_unit(
+    binary_path: &str,
+    service: &ServiceSettings,
+    java_home_env: Option<&str>,
+) -> String {
+    let mut exec_start = vec![systemd_quote(binary_path)];
+    if let Some(java_home) = &service.java_home {
+        exec_start.push("--java-home".to_string());
+        exec_start.push(systemd_quote(java_home));
+    }
+    if let Some(listen_address) = &service.listen_address {
+        exec_start.push("--listen-address".to_string());
+        exec_start.push(systemd_quote(listen_address));
+    }
+    if service.full_path {
+        exec_start.push("--full-path".to_string());
+    }
+
+    let environment = match java_home_env {
+        Some(jh) => format!(
+            "Environment=\"JAVA_HOME={}\"
+Environment=\"PATH={}/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin\"
+",
+            jh, jh
+        ),
+        None => String::new(),
+    };
+
+    format!(
+        "[Unit]
 Description=JVM Exporter Service
 After=network.target
 
 [Service]
 Type=simple
 ExecStart={}
-User=root
-Restart=on-failure
+User={}
+{}Restart=on-failure
 
 [Install]
 WantedBy=multi-user.target",
-            binary_target_path
-        )
-    };
+        exec_start.join(" "),
+        service.user,
+        environment
+    )
+}
+
+fn configure_auto_start(service: &ServiceSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let service_path = "/etc/systemd/system/jvm-exporter.service";
+    let binary_target_dir = "/usr/local/bin";
+    let binary_target_path = format!("{}/jvm-exporter", binary_target_dir);
+
+    let current_executable_path = std::env::current_exe()?;
+    println!(
+        "Current executable path: {}",
+        current_executable_path.display()
+    );
+
+    if !Path::new(binary_target_dir).exists() {
+        fs::create_dir_all(binary_target_dir)?;
+        println!("Target directory created: {}", binary_target_dir);
+    }
+
+    fs::copy(&current_executable_path, &binary_target_path)?;
+    println!("Executable copied to: {}", binary_target_path);
+
+    let java_home = std::env::var("JAVA_HOME").ok();
+    let service_content = service_unit(&binary_target_path, service, java_home.as_deref());
 
     let service_dir = Path::new("/etc/systemd/system");
     if !service_dir.exists() {
@@ -182,3 +708,19 @@ WantedBy=multi-user.target",
 
     std::process::exit(0);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_misspelled_config_keys() {
+        let content =
+            "collection_interval_secs: 15\ncolection_interval_secs: 30\njava_home: /opt/jdk\n";
+        assert_eq!(
+            unknown_config_keys(content),
+            vec!["colection_interval_secs".to_string()]
+        );
+        assert!(unknown_config_keys("java_home: /opt/jdk\n").is_empty());
+    }
+}
@@ -1,60 +1,497 @@
+use crate::collector::JvmCollector;
 use crate::config::{Config, with_config};
 use crate::metrics;
+use crate::metrics::metrics::Metrics;
+use log::warn;
 use prometheus::Registry;
-use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
 use warp::Filter;
 
-pub fn setup_routes(
+// Updates of /config a client can make in a burst, then one more every CONFIG_WRITE_INTERVAL
+const CONFIG_WRITE_BURST: f64 = 5.0;
+const CONFIG_WRITE_INTERVAL: Duration = Duration::from_secs(6);
+
+// Registry of the JVM collector, served over HTTP and written to textfile_output_dir, along
+// with the collector's metrics
+pub fn metrics_registry(
     java_home: Arc<Option<String>>,
+    java_home_source: &'static str,
     full_path: bool,
     config: Arc<RwLock<Config>>,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+) -> (Arc<Registry>, Arc<Metrics>) {
     let registry = Arc::new(Registry::new());
-    let metrics = Arc::new(metrics::metrics::Metrics::new(&registry, config.clone()));
-    metrics::timer::run(metrics.clone());
+    let collector = JvmCollector::builder()
+        .java_home((*java_home).clone())
+        .java_home_source(java_home_source)
+        .full_path(full_path)
+        .shared_config(config)
+        .build();
+    let metrics = collector.metrics();
+    registry
+        .register(Box::new(collector))
+        .expect("Failed to register JVM collector");
+    (registry, metrics)
+}
+
+pub fn setup_routes(
+    registry: Arc<Registry>,
+    metrics: Arc<Metrics>,
+    config: Arc<RwLock<Config>>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // The metrics path is fixed at startup, changing metrics_path requires a restart
+    let metrics_path = config.read().unwrap().metrics_path();
+    // ?container=<id> only returns the series of that container
+    let metrics_route = path_filter(&metrics_path)
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then({
+            let registry = Arc::clone(&registry);
+            let metrics = Arc::clone(&metrics);
+
+            move |accept_encoding: Option<String>,
+                  if_none_match: Option<String>,
+                  query: HashMap<String, String>| {
+                let registry = Arc::clone(&registry);
+                let metrics = Arc::clone(&metrics);
+                let container = query.get("container").cloned();
+
+                async move {
+                    metrics::collect::handle_metrics(
+                        registry,
+                        metrics,
+                        accept_encoding,
+                        if_none_match,
+                        container,
+                    )
+                    .await
+                }
+            }
+        });
 
-    let metrics_route = warp::path("metrics").and_then({
-        let metrics = Arc::clone(&metrics);
+    let metrics_json_route = warp::path("metrics.json").and_then({
         let registry = Arc::clone(&registry);
-        let java_home = Arc::clone(&java_home);
-        let full_path = full_path;
 
         move || {
-            let metrics = Arc::clone(&metrics);
             let registry = Arc::clone(&registry);
-            let java_home = java_home.clone();
-            let full_path = full_path;
 
-            async move {
-                metrics::collect::handle_metrics(metrics, registry, java_home, full_path).await
-            }
+            async move { metrics::collect::handle_metrics_json(registry).await }
         }
     });
 
-    let config_route = warp::path("config")
+    // Ready once JVMs can be discovered, so that a node with a broken JDK gets no scrape traffic
+    let readyz_route = warp::path("readyz")
+        .and(warp::path::end())
         .and(warp::get())
-        .and(with_config(config.clone()))
-        .map(|config: Arc<RwLock<Config>>| {
-            let config = config.read().unwrap();
-            let config_data = (*config).clone();
-            warp::reply::json(&config_data)
-        })
+        .map(move || {
+            if metrics.is_ready() {
+                warp::reply::with_status("ok", StatusCode::OK)
+            } else {
+                warp::reply::with_status(
+                    "jps has not succeeded yet",
+                    StatusCode::SERVICE_UNAVAILABLE,
+                )
+            }
+        });
+
+    // JSON Schema of the configuration, for clients building an editor for /config
+    let config_schema = Arc::new(schemars::schema_for!(Config));
+    let config_schema_route = warp::path!("config" / "schema")
+        .and(warp::get())
+        .map(move || warp::reply::json(&*config_schema));
+
+    let config_writes = Arc::new(RateLimiter::default());
+    let config_route = config_schema_route
+        .or(warp::path("config")
+            .and(warp::get())
+            .and(with_config(config.clone()))
+            .map(|config: Arc<RwLock<Config>>| {
+                let config = config.read().unwrap();
+                let config_data = (*config).clone();
+                warp::reply::json(&config_data)
+            }))
         .or(warp::path("config")
             .and(warp::post())
+            .and(warp::addr::remote())
             .and(warp::body::json())
             .and(with_config(config.clone()))
-            .map(|new_config: Config, config: Arc<RwLock<Config>>| {
-                let mut config = config.write().unwrap();
-                *config = new_config;
-                warp::reply::json(&*config)
-            }));
-    
+            .map(
+                move |client: Option<SocketAddr>,
+                      new_config: Config,
+                      config: Arc<RwLock<Config>>| {
+                    // Clients on the Unix socket have no address and share a bucket
+                    let client = client.map(|addr| addr.ip());
+                    if !config_writes.allow(client, Instant::now()) {
+                        warn!(
+                            "Rejected a /config update from {:?}: too many requests",
+                            client
+                        );
+                        return warp::reply::with_status(
+                            warp::reply::json(&"Too many configuration updates, retry later"),
+                            StatusCode::TOO_MANY_REQUESTS,
+                        );
+                    }
+                    let mut config = config.write().unwrap();
+                    apply_config_update(&mut config, new_config);
+                    warp::reply::with_status(warp::reply::json(&*config), StatusCode::OK)
+                },
+            ));
+
+    // Allow browsers on the configured origins to read and update /config, including preflight requests
+    let cors_allowed_origins = config.read().unwrap().cors_allowed_origins.clone();
+    let config_route: BoxedFilter<(Box<dyn warp::Reply>,)> = match cors_allowed_origins {
+        Some(origins) if !origins.is_empty() => config_route
+            .with(config_cors(&origins))
+            .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+            .boxed(),
+        _ => config_route
+            .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+            .boxed(),
+    };
 
     // let deploy_route = warp::path("deploy")
     //     .and(warp::post())
     //     .and(warp::multipart::form().max_length(100_000_000_000))
     //     .and_then(deploy::deploy::handle_deploy);
 
-    let routes = metrics_route.or(config_route);
+    let routes = metrics_route
+        .or(metrics_json_route)
+        .or(readyz_route)
+        .or(config_route);
     Ok::<_, warp::Rejection>(routes).expect("TODO: panic message")
 }
+
+// Replace the configuration with one posted to /config. remote_hosts makes the exporter run ssh
+// and jolokia_urls makes it send HTTP requests, they keep the value of the configuration file
+// since anyone reaching /config can post.
+fn apply_config_update(config: &mut Config, mut new_config: Config) {
+    if new_config.remote_hosts != config.remote_hosts {
+        warn!("Ignoring remote_hosts of a /config update, it can only be set in the configuration file");
+    }
+    if new_config.jolokia_urls != config.jolokia_urls {
+        warn!("Ignoring jolokia_urls of a /config update, it can only be set in the configuration file");
+    }
+    new_config.remote_hosts = config.remote_hosts.take();
+    new_config.jolokia_urls = config.jolokia_urls.take();
+    *config = new_config;
+}
+
+// Token bucket per client, refilled with one token every CONFIG_WRITE_INTERVAL
+#[derive(Default)]
+struct RateLimiter {
+    buckets: Mutex<HashMap<Option<IpAddr>, (f64, Instant)>>, // Key: client IP, (tokens, last update)
+}
+
+impl RateLimiter {
+    fn allow(&self, client: Option<IpAddr>, now: Instant) -> bool {
+        let refilled = |tokens: f64, updated: Instant| {
+            let refill =
+                now.duration_since(updated).as_secs_f64() / CONFIG_WRITE_INTERVAL.as_secs_f64();
+            (tokens + refill).min(CONFIG_WRITE_BURST)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        // Full buckets are the same as missing ones, dropping them bounds the map
+        buckets.retain(|_, (tokens, updated)| refilled(*tokens, *updated) < CONFIG_WRITE_BURST);
+        let (tokens, updated) = buckets.entry(client).or_insert((CONFIG_WRITE_BURST, now));
+        *tokens = refilled(*tokens, *updated);
+        *updated = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+// Filter matching a path of one or more segments, such as "jvm/metrics"
+fn path_filter(path: &str) -> BoxedFilter<()> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_string())).boxed()
+        })
+        .and(warp::path::end())
+        .boxed()
+}
+
+// CORS for /config, "*" allows any origin. Origins that are not scheme://host[:port] are ignored,
+// warp would panic on them.
+fn config_cors(origins: &[String]) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(vec!["GET", "POST"])
+        .allow_header("content-type");
+    if origins.iter().any(|origin| origin == "*") {
+        return cors.allow_any_origin();
+    }
+    let valid_origins: Vec<&str> = origins
+        .iter()
+        .map(|origin| origin.trim_end_matches('/'))
+        .filter(|origin| {
+            let valid = origin
+                .parse::<warp::http::Uri>()
+                .map(|uri| {
+                    uri.scheme().is_some()
+                        && uri.authority().is_some()
+                        && matches!(uri.path(), "" | "/")
+                        && uri.query().is_none()
+                })
+                .unwrap_or(false);
+            if !valid {
+                warn!("Ignoring invalid CORS origin: {}", origin);
+            }
+            valid
+        })
+        .collect();
+    cors.allow_origins(valid_origins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RemoteHost;
+    use crate::metrics::runner::{CommandFuture, CommandRunner};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::process::Command;
+
+    // jps lists the test process as a JVM until `gone` is set. jstat -class prints a leading
+    // uptime without its header, to exercise the mismatch handling.
+    struct ScrapeRunner {
+        pid: String,
+        gone: AtomicBool,
+    }
+
+    #[cfg(unix)]
+    impl CommandRunner for ScrapeRunner {
+        fn run(&self, command: Command) -> CommandFuture {
+            use std::os::unix::process::ExitStatusExt;
+
+            let command = command.as_std();
+            let program = command.get_program().to_string_lossy().into_owned();
+            let option = command
+                .get_args()
+                .next()
+                .map(|arg| arg.to_string_lossy().into_owned());
+            let stdout = match (program.as_str(), option.as_deref()) {
+                ("jps", _) if self.gone.load(Ordering::Relaxed) => String::new(),
+                ("jps", _) => format!("{} com.example.Main\n", self.pid),
+                ("jstat", Some("-gc")) => "    S0C    S1C    S0U    S1U      EC       EU       OC       OU  YGC     YGCT\n\
+                                             0.0 1024.0    0.0  512.0  2048.0   1024.0   4096.0   1024.0   12    0.345\n"
+                    .to_string(),
+                ("jstat", Some("-gccapacity")) => "   NGCMN    NGCMX     NGC     OGCMN    OGCMX      OGC  YGC\n\
+                                                     0.0 262144.0  3072.0       0.0 262144.0   4096.0   12\n"
+                    .to_string(),
+                ("jstat", Some("-class")) => "Loaded  Bytes  Unloaded  Bytes     Time\n\
+                                                12.5  1000  2048.0        0     0.0    0.50\n"
+                    .to_string(),
+                ("jstat", Some("-compiler")) => "Compiled Failed Invalid   Time   FailedType FailedMethod\n\
+                                                     512      1       0     1.25          1 com/example/Main run\n"
+                    .to_string(),
+                _ => {
+                    return Box::pin(async {
+                        Ok(std::process::Output {
+                            status: std::process::ExitStatus::from_raw(1 << 8),
+                            stdout: Vec::new(),
+                            stderr: Vec::new(),
+                        })
+                    })
+                }
+            };
+            Box::pin(async move {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: stdout.into_bytes(),
+                    stderr: Vec::new(),
+                })
+            })
+        }
+    }
+
+    #[cfg(unix)]
+    async fn scrape(url: String) -> String {
+        tokio::task::spawn_blocking(move || ureq::get(&url).call().unwrap().into_string().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[cfg(unix)]
+    async fn wait_for(url: &str, what: &str, present: bool) -> String {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let body = scrape(url.to_string()).await;
+            if body.contains(what) == present || Instant::now() > deadline {
+                return body;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    // End to end through the routes served by the exporter, with jps and jstat answered by
+    // ScrapeRunner
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn scrapes_jstat_and_process_metrics() {
+        let pid = std::process::id();
+        // jstat is skipped for processes without a hsperfdata file
+        let hsperfdata_dir =
+            std::path::PathBuf::from(format!("/tmp/hsperfdata_jvm-exporter-test-{}", pid));
+        std::fs::create_dir_all(&hsperfdata_dir).unwrap();
+        std::fs::write(hsperfdata_dir.join(pid.to_string()), "").unwrap();
+
+        let runner = Arc::new(ScrapeRunner {
+            pid: pid.to_string(),
+            gone: AtomicBool::new(false),
+        });
+        let config = Arc::new(RwLock::new(Config {
+            collection_interval_secs: Some(1),
+            collect_tcp_states: Some(false),
+            jstat_extra_commands: Some(vec!["-gccapacity".to_string()]),
+            ..Config::default()
+        }));
+        let collector = JvmCollector::builder()
+            .shared_config(Arc::clone(&config))
+            .command_runner(runner.clone())
+            .build();
+        let metrics = collector.metrics();
+        let registry = Arc::new(Registry::new());
+        registry.register(Box::new(collector)).unwrap();
+        let routes = setup_routes(registry, metrics, config);
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("http://{}/metrics", addr);
+
+        let labels = format!(
+            "container=\"host\",pid=\"{}\",process_name=\"com.example.Main\"",
+            pid
+        );
+        let s1c = format!(
+            "jstat_gc_metrics{{container=\"host\",metric_name=\"S1C\",pid=\"{}\",process_name=\"com.example.Main\"}} 1024",
+            pid
+        );
+        let body = wait_for(&url, &s1c, true).await;
+        assert!(body.contains(&s1c), "{}", body);
+        assert!(body.contains(&format!(
+            "jstat_gc_metrics{{container=\"host\",metric_name=\"YGCT\",pid=\"{}\",process_name=\"com.example.Main\"}} 0.345",
+            pid
+        )));
+        assert!(body.contains(&format!(
+            "jstat_class_metrics{{container=\"host\",metric_name=\"Loaded\",pid=\"{}\",process_name=\"com.example.Main\"}} 1000",
+            pid
+        )));
+        assert!(body.contains(&format!(
+            "jvm_old_gen_utilization_percent{{container=\"host\",pid=\"{}\",process_name=\"com.example.Main\"}} 25",
+            pid
+        )));
+        assert!(body.contains(&format!(
+            "jstat_gccapacity_metrics{{container=\"host\",metric_name=\"OGCMX\",pid=\"{}\",process_name=\"com.example.Main\"}} 262144",
+            pid
+        )));
+        assert!(body.contains(&format!(
+            "jstat_compiler_metrics{{container=\"host\",metric_name=\"Compiled\",pid=\"{}\",process_name=\"com.example.Main\"}} 512",
+            pid
+        )));
+        assert!(body.contains(&format!("process_cpu_usage{{{}}}", labels)));
+        assert!(body.contains(&format!("process_memory_usage_bytes{{{}}}", labels)));
+
+        // The process is gone at the next collection
+        runner.gone.store(true, Ordering::Relaxed);
+        let body = wait_for(&url, &labels, false).await;
+        assert!(!body.contains(&labels), "{}", body);
+
+        let _ = std::fs::remove_dir_all(&hsperfdata_dir);
+    }
+
+    #[test]
+    fn limits_config_writes_per_client() {
+        let limiter = RateLimiter::default();
+        let client = Some("10.0.0.1".parse().unwrap());
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert!(limiter.allow(client, start));
+        }
+        assert!(!limiter.allow(client, start));
+        // Other clients have their own bucket
+        assert!(limiter.allow(Some("10.0.0.2".parse().unwrap()), start));
+        assert!(limiter.allow(None, start));
+
+        assert!(!limiter.allow(client, start + Duration::from_secs(3)));
+        assert!(limiter.allow(client, start + Duration::from_secs(6)));
+        assert!(!limiter.allow(client, start + Duration::from_secs(6)));
+    }
+
+    #[tokio::test]
+    async fn serves_readyz_only_at_its_path() {
+        // target_pids replaces discovery, so the exporter is ready at once
+        let config = Arc::new(RwLock::new(Config {
+            target_pids: Some(Vec::new()),
+            ..Default::default()
+        }));
+        let (registry, metrics) =
+            metrics_registry(Arc::new(None), "detected", false, config.clone());
+        let routes = setup_routes(registry, metrics, config);
+        let status = |path: &'static str| {
+            let routes = routes.clone();
+            async move {
+                warp::test::request()
+                    .path(path)
+                    .reply(&routes)
+                    .await
+                    .status()
+            }
+        };
+        assert_eq!(status("/readyz").await, StatusCode::OK);
+        assert_eq!(status("/readyz/extra").await, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn keeps_remote_hosts_on_config_updates() {
+        let remote_hosts = Some(vec![RemoteHost {
+            host: "db1".to_string(),
+            ..Default::default()
+        }]);
+        let mut config = Config {
+            remote_hosts: remote_hosts.clone(),
+            ..Default::default()
+        };
+        apply_config_update(
+            &mut config,
+            Config {
+                remote_hosts: Some(vec![RemoteHost {
+                    host: "-oProxyCommand=id".to_string(),
+                    ..Default::default()
+                }]),
+                collection_interval_secs: Some(30),
+                ..Default::default()
+            },
+        );
+        assert_eq!(config.remote_hosts, remote_hosts);
+        assert_eq!(config.collection_interval_secs, Some(30));
+    }
+
+    #[test]
+    fn keeps_jolokia_urls_on_config_updates() {
+        let jolokia_urls = Some(HashMap::from([(
+            "OrderService".to_string(),
+            "http://localhost:8778/jolokia".to_string(),
+        )]));
+        let mut config = Config {
+            jolokia_urls: jolokia_urls.clone(),
+            ..Default::default()
+        };
+        apply_config_update(
+            &mut config,
+            Config {
+                jolokia_urls: Some(HashMap::from([(
+                    "OrderService".to_string(),
+                    "http://169.254.169.254/latest".to_string(),
+                )])),
+                ..Default::default()
+            },
+        );
+        assert_eq!(config.jolokia_urls, jolokia_urls);
+    }
+}